@@ -1,5 +1,6 @@
 MEMORY
 {
-  FLASH : ORIGIN = 0x08000000, LENGTH = 512K
+  /* The last 128K sector (0x08060000) holds the saved config, see storage.rs */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 384K
   RAM   : ORIGIN = 0x20000000, LENGTH = 96K
 }
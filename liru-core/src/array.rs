@@ -0,0 +1,688 @@
+//! Calibrated line sensing over any source of raw readings: the ADC array
+//! in the firmware (`sensors.rs`), or scripted sweeps on the host.
+
+use embassy_time::{Duration, Instant, Ticker};
+
+use crate::line::*;
+
+/// Default binary threshold: 40% of the way from min (white) to max (black).
+const DEFAULT_THRESHOLD_PCT: u16 = 40;
+
+/// Reads averaged for each point of a two-point calibration.
+const TWO_POINT_SAMPLES: u32 = 16;
+
+/// Position readings averaged by `CalibratedArray::calibrate_center`.
+const CENTER_SAMPLES: i32 = 16;
+
+/// Binary threshold used before calibration.
+const UNCALIBRATED_THRESHOLD: u16 = 2000;
+
+/// Min/max substituted for a channel that calibrated with too little
+/// contrast: a wide band around the uncalibrated threshold, so the channel
+/// still normalizes (crudely) instead of always reading 0.
+const FALLBACK_MIN: u16 = UNCALIBRATED_THRESHOLD - 1000;
+const FALLBACK_MAX: u16 = UNCALIBRATED_THRESHOLD + 1000;
+
+/// Interval between calibration updates in `CalibratedArray::calibrate`.
+const CALIBRATION_SAMPLE_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Normalized reading at or above which a channel is clearly over the line.
+const LINE_LEVEL: u32 = 500;
+
+/// Where calibrated line sensing gets its raw readings: the ADC array on
+/// the robot, or anything else that can produce a sweep (e.g. scripted
+/// readings), so the calibration and position logic doesn't depend on the
+/// hardware.
+// Callers run on one executor and never need the futures to be `Send`
+#[allow(async_fn_in_trait)]
+pub trait RawSensorSource<const N: usize> {
+    /// One sweep of raw ADC values (0-4095) in physical left-to-right order
+    async fn read_raw(&mut self) -> SensorReadings<N>;
+
+    /// Battery voltage in millivolts (0 = unknown)
+    fn read_battery_mv(&mut self) -> u16 {
+        0
+    }
+
+    /// Raw obstacle distance reading (higher = closer, 0 = none)
+    #[cfg(feature = "obstacle-sensor")]
+    fn read_obstacle_raw(&mut self) -> u16 {
+        0
+    }
+
+    /// Raw motor current readings (left, right), 0 if not sensed
+    #[cfg(feature = "stall-guard")]
+    fn read_motor_current_raw(&mut self) -> [u16; 2] {
+        [0; 2]
+    }
+}
+
+/// Line sensor controller with calibration support, over any raw source
+pub struct CalibratedArray<S, const N: usize = SENSOR_COUNT> {
+    sensors: S,
+    min_readings: SensorReadings<N>,
+    max_readings: SensorReadings<N>,
+    thresholds: SensorReadings<N>,
+    calibrated: bool,
+    /// Threshold margin between calibrated min and max, in percent,
+    /// measured from `threshold_origin`
+    threshold_pct: u16,
+    threshold_origin: ThresholdOrigin,
+    /// Hysteresis margin around each threshold (raw ADC counts)
+    hysteresis: u16,
+    /// Previous binary pattern, so bits only flip once past the margin
+    binary_state: u16,
+    /// Two-point calibration: white (min) and black (max) captured so far
+    white_captured: bool,
+    black_captured: bool,
+    /// Correction applied to normalized values before the position is
+    /// computed (None = identity)
+    response_curve: Option<ResponseCurve>,
+    /// Position units each sensor pulls the line position toward
+    weights: [i32; N],
+    /// Line coverage (0-100) at the last line position reading
+    coverage: u8,
+    /// Position reported for a truly centered line (mounting misalignment),
+    /// subtracted from every position reading
+    center_offset: i32,
+    /// Mounting direction of the bar; applied on top of the remap table
+    orientation: Orientation,
+    /// Reads averaged per `update_calibration`, so one noisy sample can't
+    /// set an extreme min/max
+    cal_samples: u8,
+    /// Expected line width in tenths of a sensor spacing (0 = no hint): the
+    /// position only uses the cluster of channels closest to it
+    expected_line_sensors: u8,
+    /// Channels known dead (bit i = sensor i+1), replaced by their
+    /// neighbors in the line position
+    dead_channels: u16,
+    /// Raw readings from the most recent read
+    last_raw: SensorReadings<N>,
+    /// Dark or light line; calibration is the same either way, only the
+    /// binary comparison and the normalized values flip
+    polarity: LinePolarity,
+    /// One raw threshold for every channel, used instead of the calibrated
+    /// thresholds and position (None = calibrated)
+    fixed_threshold: Option<u16>,
+}
+
+impl<S: RawSensorSource<N>, const N: usize> CalibratedArray<S, N> {
+    /// Full-scale line position with 1000 units per sensor spacing
+    /// (3500 for the 8-channel array).
+    pub const POSITION_FULL_SCALE: i32 = (N as i32 - 1) * 500;
+
+    pub fn new(sensors: S) -> Self {
+        Self {
+            sensors,
+            min_readings: [4095; N],
+            max_readings: [0; N],
+            thresholds: [UNCALIBRATED_THRESHOLD; N],
+            calibrated: false,
+            threshold_pct: DEFAULT_THRESHOLD_PCT,
+            threshold_origin: ThresholdOrigin::White,
+            hysteresis: 0,
+            binary_state: 0,
+            white_captured: false,
+            black_captured: false,
+            response_curve: None,
+            weights: linear_weights(),
+            coverage: 0,
+            center_offset: 0,
+            orientation: Orientation::Normal,
+            cal_samples: 1,
+            expected_line_sensors: 0,
+            dead_channels: 0,
+            last_raw: [0; N],
+            polarity: LinePolarity::DarkOnLight,
+            fixed_threshold: None,
+        }
+    }
+
+    /// Follow a dark line on a light mat (default) or a light line on a
+    /// dark one. Position, intensity and the binary pattern keep their
+    /// meaning (high = line); the calibration stays valid.
+    pub fn set_polarity(&mut self, polarity: LinePolarity) {
+        self.polarity = polarity;
+        self.binary_state = 0;
+    }
+
+    /// Set where the binary threshold sits: `pct` of the calibrated range
+    /// away from the white (min) or black (max) end. Applies immediately if
+    /// already calibrated.
+    pub fn set_threshold(&mut self, origin: ThresholdOrigin, pct: u16) {
+        self.threshold_origin = origin;
+        self.threshold_pct = pct.min(100);
+        if self.calibrated {
+            self.compute_thresholds();
+        }
+    }
+
+    /// Set the hysteresis margin for `read_binary`: a bit sets above
+    /// `threshold + margin` and clears below `threshold - margin`.
+    pub fn set_hysteresis(&mut self, margin: u16) {
+        self.hysteresis = margin;
+    }
+
+    /// Linearize the sensors' reflectance response: normalized values are
+    /// mapped through `curve` (values clamped to 1000) before the weighted
+    /// position sum, correcting the centroid bias when the line straddles
+    /// two sensors.
+    pub fn set_response_curve(&mut self, curve: &ResponseCurve) {
+        self.response_curve = Some(curve.map(|point| point.min(1000)));
+    }
+
+    /// Replace the sensor-to-position weighting: the line position is the
+    /// average of `weights` weighted by the normalized readings, so
+    /// `weights[i]` is the position (in whatever units the caller wants)
+    /// reported when only sensor i sees the line. The default is
+    /// `linear_weights()`, 1000 per sensor spacing centered on 0; weighting
+    /// outer sensors more makes big deviations react faster.
+    /// Intensity and line loss detection don't depend on the weights.
+    pub fn set_position_weights(&mut self, weights: &[i32; N]) {
+        self.weights = *weights;
+    }
+
+    /// Set which way round the bar is mounted. `Reversed` flips the channel
+    /// order of every reading (after the remap table), so left and right
+    /// stay the robot's left and right without touching the control code.
+    /// Set before calibrating: the calibrated ranges are per position.
+    pub fn set_orientation(&mut self, orientation: Orientation) {
+        self.orientation = orientation;
+    }
+
+    /// Number of quick reads averaged per calibration update (at least 1,
+    /// the default).
+    pub fn set_cal_samples(&mut self, samples: u8) {
+        self.cal_samples = samples.max(1);
+    }
+
+    /// Hint how wide the line is, in tenths of a sensor spacing (e.g. 15 for
+    /// a line covering about 1.5 sensors; 0 = off, the default). With a
+    /// hint, the position only uses the cluster of adjacent channels whose
+    /// width is closest to it (see `dominant_cluster`), ignoring stray marks
+    /// elsewhere under the array. Intensity still counts every channel.
+    pub fn set_expected_line_sensors(&mut self, tenths: u8) {
+        self.expected_line_sensors = tenths;
+    }
+
+    /// Mark channels as dead (bit i = sensor i+1, e.g. the self-test's
+    /// `dead_channels`): for the line position, each one reads the average of
+    /// its neighbors' normalized values instead of its stuck reading, so the
+    /// robot keeps following on a flaky connector. 0 (the default) uses every
+    /// channel as read.
+    pub fn set_dead_channels(&mut self, mask: u16) {
+        self.dead_channels = mask;
+    }
+
+    /// Correct for a sensor bar mounted off-center: `offset` (in position
+    /// units) is what a truly centered line reads, and is subtracted from
+    /// every position.
+    pub fn set_center_offset(&mut self, offset: i32) {
+        self.center_offset = offset;
+    }
+
+    /// With the robot centered over the line, average the position over a
+    /// few reads and store it as the center offset. Returns the offset, or
+    /// None (offset unchanged) if uncalibrated or the line wasn't seen.
+    pub async fn calibrate_center(&mut self) -> Option<i32> {
+        if !self.calibrated {
+            return None;
+        }
+        let mut sum = 0;
+        for _ in 0..CENTER_SAMPLES {
+            let (position, intensity, _) = self.read_uncorrected_position(0).await;
+            if intensity == 0 {
+                return None;
+            }
+            sum += position;
+        }
+        self.center_offset = sum / CENTER_SAMPLES;
+        defmt::info!("Center offset: {}", self.center_offset);
+        Some(self.center_offset)
+    }
+
+    pub async fn read_all(&mut self) -> SensorReadings<N> {
+        self.read_oriented().await
+    }
+
+    /// Raw readings in the robot's left-to-right order.
+    async fn read_oriented(&mut self) -> SensorReadings<N> {
+        let mut readings = self.sensors.read_raw().await;
+        if self.orientation == Orientation::Reversed {
+            readings.reverse();
+        }
+        self.last_raw = readings;
+        readings
+    }
+
+    /// Raw readings from the most recent read, in left-to-right order.
+    pub fn last_raw(&self) -> SensorReadings<N> {
+        self.last_raw
+    }
+
+    pub fn is_calibrated(&self) -> bool {
+        self.calibrated
+    }
+
+    /// Thresholds and the min/max they were computed from.
+    pub fn calibration_report(&self) -> CalibrationReport<N> {
+        CalibrationReport {
+            calibrated: self.calibrated,
+            thresholds: self.thresholds,
+            min: self.min_readings,
+            max: self.max_readings,
+        }
+    }
+
+    pub fn reset_calibration(&mut self) {
+        self.min_readings = [4095; N];
+        self.max_readings = [0; N];
+        self.calibrated = false;
+        self.binary_state = 0;
+        self.white_captured = false;
+        self.black_captured = false;
+        defmt::info!("Calibration reset");
+    }
+
+    /// Two-point calibration: with every sensor over plain background,
+    /// take the averaged readings as the calibrated minimum.
+    /// Returns the calibration result once black has been captured too.
+    pub async fn calibrate_white(&mut self) -> Option<Result<(), CalibrationError>> {
+        self.min_readings = self.read_averaged().await;
+        self.white_captured = true;
+        defmt::info!("Calibration white: {:?}", self.min_readings);
+        self.finish_two_point()
+    }
+
+    /// Two-point calibration: with every sensor over the line, take the
+    /// averaged readings as the calibrated maximum.
+    /// Returns the calibration result once white has been captured too.
+    pub async fn calibrate_black(&mut self) -> Option<Result<(), CalibrationError>> {
+        self.max_readings = self.read_averaged().await;
+        self.black_captured = true;
+        defmt::info!("Calibration black: {:?}", self.max_readings);
+        self.finish_two_point()
+    }
+
+    fn finish_two_point(&mut self) -> Option<Result<(), CalibrationError>> {
+        if !(self.white_captured && self.black_captured) {
+            return None;
+        }
+        self.white_captured = false;
+        self.black_captured = false;
+        Some(self.finalize_calibration())
+    }
+
+    /// Average of `TWO_POINT_SAMPLES` sweeps, per channel.
+    async fn read_averaged(&mut self) -> SensorReadings<N> {
+        let mut sums = [0u32; N];
+        for _ in 0..TWO_POINT_SAMPLES {
+            let readings = self.read_oriented().await;
+            for (sum, &value) in sums.iter_mut().zip(readings.iter()) {
+                *sum += value as u32;
+            }
+        }
+        sums.map(|sum| (sum / TWO_POINT_SAMPLES) as u16)
+    }
+
+    /// Read every channel `cal_samples` times and fold the per-channel mean
+    /// into the calibrated min/max. Returns the mean, so anything shown
+    /// while calibrating is exactly what was learned.
+    pub async fn update_calibration(&mut self) -> SensorReadings<N> {
+        let mut sums = [0u32; N];
+        for _ in 0..self.cal_samples {
+            let readings = self.read_oriented().await;
+            for (sum, &value) in sums.iter_mut().zip(readings.iter()) {
+                *sum += value as u32;
+            }
+        }
+        let readings = sums.map(|sum| (sum / self.cal_samples as u32) as u16);
+        for (i, &val) in readings.iter().enumerate() {
+            if val < self.min_readings[i] {
+                self.min_readings[i] = val;
+            }
+            if val > self.max_readings[i] {
+                self.max_readings[i] = val;
+            }
+        }
+        readings
+    }
+
+    /// Fold calibration updates into the min/max every
+    /// `CALIBRATION_SAMPLE_INTERVAL` for `duration_ms`, paced by its own
+    /// timer, so the sample density doesn't depend on how often (or how
+    /// late) the caller runs. Returns the last readings.
+    pub async fn calibrate(&mut self, duration_ms: u64) -> SensorReadings<N> {
+        let end = Instant::now() + Duration::from_millis(duration_ms);
+        let mut ticker = Ticker::every(CALIBRATION_SAMPLE_INTERVAL);
+        let mut readings = self.update_calibration().await;
+        while Instant::now() + CALIBRATION_SAMPLE_INTERVAL <= end {
+            ticker.next().await;
+            readings = self.update_calibration().await;
+        }
+        readings
+    }
+
+    /// Compute thresholds from the collected min/max.
+    /// Channels whose range is too small to tell line from background get a
+    /// fallback range; the error lists them.
+    pub fn finalize_calibration(&mut self) -> Result<(), CalibrationError> {
+        defmt::info!("Calibration min: {:?}", self.min_readings);
+        defmt::info!("Calibration max: {:?}", self.max_readings);
+        
+        // A flat channel (e.g. the robot never crossed the line) would
+        // normalize to 0 forever; fall back to a default range instead
+        let low_contrast = low_contrast_mask(&self.min_readings, &self.max_readings);
+        for i in (0..N).filter(|&i| low_contrast & (1 << i) != 0) {
+            self.min_readings[i] = FALLBACK_MIN;
+            self.max_readings[i] = FALLBACK_MAX;
+        }
+        self.compute_thresholds();
+        self.calibrated = true;
+
+        defmt::info!("Calibration thresholds: {:?}", self.thresholds);
+        if low_contrast != 0 {
+            defmt::warn!("Calibration contrast too low on channels {=u16:#b}, using fallback range", low_contrast);
+            return Err(CalibrationError { low_contrast });
+        }
+        Ok(())
+    }
+
+    /// Take over a calibration saved earlier (see `storage::ConfigStore`), as
+    /// if it had just been finalized.
+    pub fn restore_calibration(&mut self, calibration: &CalibrationReport<N>) {
+        self.min_readings = calibration.min;
+        self.max_readings = calibration.max;
+        self.thresholds = calibration.thresholds;
+        self.binary_state = 0;
+        self.calibrated = true;
+        defmt::info!("Calibration restored: {:?}", self.thresholds);
+    }
+
+    /// Skip calibration and use `thresholds` directly (raw ADC counts),
+    /// e.g. on a known surface. The line position still needs a range per
+    /// channel: a calibrated range is kept, otherwise one `FALLBACK_MAX -
+    /// FALLBACK_MIN` wide is centered on each threshold. Until the next
+    /// calibration or `set_threshold`, which recompute them from min/max.
+    pub fn set_thresholds(&mut self, thresholds: &SensorReadings<N>) {
+        if !self.calibrated {
+            let half = (FALLBACK_MAX - FALLBACK_MIN) / 2;
+            for (i, &threshold) in thresholds.iter().enumerate() {
+                let min = threshold.saturating_sub(half).min(4095 - 2 * half);
+                self.min_readings[i] = min;
+                self.max_readings[i] = min + 2 * half;
+            }
+        }
+        self.thresholds = *thresholds;
+        self.binary_state = 0;
+        self.calibrated = true;
+        defmt::info!("Manual thresholds: {:?}", self.thresholds);
+    }
+
+    /// Recompute thresholds from the collected min/max and the threshold margin.
+    fn compute_thresholds(&mut self) {
+        self.thresholds = thresholds(&self.min_readings, &self.max_readings, self.threshold_origin, self.threshold_pct);
+    }
+
+    /// Bypass the calibration with one raw threshold for every channel
+    /// (None = back to the calibrated thresholds), for quick demos on a
+    /// known surface. `read_binary` compares against it and the line
+    /// position comes from that pattern (see `fixed_position`).
+    pub fn set_fixed_threshold(&mut self, threshold: Option<u16>) {
+        self.fixed_threshold = threshold;
+        self.binary_state = 0;
+    }
+
+    /// Read binary using calibrated thresholds, or the fixed threshold if
+    /// one is set (bit i = sensor i+1).
+    /// Readings inside the hysteresis band keep their previous state.
+    pub async fn read_binary(&mut self) -> u16 {
+        let readings = self.read_oriented().await;
+        let thresholds = self.active_thresholds();
+        let mut result: u16 = 0;
+
+        for (i, &value) in readings.iter().enumerate() {
+            // High value = Black, Low value = White: a dark line reads above
+            // its threshold, a light line below it
+            let was_set = self.binary_state & (1 << i) != 0;
+            let threshold = thresholds[i];
+            let is_set = match (self.polarity, was_set) {
+                (LinePolarity::DarkOnLight, true) => value > threshold.saturating_sub(self.hysteresis),
+                (LinePolarity::DarkOnLight, false) => value > threshold.saturating_add(self.hysteresis),
+                (LinePolarity::LightOnDark, true) => value < threshold.saturating_add(self.hysteresis),
+                (LinePolarity::LightOnDark, false) => value < threshold.saturating_sub(self.hysteresis),
+            };
+            if is_set {
+                result |= 1 << i;
+            }
+        }
+        self.binary_state = result;
+        result
+    }
+
+    /// Calculate weighted line position using calibrated values.
+    /// Returns (position, intensity)
+    /// position: 1000 per sensor spacing, centered on 0; -3500 (Index0) to
+    /// 3500 (Index7) for the 8-channel array (with the default weights, see
+    /// `set_position_weights`).
+    /// intensity: Sum of calibrated sensor values (0-1000 per sensor), useful for line loss detection.
+    pub async fn read_line_position(&mut self) -> (i32, u32) {
+        self.read_line_position_scaled(Self::POSITION_FULL_SCALE).await
+    }
+
+    /// Like `read_line_position`, but position is mapped to
+    /// -`full_scale`..`full_scale` (e.g. 100 for a percent-style range),
+    /// independent of the number of sensors.
+    pub async fn read_line_position_scaled(&mut self, full_scale: i32) -> (i32, u32) {
+        let (position, intensity) = self.read_line_position_thresholded(0).await;
+        let position = position * full_scale / Self::POSITION_FULL_SCALE.max(1);
+        (position, intensity)
+    }
+
+    /// Like `read_line_position`, but channels whose normalized value
+    /// (0-1000) is below `floor` are left out of the weighted sum, so noise
+    /// from sensors far from the line doesn't pull the center off.
+    /// If fewer than two channels reach the floor, all channels are used so a
+    /// thin line under a single sensor still yields a position.
+    /// Intensity is always the sum over all channels.
+    pub async fn read_line_position_thresholded(&mut self, floor: u16) -> (i32, u32) {
+        let (position, intensity, _) = self.read_line_position_ex(floor).await;
+        (position, intensity)
+    }
+
+    /// Like `read_line_position_thresholded`, plus a confidence (0-100) for
+    /// how concentrated the line signal is: high for one tight cluster of
+    /// sensors, low when spread out or split into separate groups (e.g. when
+    /// crossing another line), where the centroid is misleading.
+    pub async fn read_line_position_ex(&mut self, floor: u16) -> (i32, u32, u8) {
+        let (position, intensity, confidence) = match self.fixed_threshold {
+            Some(_) => fixed_position(self.read_binary().await, &self.weights),
+            None => self.read_uncorrected_position(floor).await,
+        };
+        if intensity == 0 {
+            return (0, 0, 0);
+        }
+        (position - self.center_offset, intensity, confidence)
+    }
+
+    /// `read_line_position_ex` before the center offset is applied.
+    async fn read_uncorrected_position(&mut self, floor: u16) -> (i32, u32, u8) {
+        let readings = self.read_oriented().await;
+        self.coverage = coverage(&self.normalized(&readings));
+        line_position(self.position_values(&readings), &self.weights, floor, self.expected_line_sensors)
+    }
+
+    /// Normalized values the line position is computed from: dead channels
+    /// filled in from their neighbors, then the response curve applied.
+    fn position_values(&self, readings: &SensorReadings<N>) -> [u32; N] {
+        let mut values = self.normalized(readings);
+        if self.dead_channels != 0 {
+            interpolate_dead(&mut values, self.dead_channels);
+        }
+        if let Some(curve) = &self.response_curve {
+            apply_response_curve(&mut values, curve);
+        }
+        values
+    }
+
+    /// Raw, normalized, binary and position from a single read, so a
+    /// misbehaving channel can be traced through the whole pipeline.
+    /// Doesn't touch the binary hysteresis state.
+    pub async fn diagnostics(&mut self) -> SensorDiagnostics<N> {
+        let raw = self.read_oriented().await;
+        let normalized = self.normalized(&raw).map(|value| value as u16);
+        let binary = self.binary_pattern(&raw);
+        let (position, intensity, _) = match self.fixed_threshold {
+            Some(_) => fixed_position(binary, &self.weights),
+            None => line_position(self.position_values(&raw), &self.weights, 0, self.expected_line_sensors),
+        };
+        let position = if intensity == 0 { 0 } else { position - self.center_offset };
+        SensorDiagnostics { raw, normalized, binary, position, intensity }
+    }
+
+    /// Roughly how much of the array was over the line (0-100) at the last
+    /// line position reading: the normalized sum over its maximum. Unlike the
+    /// raw intensity (0..N*1000) it reads the same for any array size, and
+    /// stays meaningful below the line loss cutoff (intensity 500, about 6%
+    /// on 8 channels) where intensity reports 0.
+    pub fn line_coverage(&self) -> u8 {
+        self.coverage
+    }
+
+    /// Classify the surface under the array: on the line, clearly over
+    /// background only, or ambiguous.
+    pub async fn surface_state(&mut self) -> SurfaceState {
+        if !self.calibrated {
+            return SurfaceState::Unknown;
+        }
+        let values = self.read_normalized().await;
+        if values.iter().any(|&val| val >= LINE_LEVEL) {
+            SurfaceState::OnLine
+        } else if values.iter().all(|&val| val <= WHITE_LEVEL) {
+            SurfaceState::OffLineWhite
+        } else {
+            SurfaceState::Unknown
+        }
+    }
+
+    /// Read all channels normalized to 0-1000 between calibrated min and max.
+    async fn read_normalized(&mut self) -> [u32; N] {
+        let readings = self.read_oriented().await;
+        self.normalized(&readings)
+    }
+
+    /// `readings` normalized to 0-1000 between calibrated min and max, 1000
+    /// = fully on the line whatever the polarity.
+    fn normalized(&self, readings: &SensorReadings<N>) -> [u32; N] {
+        let values = normalize(readings, &self.min_readings, &self.max_readings);
+        match self.polarity {
+            LinePolarity::DarkOnLight => values,
+            LinePolarity::LightOnDark => values.map(|value| 1000 - value),
+        }
+    }
+
+    /// Bitmask of the channels over the line in `readings` by the current
+    /// thresholds (the fixed one if set) and polarity, without hysteresis.
+    pub fn binary_pattern(&self, readings: &SensorReadings<N>) -> u16 {
+        binary_pattern(readings, &self.active_thresholds(), self.polarity)
+    }
+
+    /// The thresholds binary patterns are read against: the fixed threshold
+    /// on every channel if one is set, the calibrated ones otherwise.
+    fn active_thresholds(&self) -> SensorReadings<N> {
+        match self.fixed_threshold {
+            Some(threshold) => [threshold; N],
+            None => self.thresholds,
+        }
+    }
+}
+
+/// The other inputs on the sensor board's ADC, passed through
+impl<S: RawSensorSource<N>, const N: usize> CalibratedArray<S, N> {
+    pub fn read_battery_mv(&mut self) -> u16 {
+        self.sensors.read_battery_mv()
+    }
+
+    #[cfg(feature = "obstacle-sensor")]
+    pub fn read_obstacle_raw(&mut self) -> u16 {
+        self.sensors.read_obstacle_raw()
+    }
+
+    #[cfg(feature = "stall-guard")]
+    pub fn read_motor_current_raw(&mut self) -> [u16; 2] {
+        self.sensors.read_motor_current_raw()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use embassy_futures::block_on;
+
+    const WHITE: SensorReadings = [200; SENSOR_COUNT];
+    const BLACK: SensorReadings = [3000; SENSOR_COUNT];
+
+    /// Replays `sweeps` in order, then keeps repeating the last one
+    struct Scripted {
+        sweeps: Vec<SensorReadings>,
+    }
+
+    impl RawSensorSource<SENSOR_COUNT> for Scripted {
+        async fn read_raw(&mut self) -> SensorReadings {
+            if self.sweeps.len() > 1 {
+                self.sweeps.remove(0)
+            } else {
+                self.sweeps[0]
+            }
+        }
+    }
+
+    /// Two-point calibrated over `WHITE` and `BLACK`, then reading `sweeps`
+    fn calibrated(sweeps: &[SensorReadings]) -> CalibratedArray<Scripted> {
+        let mut script = vec![WHITE; TWO_POINT_SAMPLES as usize];
+        script.extend([BLACK; TWO_POINT_SAMPLES as usize]);
+        script.extend_from_slice(sweeps);
+        let mut array = CalibratedArray::new(Scripted { sweeps: script });
+        assert_eq!(block_on(array.calibrate_white()), None);
+        assert_eq!(block_on(array.calibrate_black()), Some(Ok(())));
+        array
+    }
+
+    #[test]
+    fn centered_line() {
+        let mut array = calibrated(&[[210, 190, 205, 2550, 2620, 200, 195, 205]]);
+        let (position, intensity, _) = block_on(array.read_line_position_ex(0));
+        assert!(position.abs() <= 50, "position {position}");
+        assert!(intensity > 1500, "intensity {intensity}");
+    }
+
+    #[test]
+    fn far_left_line() {
+        let mut array = calibrated(&[[2950, 210, 190, 200, 205, 195, 200, 210]]);
+        let (position, _, _) = block_on(array.read_line_position_ex(0));
+        assert!((position + 3500).abs() <= 50, "position {position}");
+    }
+
+    #[test]
+    fn low_intensity_is_lost() {
+        let mut array = calibrated(&[[400, 220, 200, 260, 240, 200, 210, 380]]);
+        let (position, intensity, _) = block_on(array.read_line_position_ex(0));
+        assert_eq!(intensity, 0);
+        let binary = block_on(array.read_binary());
+        assert_eq!(classify_line::<SENSOR_COUNT>(binary, position, intensity), LineEvent::Lost);
+    }
+
+    #[test]
+    fn fixed_threshold_diagnostics_match_the_run() {
+        // Below the calibrated thresholds, above the fixed one
+        let sweep = [200, 200, 1200, 1200, 200, 200, 200, 200];
+        let mut array = calibrated(&[sweep]);
+        array.set_fixed_threshold(Some(1000));
+        let (position, intensity, _) = block_on(array.read_line_position_ex(0));
+        let diag = block_on(array.diagnostics());
+        assert_eq!(diag.binary, 0b0000_1100);
+        assert_eq!((diag.position, diag.intensity), (position, intensity));
+        assert_eq!(array.binary_pattern(&sweep), diag.binary);
+    }
+}
@@ -0,0 +1,222 @@
+//! Host simulator for LiRu: the firmware's state machine following a
+//! modelled track, for trying control changes before flashing.
+//!
+//! The robot is a differential drive on a stadium-shaped track (two
+//! straights joined by half circles). Each control tick the sensor array is
+//! read from where it sits over the track, `RobotState::tick` runs exactly as
+//! on the robot, and its motor command moves the model. Time is virtual, so
+//! a run takes as long as the math does.
+//!
+//! ```text
+//! cargo run --bin sim --features sim --target x86_64-unknown-linux-gnu -- [seconds] [text command]...
+//! ```
+//!
+//! Extra arguments are text commands (as typed in a serial terminal, e.g.
+//! `"PID 250 0 10"` or `"SPEED 70 50"`) sent before `START`. The run is
+//! printed as CSV, one row per control tick.
+
+use std::cell::Cell;
+use std::f32::consts::PI;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::Waker;
+
+use embassy_futures::block_on;
+use embassy_time::{Duration, Instant};
+use embassy_time_driver::Driver;
+
+use liru_core::array::{CalibratedArray, RawSensorSource};
+use liru_core::config::RobotConfig;
+use liru_core::line::{SensorReadings, SENSOR_COUNT};
+use liru_core::protocol::{parse_text_command, Reply};
+use liru_core::state::RobotState;
+
+/// Length of each straight and radius of the curves (mm)
+const STRAIGHT_MM: f32 = 1000.0;
+const CURVE_RADIUS_MM: f32 = 300.0;
+/// Electrical tape
+const LINE_WIDTH_MM: f32 = 19.0;
+
+/// Distance between adjacent sensors (mm)
+const SENSOR_SPACING_MM: f32 = 10.0;
+/// How far ahead of the wheel axle the sensor array sits (mm)
+const ARRAY_AHEAD_MM: f32 = 70.0;
+/// Radius of the spot each sensor sees (mm)
+const SPOT_RADIUS_MM: f32 = 4.0;
+/// Raw readings over plain background and fully over the line
+const WHITE_RAW: f32 = 300.0;
+const BLACK_RAW: f32 = 3200.0;
+
+/// Distance between the wheels (mm)
+const WHEELBASE_MM: f32 = 120.0;
+/// Wheel speed at 100% duty (mm/s)
+const TOP_SPEED_MM_S: f32 = 600.0;
+
+/// Run length when none is given (s)
+const DEFAULT_RUN_S: u64 = 30;
+
+/// Virtual clock: timers complete as soon as they are awaited, by jumping
+/// the clock to their deadline.
+struct SimClock {
+    ticks: AtomicU64,
+}
+
+impl SimClock {
+    fn advance_to(&self, at: Instant) {
+        self.ticks.fetch_max(at.as_ticks(), Ordering::Relaxed);
+    }
+}
+
+impl Driver for SimClock {
+    fn now(&self) -> u64 {
+        self.ticks.load(Ordering::Relaxed)
+    }
+
+    fn schedule_wake(&self, at: u64, waker: &Waker) {
+        self.ticks.fetch_max(at, Ordering::Relaxed);
+        waker.wake_by_ref();
+    }
+}
+
+embassy_time_driver::time_driver_impl!(static CLOCK: SimClock = SimClock { ticks: AtomicU64::new(0) });
+
+/// The firmware logs through defmt; the simulator only prints its CSV.
+#[defmt::global_logger]
+struct Discard;
+
+unsafe impl defmt::Logger for Discard {
+    fn acquire() {}
+    unsafe fn flush() {}
+    unsafe fn release() {}
+    unsafe fn write(_bytes: &[u8]) {}
+}
+
+defmt::timestamp!("");
+
+/// Robot position (mm) and heading (radians, 0 = +x, counterclockwise)
+#[derive(Clone, Copy)]
+struct Pose {
+    x: f32,
+    y: f32,
+    heading: f32,
+}
+
+impl Pose {
+    /// Drive both wheels at `speeds` (mm/s, left and right) for `dt` seconds.
+    fn drive(&mut self, [left, right]: [f32; 2], dt: f32) {
+        let linear = (left + right) / 2.0;
+        let angular = (right - left) / WHEELBASE_MM;
+        self.heading += angular * dt;
+        self.x += linear * self.heading.cos() * dt;
+        self.y += linear * self.heading.sin() * dt;
+    }
+
+    /// Where sensor `i` (0 = leftmost) sits on the track
+    fn sensor(&self, i: usize) -> (f32, f32) {
+        let lateral = (SENSOR_COUNT as f32 - 1.0) / 2.0 - i as f32;
+        self.on_array(lateral * SENSOR_SPACING_MM)
+    }
+
+    /// The point on the array `left` mm left of its middle
+    fn on_array(&self, left: f32) -> (f32, f32) {
+        let (sin, cos) = self.heading.sin_cos();
+        (
+            self.x + ARRAY_AHEAD_MM * cos - left * sin,
+            self.y + ARRAY_AHEAD_MM * sin + left * cos,
+        )
+    }
+}
+
+/// Distance (mm) from a point to the middle of the line. The track is every
+/// point `CURVE_RADIUS_MM` away from the segment between the curve centers.
+fn track_distance(x: f32, y: f32) -> f32 {
+    let cx = x.clamp(-STRAIGHT_MM / 2.0, STRAIGHT_MM / 2.0);
+    ((x - cx).hypot(y) - CURVE_RADIUS_MM).abs()
+}
+
+/// Raw reading of a sensor whose spot is centered `distance` mm from the
+/// middle of the line: the share of the spot over the line, roughly.
+fn raw_reading(distance: f32) -> u16 {
+    let covered = ((LINE_WIDTH_MM / 2.0 + SPOT_RADIUS_MM - distance) / (2.0 * SPOT_RADIUS_MM)).clamp(0.0, 1.0);
+    (WHITE_RAW + (BLACK_RAW - WHITE_RAW) * covered) as u16
+}
+
+/// The sensor array over the modelled track
+struct TrackSensors {
+    pose: Rc<Cell<Pose>>,
+}
+
+impl RawSensorSource<SENSOR_COUNT> for TrackSensors {
+    async fn read_raw(&mut self) -> SensorReadings {
+        let pose = self.pose.get();
+        core::array::from_fn(|i| {
+            let (x, y) = pose.sensor(i);
+            raw_reading(track_distance(x, y))
+        })
+    }
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let run_s = match args.next() {
+        Some(arg) => arg.parse().unwrap_or_else(|_| panic!("run length in seconds expected, got {arg:?}")),
+        None => DEFAULT_RUN_S,
+    };
+    let commands: Vec<String> = ["MODE 1".to_string()]
+        .into_iter()
+        .chain(args)
+        .chain(["START".to_string()])
+        .collect();
+
+    // On the bottom straight, facing along it
+    let pose = Rc::new(Cell::new(Pose { x: 0.0, y: -CURVE_RADIUS_MM, heading: 0.0 }));
+    let mut sensors = CalibratedArray::new(TrackSensors { pose: pose.clone() });
+    let mut state = RobotState::new(Instant::now(), RobotConfig::default());
+    let mut speeds = [0i16; 2];
+
+    for line in &commands {
+        let Some(command) = parse_text_command(line.as_bytes()) else {
+            panic!("not a text command: {line:?}");
+        };
+        let actions = block_on(state.handle_command(command, &mut sensors, Instant::now()));
+        if let Some(command) = actions.motors {
+            speeds = command.wheel_speeds();
+        }
+    }
+
+    println!("t_ms,mode,x_mm,y_mm,heading_deg,offset_mm,left,right");
+    let end = Instant::now() + Duration::from_secs(run_s);
+    while Instant::now() < end {
+        let now = Instant::now();
+        let period = Duration::from_millis(state.control_period_ms());
+        let actions = block_on(state.tick(&mut sensors, now));
+        if let Some(command) = actions.motors {
+            speeds = command.wheel_speeds();
+        }
+        for reply in &actions.replies {
+            match reply {
+                Reply::Error(code) => eprintln!("{} ms: error 0x{code:02X}", now.as_millis()),
+                Reply::Finished => eprintln!("{} ms: finished", now.as_millis()),
+                _ => {}
+            }
+        }
+
+        let mut p = pose.get();
+        p.drive(speeds.map(|speed| speed as f32 / 1000.0 * TOP_SPEED_MM_S), period.as_micros() as f32 / 1e6);
+        pose.set(p);
+        CLOCK.advance_to(now + period);
+
+        let (x, y) = p.on_array(0.0);
+        println!(
+            "{},{},{:.1},{:.1},{:.1},{:.1},{},{}",
+            now.as_millis(),
+            state.mode_byte(),
+            p.x,
+            p.y,
+            p.heading.rem_euclid(2.0 * PI) * 180.0 / PI,
+            track_distance(x, y),
+            speeds[0],
+            speeds[1],
+        );
+    }
+}
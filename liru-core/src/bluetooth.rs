@@ -5,85 +5,184 @@
 //! - PC7: RX (from HC-05 TX)  
 //! - PB6: STATE (high when connected)
 //!
-//! Protocol:
-//! - Commands from GUI: [CMD_BYTE, DATA...]
-//! - Data to GUI: [MSG_TYPE, DATA...]
+//! The protocol itself (command and message bytes, their parsing and
+//! payload layouts) is hardware-free and lives in the library's
+//! `protocol.rs`; this module moves it over the UART, framed as described
+//! in `framing.rs`. A frame whose checksum doesn't match is counted and
+//! dropped without being acted on.
 
-use embassy_stm32::usart::{self, Uart};
+use embassy_stm32::usart::{self, Uart, UartRx, UartTx};
 use embassy_stm32::gpio::Input;
 use embassy_stm32::mode::Async;
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicU32, Ordering};
 
-/// Command bytes from GUI
-pub mod cmd {
-    /// Set motor speeds: [CMD_MOTOR, left_speed_i8, right_speed_i8]
-    pub const MOTOR: u8 = 0x01;
-    /// Stop all motors
-    pub const STOP: u8 = 0x02;
-    /// Request sensor data
-    pub const GET_SENSORS: u8 = 0x03;
-    /// Ping (for connection check)
-    pub const PING: u8 = 0x04;
-    /// Request raw sensor data (16-bit)
-    pub const GET_RAW_SENSORS: u8 = 0x05;
-    /// Set robot mode: [CMD_SET_MODE, mode_byte] (0=Car, 1=Line)
-    pub const SET_MODE: u8 = 0x06;
-    /// Start command for Line Follower calibration
-    pub const START: u8 = 0x07;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant};
+use heapless::{Deque, Vec};
+
+use crate::config::RobotConfig;
+use crate::motors::MotorAction;
+use crate::sensors::{CalibrationReport, RawRange, SensorDiagnostics};
+pub use liru_core::protocol::*;
+use liru_core::framing::{checksum, cobs_encode, Frame, FrameParser, FRAME_DELIMITER, MAX_ENCODED_LEN, MAX_FRAME_LEN};
+
+/// When the receive half last decoded a command (ms since boot, 0 = never),
+/// so the transmit half can tell a live link from a floating STATE pin
+static LAST_COMMAND_MS: AtomicU32 = AtomicU32::new(0);
+
+/// Most bytes taken from the UART per `BluetoothRx::read_commands` call
+const RX_CHUNK_LEN: usize = 32;
+
+/// Most commands one chunk can hold: the shortest is two bytes (a one-letter
+/// text command and its line ending; a binary frame takes at least four)
+pub const MAX_COMMANDS_PER_READ: usize = RX_CHUNK_LEN / 2;
+
+/// TX budgeting window
+const TX_WINDOW: Duration = Duration::from_millis(100);
+
+/// Bytes the link can carry per `TX_WINDOW`: 9600 baud at 10 bits per byte
+/// (start + 8 data + stop) is 960 bytes/s.
+const TX_BYTES_PER_WINDOW: usize = 96;
+
+/// Outbound ring buffer between the tasks producing replies and the task
+/// transmitting them. `push` never waits, so a slow link can't delay the
+/// control loop.
+///
+/// Drop policy when full: the oldest droppable reply (telemetry, sensor or
+/// battery data, see `Reply::is_droppable`) is evicted to make room. If
+/// only ACKs, errors and other must-send replies are queued, a new droppable
+/// reply is discarded instead; a must-send reply then evicts the oldest
+/// entry, which can only happen once the link has been stuck for a while.
+pub struct ReplyQueue<const N: usize> {
+    queue: Mutex<CriticalSectionRawMutex, RefCell<Deque<Reply, N>>>,
+    ready: Signal<CriticalSectionRawMutex, ()>,
+}
+
+impl<const N: usize> ReplyQueue<N> {
+    pub const fn new() -> Self {
+        Self {
+            queue: Mutex::new(RefCell::new(Deque::new())),
+            ready: Signal::new(),
+        }
+    }
+
+    /// Queue a reply without waiting, applying the drop policy when full.
+    pub fn push(&self, reply: Reply) {
+        self.queue.lock(|queue| {
+            let mut queue = queue.borrow_mut();
+            if queue.is_full() {
+                let oldest_droppable = queue.iter().position(|queued| queued.is_droppable());
+                match oldest_droppable {
+                    Some(index) => remove_at(&mut queue, index),
+                    None if reply.is_droppable() => return,
+                    None => {
+                        queue.pop_front();
+                    }
+                }
+            }
+            let _ = queue.push_back(reply);
+        });
+        self.ready.signal(());
+    }
+
+    /// Take the oldest queued reply.
+    pub fn pop(&self) -> Option<Reply> {
+        self.queue.lock(|queue| queue.borrow_mut().pop_front())
+    }
+
+    /// Wait until something was pushed since the last wait.
+    pub async fn wait(&self) {
+        self.ready.wait().await
+    }
 }
 
-/// Message types to GUI
-pub mod msg {
-    /// Sensor data: [MSG_SENSORS, sensor_byte]
-    pub const SENSORS: u8 = 0x10;
-    /// Pong response
-    pub const PONG: u8 = 0x11;
-    /// Connection established
-    pub const CONNECTED: u8 = 0x12;
-    /// Raw sensor data: [MSG_RAW_SENSORS, 16 bytes of data]
-    pub const RAW_SENSORS: u8 = 0x13;
-    /// Debug message: [MSG_DEBUG, mode_byte, position_byte, motor_action_byte]
-    pub const DEBUG: u8 = 0x14;
-    /// Calibration started
-    pub const CALIBRATION_START: u8 = 0x15;
-    /// Calibration ended
-    pub const CALIBRATION_END: u8 = 0x16;
-    /// Analog debug data: [MSG_DEBUG_ANALOG, PosH, PosL, IntH, IntL, Steer, L_Speed, R_Speed]
-    pub const DEBUG_ANALOG: u8 = 0x17;
-    /// Error message
-    pub const ERROR: u8 = 0xFF;
+/// Remove the entry at `index`, keeping the order of the others.
+fn remove_at<const N: usize>(queue: &mut Deque<Reply, N>, index: usize) {
+    for _ in 0..index {
+        if let Some(reply) = queue.pop_front() {
+            let _ = queue.push_back(reply);
+        }
+    }
+    queue.pop_front();
+    for _ in index..queue.len() {
+        if let Some(reply) = queue.pop_front() {
+            let _ = queue.push_back(reply);
+        }
+    }
 }
 
-/// Parsed command from GUI
-#[derive(Debug, Clone, Copy)]
-pub enum Command {
-    /// Set motor speeds (left, right) from -100 to 100
-    Motor { left: i8, right: i8 },
-    /// Stop all motors
-    Stop,
-    /// Request sensor readings
-    GetSensors,
-    /// Request raw sensor readings
-    GetRawSensors,
-    /// Ping request
-    Ping,
-    /// Set Robot Mode
-    SetMode(u8),
-    /// Start calibration/run
-    Start,
-    /// Unknown command
-    Unknown(u8),
+/// Bytes written in the current `TX_WINDOW`, so droppable telemetry can be
+/// skipped instead of backing up the UART.
+struct TxBudget {
+    window_start: Instant,
+    used: usize,
 }
 
-/// HC-05 Bluetooth driver
+impl TxBudget {
+    fn new() -> Self {
+        Self { window_start: Instant::now(), used: 0 }
+    }
+
+    fn refresh(&mut self) {
+        if self.window_start.elapsed() >= TX_WINDOW {
+            self.window_start = Instant::now();
+            self.used = 0;
+        }
+    }
+
+    /// Whether `len` more bytes fit in the current window
+    fn has_room(&mut self, len: usize) -> bool {
+        self.refresh();
+        self.used + len <= TX_BYTES_PER_WINDOW
+    }
+
+    /// Account for `len` bytes written (may overrun the window for
+    /// replies that are never dropped)
+    fn spend(&mut self, len: usize) {
+        self.refresh();
+        self.used += len;
+    }
+}
+
+/// HC-05 Bluetooth driver, transmit side (plus the STATE pin).
+///
+/// Created together with its `BluetoothRx` so sending and receiving can run
+/// in separate tasks without one waiting on the other.
 pub struct Bluetooth<'d> {
-    uart: Uart<'d, Async>,
+    tx: UartTx<'d, Async>,
     state_pin: Input<'d>,
+    tx_budget: TxBudget,
+    /// The reply being sent may be dropped if its frame doesn't fit in
+    /// `tx_budget`
+    droppable: bool,
+    /// Commands must have arrived this recently for the link to count as
+    /// connected (None = trust the STATE pin alone)
+    activity_timeout: Option<Duration>,
+    /// When STATE was first seen high
+    state_high_since: Option<Instant>,
 }
 
 impl<'d> Bluetooth<'d> {
-    /// Create a new Bluetooth driver instance
-    pub fn new(uart: Uart<'d, Async>, state_pin: Input<'d>) -> Self {
-        Self { uart, state_pin }
+    /// Create a new Bluetooth driver instance, split into transmit and receive halves
+    pub fn new(uart: Uart<'d, Async>, state_pin: Input<'d>) -> (Self, BluetoothRx<'d>) {
+        let (tx, rx) = uart.split();
+        let bt = Self {
+            tx,
+            state_pin,
+            tx_budget: TxBudget::new(),
+            droppable: false,
+            activity_timeout: None,
+            state_high_since: None,
+        };
+        let bt_rx = BluetoothRx {
+            rx,
+            frames: FrameParser::new(),
+            errors: UartErrorStats::default(),
+        };
+        (bt, bt_rx)
     }
 
     /// Check if a device is connected (STATE pin high)
@@ -91,31 +190,56 @@ impl<'d> Bluetooth<'d> {
         self.state_pin.is_high()
     }
 
-    /// Read a single byte with timeout (returns None if no data within timeout)
-    pub async fn try_read_byte(&mut self, timeout_ms: u64) -> Option<u8> {
-        use embassy_time::{with_timeout, Duration};
-        let mut buf = [0u8; 1];
-        match with_timeout(Duration::from_millis(timeout_ms), self.uart.read(&mut buf)).await {
-            Ok(Ok(_)) => Some(buf[0]),
-            _ => None,
-        }
+    /// For modules whose STATE pin floats high while unpaired: only count
+    /// the link once a command has arrived since STATE went high, and drop
+    /// it when no command arrived within `timeout`.
+    pub fn require_activity(&mut self, timeout: Duration) {
+        self.activity_timeout = Some(timeout);
     }
 
-    /// Read a single byte (blocking until received)
-    pub async fn read_byte(&mut self) -> Result<u8, usart::Error> {
-        let mut buf = [0u8; 1];
-        self.uart.read(&mut buf).await?;
-        Ok(buf[0])
+    /// STATE pin high and, with `require_activity`, a command received since
+    /// it went high and within the timeout.
+    pub fn connection_confirmed(&mut self) -> bool {
+        if !self.is_connected() {
+            self.state_high_since = None;
+            return false;
+        }
+        let since = *self.state_high_since.get_or_insert_with(Instant::now);
+        let Some(timeout) = self.activity_timeout else {
+            return true;
+        };
+        match LAST_COMMAND_MS.load(Ordering::Relaxed) {
+            0 => false,
+            ms => {
+                let last_command = Instant::from_millis(ms as u64);
+                last_command >= since && last_command.elapsed() <= timeout
+            }
+        }
     }
 
-    /// Write bytes to Bluetooth
+    /// Write one message to Bluetooth as a COBS frame, checksum appended.
+    /// This is the single choke point for outgoing data, so the budget is
+    /// charged what actually goes on the wire: encoded frame plus delimiter.
     pub async fn write(&mut self, data: &[u8]) -> Result<(), usart::Error> {
-        self.uart.write(data).await
+        let data = &data[..data.len().min(MAX_FRAME_LEN - 1)];
+        let mut message = [0u8; MAX_FRAME_LEN];
+        message[..data.len()].copy_from_slice(data);
+        message[data.len()] = checksum(data);
+        let mut frame = [0u8; MAX_ENCODED_LEN + 1];
+        let len = cobs_encode(&message[..data.len() + 1], &mut frame);
+        frame[len] = FRAME_DELIMITER;
+        let cost = len + 1;
+        if self.droppable && !self.tx_budget.has_room(cost) {
+            return Ok(());
+        }
+        self.tx_budget.spend(cost);
+        self.tx.write(&frame[..cost]).await
     }
 
     /// Send sensor data to GUI
-    pub async fn send_sensors(&mut self, sensor_byte: u8) -> Result<(), usart::Error> {
-        self.write(&[msg::SENSORS, sensor_byte]).await
+    pub async fn send_sensors(&mut self, pattern: u16) -> Result<(), usart::Error> {
+        let [low, high] = pattern_bytes(pattern);
+        self.write(&[msg::SENSORS, low, high]).await
     }
 
     /// Send raw sensor data (8 channels, u16)
@@ -130,14 +254,73 @@ impl<'d> Bluetooth<'d> {
         self.write(&buf).await
     }
 
+    /// Send calibration thresholds, min and max (8 channels each, u16 little-endian)
+    pub async fn send_calibration(&mut self, report: &CalibrationReport) -> Result<(), usart::Error> {
+        let mut buf = [0u8; 50];
+        buf[0] = msg::CALIBRATION;
+        buf[1] = report.calibrated as u8;
+        let values = report.thresholds.iter().chain(&report.min).chain(&report.max);
+        for (i, &value) in values.enumerate() {
+            let bytes = value.to_le_bytes();
+            buf[2 + i * 2] = bytes[0];
+            buf[2 + i * 2 + 1] = bytes[1];
+        }
+        self.write(&buf).await
+    }
+
+    /// Send the raw ADC extremes of the last run (`msg::RUN_RANGE`)
+    pub async fn send_run_range(&mut self, range: &RawRange) -> Result<(), usart::Error> {
+        let mut buf = [0u8; 33];
+        buf[0] = msg::RUN_RANGE;
+        for (i, &value) in range.min.iter().chain(&range.max).enumerate() {
+            buf[1 + i * 2..3 + i * 2].copy_from_slice(&value.to_le_bytes());
+        }
+        self.write(&buf).await
+    }
+
+    /// Send a diagnostic snapshot (`msg::DIAG`): raw and normalized values,
+    /// binary pattern, position and intensity, little-endian
+    pub async fn send_diagnostics(&mut self, diag: &SensorDiagnostics) -> Result<(), usart::Error> {
+        let mut buf = [0u8; 39];
+        buf[0] = msg::DIAG;
+        for (i, &value) in diag.raw.iter().chain(&diag.normalized).enumerate() {
+            buf[1 + i * 2..3 + i * 2].copy_from_slice(&value.to_le_bytes());
+        }
+        [buf[33], buf[38]] = pattern_bytes(diag.binary);
+        buf[34..36].copy_from_slice(&(diag.position.clamp(i16::MIN as i32, i16::MAX as i32) as i16).to_le_bytes());
+        buf[36..38].copy_from_slice(&(diag.intensity.min(u16::MAX as u32) as u16).to_le_bytes());
+        self.write(&buf).await
+    }
+
+    /// Send calibration progress: elapsed seconds and per-channel spread
+    pub async fn send_calibration_progress(&mut self, elapsed_s: u8, spread: &[u16; 8]) -> Result<(), usart::Error> {
+        let mut buf = [0u8; 18];
+        buf[0] = msg::CALIBRATION_PROGRESS;
+        buf[1] = elapsed_s;
+        for (i, &value) in spread.iter().enumerate() {
+            let bytes = value.to_le_bytes();
+            buf[2 + i * 2] = bytes[0];
+            buf[2 + i * 2 + 1] = bytes[1];
+        }
+        self.write(&buf).await
+    }
+
     /// Send pong response
     pub async fn send_pong(&mut self) -> Result<(), usart::Error> {
         self.write(&[msg::PONG]).await
     }
 
-    /// Send connected notification
+    /// Send connected notification, followed by the firmware version
+    /// so the GUI learns capabilities at connect time
     pub async fn send_connected(&mut self) -> Result<(), usart::Error> {
-        self.write(&[msg::CONNECTED]).await
+        self.write(&[msg::CONNECTED]).await?;
+        self.send_version().await
+    }
+
+    /// Send firmware version and capability bitmask
+    pub async fn send_version(&mut self) -> Result<(), usart::Error> {
+        let [major, minor, patch] = version::FIRMWARE;
+        self.write(&[msg::VERSION, major, minor, patch, version::CAPABILITIES]).await
     }
 
     /// Send calibration start notification
@@ -150,83 +333,196 @@ impl<'d> Bluetooth<'d> {
         self.write(&[msg::CALIBRATION_END]).await
     }
 
-    /// Send debug message: mode, sensor position, motor action
-    /// motor_action: 0=stop, 1=forward, 2=left, 3=right
-    pub async fn send_debug(&mut self, mode: u8, position: u8, motor_action: u8) -> Result<(), usart::Error> {
-        self.write(&[msg::DEBUG, mode, position, motor_action]).await
-    }
-
-    /// Send detailed analog debug message (7 bytes payload)
-    /// [Type 0x17] [Pos_H] [Pos_L] [Int_H] [Int_L] [Steer] [L_Speed] [R_Speed]
-    pub async fn send_analog_debug(
-        &mut self, 
-        position: i16, 
-        intensity: u16, 
-        steering: i8,
-        left_speed: u8,
-        right_speed: u8
-    ) -> Result<(), usart::Error> {
-        let pos_bytes = position.to_be_bytes();
-        let int_bytes = intensity.to_be_bytes();
-        // steering is i8, map to u8 (safe cast)
-        let steer_byte = steering as u8;
-        
-        self.write(&[
-            msg::DEBUG_ANALOG, 
-            pos_bytes[0], pos_bytes[1],
-            int_bytes[0], int_bytes[1],
-            steer_byte,
-            left_speed,
-            right_speed
-        ]).await
-    }
-
-    /// Read and parse a command from GUI
-    /// Returns None if no complete command available
-    pub async fn read_command(&mut self) -> Result<Command, usart::Error> {
-        let cmd_byte = self.read_byte().await?;
-
-        match cmd_byte {
-            cmd::MOTOR => {
-                let left = self.read_byte().await? as i8;
-                let right = self.read_byte().await? as i8;
-                Ok(Command::Motor { left, right })
+    /// Echo applied PID gains (x100) so the GUI can confirm
+    pub async fn send_pid(&mut self, kp: u16, ki: u16, kd: u16) -> Result<(), usart::Error> {
+        let kp = kp.to_be_bytes();
+        let ki = ki.to_be_bytes();
+        let kd = kd.to_be_bytes();
+        self.write(&[msg::PID, kp[0], kp[1], ki[0], ki[1], kd[0], kd[1]]).await
+    }
+
+    /// Send battery voltage in millivolts (0 = unknown / not wired)
+    pub async fn send_battery(&mut self, mv: u16) -> Result<(), usart::Error> {
+        let mv = mv.to_be_bytes();
+        self.write(&[msg::BATTERY, mv[0], mv[1]]).await
+    }
+
+    /// Send finish-line notification
+    pub async fn send_finished(&mut self) -> Result<(), usart::Error> {
+        self.write(&[msg::FINISHED]).await
+    }
+
+    /// Send emergency stop state
+    pub async fn send_estop(&mut self, latched: bool) -> Result<(), usart::Error> {
+        self.write(&[msg::ESTOP, latched as u8]).await
+    }
+
+    /// Acknowledge a handled command
+    pub async fn send_ack(&mut self, command: u8) -> Result<(), usart::Error> {
+        self.write(&[msg::ACK, command]).await
+    }
+
+    /// Send self-test result: pass flag and bitmask of dead sensor channels
+    pub async fn send_selftest(&mut self, dead_channels: u8) -> Result<(), usart::Error> {
+        self.write(&[msg::SELFTEST, (dead_channels == 0) as u8, dead_channels]).await
+    }
+
+    /// Send error message with one of the `err` codes
+    pub async fn send_error(&mut self, code: u8) -> Result<(), usart::Error> {
+        self.write(&[msg::ERROR, code]).await
+    }
+
+    /// Send the UART receive error counters (`err::UART_ERRORS`)
+    pub async fn send_error_stats(&mut self, stats: UartErrorStats) -> Result<(), usart::Error> {
+        let UartErrorStats { overrun, framing, parity, noise, checksum } = stats;
+        let [o0, o1] = overrun.to_be_bytes();
+        let [f0, f1] = framing.to_be_bytes();
+        let [p0, p1] = parity.to_be_bytes();
+        let [n0, n1] = noise.to_be_bytes();
+        let [c0, c1] = checksum.to_be_bytes();
+        self.write(&[msg::ERROR, err::UART_ERRORS, o0, o1, f0, f1, p0, p1, n0, n1, c0, c1]).await
+    }
+
+    /// Send debug message: mode, binary sensor pattern, motor action
+    pub async fn send_debug(&mut self, mode: u8, pattern: u16, motor_action: MotorAction) -> Result<(), usart::Error> {
+        let [low, high] = pattern_bytes(pattern);
+        self.write(&[msg::DEBUG, mode, low, motor_action as u8, high]).await
+    }
+
+    /// Send the packed status heartbeat (`msg::STATUS`)
+    pub async fn send_status(&mut self, status: StatusFlags) -> Result<(), usart::Error> {
+        let [flags, stalled] = status.bits();
+        self.write(&[msg::STATUS, flags, stalled]).await
+    }
+
+    /// Send the current mode (`msg::MODE`)
+    pub async fn send_mode(&mut self, mode: u8) -> Result<(), usart::Error> {
+        self.write(&[msg::MODE, mode]).await
+    }
+
+    /// Send the tunables (`msg::CONFIG`)
+    pub async fn send_config(&mut self, config: &RobotConfig) -> Result<(), usart::Error> {
+        let mut buf = [0u8; 1 + RobotConfig::LEN];
+        buf[0] = msg::CONFIG;
+        buf[1..].copy_from_slice(&config.encode());
+        self.write(&buf).await
+    }
+
+    /// Send line follower telemetry (`msg::DEBUG_ANALOG`)
+    pub async fn send_telemetry(&mut self, frame: &TelemetryFrame) -> Result<(), usart::Error> {
+        self.write(&frame.encode()).await
+    }
+
+    /// Send an outgoing message produced by the robot state machine.
+    /// Telemetry and sensor data that would exceed the link's capacity for
+    /// the current 100ms window are dropped; ACKs, errors and other replies
+    /// always go out.
+    pub async fn send_reply(&mut self, reply: Reply) -> Result<(), usart::Error> {
+        self.droppable = reply.is_droppable();
+        let result = match reply {
+            Reply::Sensors(binary) => self.send_sensors(binary).await,
+            Reply::RawSensors(readings) => self.send_raw_sensors(readings).await,
+            Reply::Calibration(report) => self.send_calibration(&report).await,
+            Reply::Pong => self.send_pong().await,
+            Reply::CalibrationStart => self.send_calibration_start().await,
+            Reply::CalibrationEnd => self.send_calibration_end().await,
+            Reply::CalibrationProgress { elapsed_s, spread } => {
+                self.send_calibration_progress(elapsed_s, &spread).await
             }
-            cmd::STOP => Ok(Command::Stop),
-            cmd::GET_SENSORS => Ok(Command::GetSensors),
-            cmd::GET_RAW_SENSORS => Ok(Command::GetRawSensors),
-            cmd::PING => Ok(Command::Ping),
-            cmd::SET_MODE => {
-                let mode = self.read_byte().await?;
-                Ok(Command::SetMode(mode))
+            Reply::Pid { kp, ki, kd } => self.send_pid(kp, ki, kd).await,
+            Reply::Battery(mv) => self.send_battery(mv).await,
+            Reply::Version => self.send_version().await,
+            Reply::Finished => self.send_finished().await,
+            Reply::EStop(latched) => self.send_estop(latched).await,
+            Reply::Ack(command) => self.send_ack(command).await,
+            Reply::Telemetry(frame) => self.send_telemetry(&frame).await,
+            Reply::Error(code) => self.send_error(code).await,
+            Reply::LowContrast(channels) => {
+                let [low, high] = pattern_bytes(channels);
+                self.write(&[msg::ERROR, err::LOW_CONTRAST, low, high]).await
             }
-            cmd::START => Ok(Command::Start),
-            other => Ok(Command::Unknown(other)),
-        }
+            Reply::UartErrors(stats) => self.send_error_stats(stats).await,
+            Reply::Debug { mode, position, action } => self.send_debug(mode, position, action).await,
+            #[cfg(feature = "obstacle-sensor")]
+            Reply::Obstacle(blocked) => self.write(&[msg::OBSTACLE, blocked as u8]).await,
+            Reply::RunStats(stats) => self.write(&stats.encode()).await,
+            Reply::Perf(perf) => self.write(&perf.encode()).await,
+            Reply::Config(config) => self.send_config(&config).await,
+            Reply::RunRange(range) => self.send_run_range(&range).await,
+            Reply::LineEvent(event) => self.write(&[msg::LINE_EVENT, event.as_byte()]).await,
+            Reply::Status(status) => self.send_status(status).await,
+            Reply::Mode(mode) => self.send_mode(mode).await,
+            Reply::MotorOutput([left, right]) => self.write(&[msg::MOTOR_OUTPUT, left as u8, right as u8]).await,
+            Reply::Diag(diag) => self.send_diagnostics(&diag).await,
+        };
+        self.droppable = false;
+        result
     }
+}
+
+/// HC-05 Bluetooth driver, receive side: reassembles frames into commands.
+pub struct BluetoothRx<'d> {
+    rx: UartRx<'d, Async>,
+    frames: FrameParser,
+    errors: UartErrorStats,
+}
 
-    /// Try to read a command with timeout (non-blocking)
-    /// Returns None if no data available within timeout
-    pub async fn try_read_command(&mut self, timeout_ms: u64) -> Option<Command> {
-        let cmd_byte = self.try_read_byte(timeout_ms).await?;
+impl<'d> BluetoothRx<'d> {
+    /// UART receive errors counted so far
+    pub fn error_stats(&self) -> UartErrorStats {
+        UartErrorStats { checksum: self.frames.checksum_errors(), ..self.errors }
+    }
+
+    /// Count a receive error and drop the frame it corrupted.
+    ///
+    /// The driver clears the status flags (SR read followed by a DR read)
+    /// when it reports the error and again before the next read starts, so
+    /// reception resumes cleanly with the next byte. What's left to do here
+    /// is not to splice the rest of the damaged frame onto the next one.
+    fn record_error(&mut self, error: usart::Error) {
+        let counter = match error {
+            usart::Error::Overrun => &mut self.errors.overrun,
+            usart::Error::Framing => &mut self.errors.framing,
+            usart::Error::Parity => &mut self.errors.parity,
+            usart::Error::Noise => &mut self.errors.noise,
+            _ => return,
+        };
+        *counter = counter.saturating_add(1);
+        self.frames.drop_frame();
+    }
 
-        match cmd_byte {
-            cmd::MOTOR => {
-                // For multi-byte commands, we need to wait for the rest
-                let left = self.try_read_byte(50).await? as i8;
-                let right = self.try_read_byte(50).await? as i8;
-                Some(Command::Motor { left, right })
+    /// Feed one received byte into the frame accumulator.
+    /// Returns a command once a complete, valid frame has been received.
+    fn push_byte(&mut self, byte: u8) -> Option<Command> {
+        match self.frames.push_byte(byte)? {
+            Frame::Binary(frame) => parse_command(frame),
+            Frame::Text(line) => parse_text_command(line),
+        }
+    }
+
+    /// Read what has arrived (up to `RX_CHUNK_LEN` bytes, returning once the
+    /// line goes idle) and parse every complete command in it, in order.
+    /// A frame split across reads is completed by the next call.
+    pub async fn read_commands(&mut self) -> Result<Vec<Command, MAX_COMMANDS_PER_READ>, usart::Error> {
+        let mut chunk = [0u8; RX_CHUNK_LEN];
+        let len = match self.rx.read_until_idle(&mut chunk).await {
+            Ok(len) => len,
+            Err(error) => {
+                self.record_error(error);
+                return Err(error);
             }
-            cmd::STOP => Some(Command::Stop),
-            cmd::GET_SENSORS => Some(Command::GetSensors),
-            cmd::GET_RAW_SENSORS => Some(Command::GetRawSensors),
-            cmd::PING => Some(Command::Ping),
-            cmd::SET_MODE => {
-                let mode = self.try_read_byte(50).await?;
-                Some(Command::SetMode(mode))
+        };
+
+        let mut commands = Vec::new();
+        for &byte in &chunk[..len] {
+            if let Some(command) = self.push_byte(byte) {
+                // Can't overflow: every command takes at least two bytes
+                let _ = commands.push(command);
             }
-            cmd::START => Some(Command::Start),
-            other => Some(Command::Unknown(other)),
         }
+        if !commands.is_empty() {
+            LAST_COMMAND_MS.store(Instant::now().as_millis() as u32, Ordering::Relaxed);
+        }
+        Ok(commands)
     }
 }
@@ -0,0 +1,187 @@
+//! Tunable settings for LiRu robot.
+//!
+//! Everything the GUI can tune one setting at a time, gathered into one
+//! record the state machine runs from, so it can also be read and written
+//! as a whole (`cmd::GET_CONFIG`, `cmd::LOAD_CONFIG`).
+
+use crate::control::{ControllerKind, RecoveryStrategy, MAX_GAIN, MAX_POSITION_TAU_MS};
+
+/// Range accepted for the control loop period (500Hz down to 20Hz).
+pub const MIN_CONTROL_PERIOD_MS: u8 = 2;
+pub const MAX_CONTROL_PERIOD_MS: u8 = 50;
+
+/// Largest steering bias accepted (percent): enough for a dragging caster,
+/// not enough to mask a real steering problem.
+pub const MAX_STEERING_BIAS: i8 = 20;
+
+/// Line follower and drive tunables.
+///
+/// Wire layout (`cmd::LOAD_CONFIG` payload and `msg::CONFIG`, big-endian):
+/// [Speed] [Turn] [Kp_Left (2)] [Kp_Right (2)] [Ki (2)] [Kd (2)] [Controller] [Reverse]
+/// [Bias] [Steer_Limit] [Boost] [Period] [Search_Turn] [Search_Fwd]
+/// [Timeout] [Recovery] [Tau] [Car_Timeout] [Speed_Scale] [Center_On_Start]
+/// [Finish_Sustain]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RobotConfig {
+    /// Base speed: W/S drive speed in Car mode, and the shift applied to
+    /// the line follower's modulated base speed (70 = no shift)
+    pub speed: u8,
+    /// A/D pivot speed in Car mode
+    pub turn_speed: u8,
+    /// PID gains (x100), kp split by the side of center the line is on
+    pub kp_left: u16,
+    pub kp_right: u16,
+    pub ki: u16,
+    pub kd: u16,
+    /// Active PID terms
+    pub controller: ControllerKind,
+    /// How far (percent) the inner wheel may reverse in the emergency zone
+    pub reverse_limit: u8,
+    /// Constant steering (percent, + = right) added to the PID output
+    pub steering_bias: i8,
+    /// Largest steering correction (percent) after the bias (100 = no limit)
+    pub steering_limit: u8,
+    /// Outer wheel boost cap (percent) when the inner wheel is floored
+    pub turn_boost: u8,
+    /// Control loop period in ms
+    pub control_period_ms: u8,
+    /// Line search pivot and forward creep speeds (percent)
+    pub search_turn: u8,
+    pub search_forward: u8,
+    /// Lost-line recovery timeout in 100ms units (0 = no cap)
+    pub search_timeout_ds: u8,
+    /// What to do first when the line is lost
+    pub recovery: RecoveryStrategy,
+    /// Position filter time constant in ms (0 = off)
+    pub position_tau_ms: u8,
+    /// Car mode: stop if no drive command arrives for this long, in 100ms
+    /// units (0 = never)
+    pub car_timeout_ds: u8,
+    /// Line follower: share of the base speed (percent) kept for a faint or
+    /// off-center line, scaling continuously (100 = off, stepped zones)
+    pub speed_scale_min: u8,
+    /// Line follower: pivot onto the line center before each run
+    pub center_on_start: bool,
+    /// Line follower: how long every sensor must stay on the line before it
+    /// counts as the finish bar, in 100ms units (0 = never stop at a bar)
+    pub finish_sustain_ds: u8,
+}
+
+impl RobotConfig {
+    /// Encoded length, without a command or message type byte
+    pub const LEN: usize = 25;
+
+    /// Serialize to the wire layout
+    pub fn encode(&self) -> [u8; Self::LEN] {
+        let kp_left = self.kp_left.to_be_bytes();
+        let kp_right = self.kp_right.to_be_bytes();
+        let ki = self.ki.to_be_bytes();
+        let kd = self.kd.to_be_bytes();
+        [
+            self.speed,
+            self.turn_speed,
+            kp_left[0], kp_left[1],
+            kp_right[0], kp_right[1],
+            ki[0], ki[1],
+            kd[0], kd[1],
+            self.controller.as_byte(),
+            self.reverse_limit,
+            self.steering_bias as u8,
+            self.steering_limit,
+            self.turn_boost,
+            self.control_period_ms,
+            self.search_turn,
+            self.search_forward,
+            self.search_timeout_ds,
+            self.recovery.as_byte(),
+            self.position_tau_ms,
+            self.car_timeout_ds,
+            self.speed_scale_min,
+            self.center_on_start as u8,
+            self.finish_sustain_ds,
+        ]
+    }
+
+    /// Parse the wire layout. None if any value is out of range, with the
+    /// same limits as the individual set commands.
+    pub fn decode(bytes: &[u8; Self::LEN]) -> Option<Self> {
+        let u16_at = |i: usize| u16::from_be_bytes([bytes[i], bytes[i + 1]]);
+        let config = Self {
+            speed: bytes[0],
+            turn_speed: bytes[1],
+            kp_left: u16_at(2),
+            kp_right: u16_at(4),
+            ki: u16_at(6),
+            kd: u16_at(8),
+            controller: ControllerKind::from_byte(bytes[10])?,
+            reverse_limit: bytes[11],
+            steering_bias: bytes[12] as i8,
+            steering_limit: bytes[13],
+            turn_boost: bytes[14],
+            control_period_ms: bytes[15],
+            search_turn: bytes[16],
+            search_forward: bytes[17],
+            search_timeout_ds: bytes[18],
+            recovery: RecoveryStrategy::from_byte(bytes[19])?,
+            position_tau_ms: bytes[20],
+            car_timeout_ds: bytes[21],
+            speed_scale_min: bytes[22],
+            center_on_start: match bytes[23] {
+                0 => false,
+                1 => true,
+                _ => return None,
+            },
+            finish_sustain_ds: bytes[24],
+        };
+        config.is_valid().then_some(config)
+    }
+
+    fn is_valid(&self) -> bool {
+        let percents = [
+            self.speed,
+            self.turn_speed,
+            self.reverse_limit,
+            self.steering_limit,
+            self.turn_boost,
+            self.search_turn,
+            self.search_forward,
+            self.speed_scale_min,
+        ];
+        percents.iter().all(|&pct| pct <= 100)
+            && [self.kp_left, self.kp_right, self.ki, self.kd].iter().all(|&gain| gain <= MAX_GAIN)
+            && (-MAX_STEERING_BIAS..=MAX_STEERING_BIAS).contains(&self.steering_bias)
+            && (MIN_CONTROL_PERIOD_MS..=MAX_CONTROL_PERIOD_MS).contains(&self.control_period_ms)
+            && self.position_tau_ms <= MAX_POSITION_TAU_MS
+    }
+}
+
+impl Default for RobotConfig {
+    /// 70% base speed, kp 2.5 with no I or D, 100Hz loop, 3s search cap,
+    /// Car mode stop after 700ms without a drive command, finish bar after
+    /// 300ms (well beyond the time spent crossing an intersection at speed)
+    fn default() -> Self {
+        Self {
+            speed: 70,
+            turn_speed: 50,
+            kp_left: 250,
+            kp_right: 250,
+            ki: 0,
+            kd: 0,
+            controller: ControllerKind::Pid,
+            reverse_limit: 40,
+            steering_bias: 0,
+            steering_limit: 100,
+            turn_boost: 0,
+            control_period_ms: 10,
+            search_turn: 55,
+            search_forward: 50,
+            search_timeout_ds: 30,
+            recovery: RecoveryStrategy::Pivot,
+            position_tau_ms: 0,
+            car_timeout_ds: 7,
+            speed_scale_min: 100,
+            center_on_start: false,
+            finish_sustain_ds: 3,
+        }
+    }
+}
@@ -0,0 +1,676 @@
+//! Line follower control logic for LiRu robot.
+//!
+//! Holds the steering controller used while the robot is following the line,
+//! speed modulation from path straightness and line quality, the searcher
+//! used when the line is lost, and junction and finish-line detection.
+//! Kept free of hardware types so the math can be reasoned about on its own.
+
+use embassy_time::Instant;
+
+use crate::drive::MotorCommand;
+use crate::line::LineEvent;
+
+/// Fixed-point scale for PID gains (gains are sent and stored as value x100).
+pub const GAIN_SCALE: i32 = 100;
+
+/// Largest gain accepted over Bluetooth (50.00 in fixed-point).
+pub const MAX_GAIN: u16 = 5000;
+
+/// Output divisor applied on top of the gain scale.
+/// With kp = 1.00 a full sensor spacing (1000 units) of error gives 10% steering.
+const OUTPUT_SCALE: i32 = 100;
+
+/// Limit for the accumulated integral (error x ms). Kept unscaled so short
+/// ticks with a small error still add up; divided by 1000 only when the
+/// I term is computed.
+const INTEGRAL_LIMIT: i32 = 50_000_000;
+
+/// While the line is lost the integral decays by this fraction (x1000) per ms,
+/// so it is roughly gone after a second instead of slamming the robot when
+/// the line comes back.
+const INTEGRAL_BLEED_PER_MS: i32 = 3;
+
+/// Which PID terms the steering controller uses. The gains of inactive
+/// terms are kept, so switching back restores them.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ControllerKind {
+    P,
+    PI,
+    PD,
+    Pid,
+}
+
+impl ControllerKind {
+    /// Wire value used by `cmd::SET_CONTROLLER` (0=P, 1=PI, 2=PD, 3=PID)
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(ControllerKind::P),
+            1 => Some(ControllerKind::PI),
+            2 => Some(ControllerKind::PD),
+            3 => Some(ControllerKind::Pid),
+            _ => None,
+        }
+    }
+
+    pub fn as_byte(self) -> u8 {
+        self as u8
+    }
+
+    fn uses_integral(self) -> bool {
+        matches!(self, ControllerKind::PI | ControllerKind::Pid)
+    }
+
+    fn uses_derivative(self) -> bool {
+        matches!(self, ControllerKind::PD | ControllerKind::Pid)
+    }
+}
+
+/// PID steering controller.
+///
+/// Error is the weighted line position (-3500..3500), output is the
+/// steering correction in tenths of a motor speed percent.
+pub struct PidController {
+    /// Proportional gains (x100) for a line left (negative error) and
+    /// right of center, for robots that turn differently each way
+    pub kp_left: u16,
+    pub kp_right: u16,
+    /// Integral gain (x100)
+    pub ki: u16,
+    /// Derivative gain (x100)
+    pub kd: u16,
+    /// Active terms; inactive gains are treated as zero
+    kind: ControllerKind,
+    integral: i32,
+    /// Previous error, None right after a reset or line loss
+    last_error: Option<i32>,
+}
+
+impl PidController {
+    /// Create a new controller with the given fixed-point (x100) gains.
+    pub const fn new(kp: u16, ki: u16, kd: u16) -> Self {
+        Self {
+            kp_left: kp,
+            kp_right: kp,
+            ki,
+            kd,
+            kind: ControllerKind::Pid,
+            integral: 0,
+            last_error: None,
+        }
+    }
+
+    /// Replace the gains, with the same kp on both sides. Rejects gains
+    /// above `MAX_GAIN`.
+    pub fn set_gains(&mut self, kp: u16, ki: u16, kd: u16) -> bool {
+        if kp > MAX_GAIN || ki > MAX_GAIN || kd > MAX_GAIN {
+            return false;
+        }
+        self.kp_left = kp;
+        self.kp_right = kp;
+        self.ki = ki;
+        self.kd = kd;
+        self.reset();
+        true
+    }
+
+    /// Set kp separately for each side of center. Rejects gains above
+    /// `MAX_GAIN`.
+    pub fn set_side_gains(&mut self, kp_left: u16, kp_right: u16) -> bool {
+        if kp_left > MAX_GAIN || kp_right > MAX_GAIN {
+            return false;
+        }
+        self.kp_left = kp_left;
+        self.kp_right = kp_right;
+        true
+    }
+
+    /// Choose which terms are active, e.g. to compare P against PD on the
+    /// same track without touching the gains.
+    pub fn set_kind(&mut self, kind: ControllerKind) {
+        self.kind = kind;
+        self.reset();
+    }
+
+    pub fn kind(&self) -> ControllerKind {
+        self.kind
+    }
+
+    /// Clear accumulated integral and derivative history.
+    pub fn reset(&mut self) {
+        self.integral = 0;
+        self.last_error = None;
+    }
+
+    /// Compute the steering correction (tenths of a percent) for `error`
+    /// after `dt_ms` milliseconds.
+    pub fn update(&mut self, error: i32, dt_ms: u32) -> i32 {
+        let dt = dt_ms.max(1) as i32;
+
+        self.integral = self.integral.saturating_add(error.saturating_mul(dt)).clamp(-INTEGRAL_LIMIT, INTEGRAL_LIMIT);
+        // No derivative kick on the first sample after a reset or line loss
+        let derivative = self.last_error.map_or(0, |last| (error - last) * 1000 / dt);
+        self.last_error = Some(error);
+
+        // i64 so a large derivative spike with a high kd cannot overflow
+        let kp = if error < 0 { self.kp_left } else { self.kp_right };
+        let p = kp as i64 * error as i64;
+        let ki = if self.kind.uses_integral() { self.ki } else { 0 };
+        let kd = if self.kind.uses_derivative() { self.kd } else { 0 };
+        let i = ki as i64 * self.integral as i64 / 1000;
+        let d = kd as i64 * derivative as i64 / 1000;
+
+        ((p + i + d) * 10 / (GAIN_SCALE * OUTPUT_SCALE) as i64) as i32
+    }
+
+    /// Call instead of `update` while the line is lost. Integration stops
+    /// (there is no valid error to integrate) and the integral bleeds toward
+    /// zero, so it can't wind up during the search. The derivative restarts
+    /// cleanly once the line is found again.
+    pub fn update_lost(&mut self, dt_ms: u32) {
+        let bleed = (dt_ms as i32).saturating_mul(INTEGRAL_BLEED_PER_MS).min(1000);
+        self.integral -= (self.integral as i64 * bleed as i64 / 1000) as i32;
+        self.last_error = None;
+    }
+}
+
+impl Default for PidController {
+    /// Defaults roughly match the old center-zone steering (position / 40).
+    fn default() -> Self {
+        Self::new(250, 0, 0)
+    }
+}
+
+/// Largest position filter time constant accepted (ms); beyond this the
+/// steering lags too far behind the line to be useful.
+pub const MAX_POSITION_TAU_MS: u8 = 200;
+
+/// Optional first-order low-pass on the line position fed to the PID.
+///
+/// Smooths the jumps the centroid makes as the line moves from one sensor
+/// to the next. A time constant of 0 disables it. After a reset the next
+/// position is taken as-is, so a re-acquired line doesn't slew over from
+/// the stale value.
+pub struct PositionFilter {
+    tau_ms: u32,
+    value: Option<i32>,
+}
+
+impl PositionFilter {
+    pub const fn new(tau_ms: u32) -> Self {
+        Self { tau_ms, value: None }
+    }
+
+    /// Change the time constant (ms, 0 = off).
+    pub fn set_tau(&mut self, tau_ms: u32) {
+        self.tau_ms = tau_ms;
+        self.reset();
+    }
+
+    /// Forget the filtered value, e.g. when the line is lost.
+    pub fn reset(&mut self) {
+        self.value = None;
+    }
+
+    /// Feed `position` measured `dt_ms` after the previous one and return
+    /// the filtered position.
+    pub fn update(&mut self, position: i32, dt_ms: u32) -> i32 {
+        let filtered = match self.value {
+            Some(last) if self.tau_ms > 0 => {
+                let dt = dt_ms.max(1) as i32;
+                last + (position - last) * dt / (self.tau_ms as i32 + dt)
+            }
+            _ => position,
+        };
+        self.value = Some(filtered);
+        filtered
+    }
+}
+
+/// Longest accepted pivot time for a 90 degree turn (ms).
+pub const MAX_TURN_MS_PER_90: u16 = 5000;
+
+/// Timed pivot turns for a robot without wheel encoders.
+///
+/// Calibrated as the pivot time for 90 degrees at a fixed speed; other
+/// angles scale that time linearly. Only approximate: battery level, floor
+/// and wheel slip all change the real angle.
+pub struct TurnCalibration {
+    ms_per_90: u32,
+    speed: u8,
+}
+
+impl TurnCalibration {
+    pub const fn new(ms_per_90: u32, speed: u8) -> Self {
+        Self { ms_per_90, speed }
+    }
+
+    /// Store a new calibration. Rejects a zero or out-of-range time or speed.
+    pub fn set(&mut self, ms_per_90: u16, speed: u8) -> bool {
+        if ms_per_90 == 0 || ms_per_90 > MAX_TURN_MS_PER_90 || speed == 0 || speed > 100 {
+            return false;
+        }
+        self.ms_per_90 = ms_per_90 as u32;
+        self.speed = speed;
+        true
+    }
+
+    /// Pivot command and its duration (ms) for a turn of `degrees`
+    /// (+ = right). None for a zero angle.
+    pub fn dead_reckon_turn(&self, degrees: i16) -> Option<(MotorCommand, u32)> {
+        let duration = degrees.unsigned_abs() as u32 * self.ms_per_90 / 90;
+        if duration == 0 {
+            return None;
+        }
+        let command = if degrees > 0 {
+            MotorCommand::TurnRight(self.speed)
+        } else {
+            MotorCommand::TurnLeft(self.speed)
+        };
+        Some((command, duration))
+    }
+}
+
+impl Default for TurnCalibration {
+    /// A rough guess for the stock chassis; calibrate with `cmd::TURN_CAL`
+    fn default() -> Self {
+        Self::new(350, 50)
+    }
+}
+
+/// Number of recent positions used to judge how straight the path is.
+pub const SPEED_HISTORY_LEN: usize = 16;
+
+/// Position standard deviation at or below which the path counts as straight.
+const STRAIGHT_STD_DEV: i64 = 200;
+
+/// Position standard deviation at or above which the path counts as curvy.
+const CURVY_STD_DEV: i64 = 1500;
+
+/// Base speed modulation from recent path straightness.
+///
+/// Keeps a short history of line positions. Low variance (straight) raises
+/// the base speed toward `max_speed`, high variance (curvy) lowers it toward
+/// `min_speed`, scaling continuously in between.
+pub struct SpeedModulator {
+    history: [i32; SPEED_HISTORY_LEN],
+    len: usize,
+    next: usize,
+    min_speed: i32,
+    max_speed: i32,
+}
+
+impl SpeedModulator {
+    pub const fn new(min_speed: i32, max_speed: i32) -> Self {
+        Self {
+            history: [0; SPEED_HISTORY_LEN],
+            len: 0,
+            next: 0,
+            min_speed,
+            max_speed,
+        }
+    }
+
+    /// Clear history, e.g. when the line is lost.
+    pub fn reset(&mut self) {
+        self.len = 0;
+        self.next = 0;
+    }
+
+    /// Record `position` and return the base speed for this tick.
+    /// Stays at `min_speed` until the history has filled after a reset,
+    /// so the robot starts conservatively right after re-acquiring the line.
+    pub fn update(&mut self, position: i32) -> i32 {
+        self.history[self.next] = position;
+        self.next = (self.next + 1) % SPEED_HISTORY_LEN;
+        self.len = (self.len + 1).min(SPEED_HISTORY_LEN);
+
+        if self.len < SPEED_HISTORY_LEN {
+            return self.min_speed;
+        }
+
+        let n = SPEED_HISTORY_LEN as i64;
+        let mean = self.history.iter().map(|&p| p as i64).sum::<i64>() / n;
+        let variance = self.history.iter()
+            .map(|&p| (p as i64 - mean).pow(2))
+            .sum::<i64>() / n;
+        let std_dev = (variance as u64).isqrt() as i64;
+
+        let curviness = (std_dev - STRAIGHT_STD_DEV).clamp(0, CURVY_STD_DEV - STRAIGHT_STD_DEV);
+        let range = (self.max_speed - self.min_speed) as i64;
+        self.max_speed - (curviness * range / (CURVY_STD_DEV - STRAIGHT_STD_DEV)) as i32
+    }
+}
+
+impl Default for SpeedModulator {
+    /// 50% on curvy sections up to 90% on straights
+    fn default() -> Self {
+        Self::new(50, 90)
+    }
+}
+
+/// Continuous base speed scaling from how well the line is seen: full speed
+/// for a strong line at the center, down to `min_pct` of it for a faint line
+/// or one at `edge_position`, with no steps in between.
+#[derive(Clone, Copy, Debug)]
+pub struct LineSpeedScale {
+    /// Share of the base speed (percent) kept at the worst end (100 = no scaling)
+    pub min_pct: u8,
+    /// Line position (absolute) at which the position factor bottoms out
+    pub edge_position: i32,
+    /// Intensity at or above which the line counts as fully seen (1000 = one
+    /// sensor fully on the line)
+    pub full_intensity: u32,
+}
+
+impl LineSpeedScale {
+    /// Scale down to `min_pct` at the start of the emergency zone (2500) or
+    /// below one sensor's worth of intensity.
+    pub const fn new(min_pct: u8) -> Self {
+        Self { min_pct, edge_position: 2500, full_intensity: 1000 }
+    }
+
+    /// Whether scaling does anything.
+    pub fn is_enabled(&self) -> bool {
+        self.min_pct < 100
+    }
+
+    /// Base speed for a line at `position` read with `intensity`: the
+    /// centering and intensity factors (0-1000 each) are multiplied, then
+    /// mapped linearly between `min_pct` and 100% of `base`.
+    pub fn scale(&self, base: i32, position: i32, intensity: u32) -> i32 {
+        let edge = self.edge_position.max(1);
+        let centered = 1000 - position.abs().min(edge) * 1000 / edge;
+        let full = self.full_intensity.max(1);
+        let seen = (intensity.min(full) * 1000 / full) as i32;
+        let quality = centered * seen / 1000;
+        let min = self.min_pct.min(100) as i32 * 10;
+        base * (min + (1000 - min) * quality / 1000) / 1000
+    }
+}
+
+/// How the line follower tries to get back onto a lost line
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RecoveryStrategy {
+    /// Start the widening pivot search right away; best on sharp tracks
+    Pivot,
+    /// Keep arcing forward on the last steering bias at reduced speed for a
+    /// short window, then escalate to the pivot search; best on smooth curves
+    Arc,
+}
+
+impl RecoveryStrategy {
+    /// Wire value used by `cmd::SET_RECOVERY` (0=pivot, 1=arc)
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(RecoveryStrategy::Pivot),
+            1 => Some(RecoveryStrategy::Arc),
+            _ => None,
+        }
+    }
+
+    pub fn as_byte(self) -> u8 {
+        self as u8
+    }
+}
+
+/// What the line follower is doing about a lost line, as reported in
+/// telemetry (0 = on the line)
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SearchState {
+    /// Just lost: arcing on the last steering or creeping forward, the line
+    /// is probably between sensors or just past them
+    Searching = 1,
+    /// Backing up, as the line may be behind the sensors
+    ReversingToFind = 2,
+    /// Pivoting through the widening sweeps
+    Sweeping = 3,
+    /// Out of sweeps or past the search timeout; the run ends
+    GaveUp = 4,
+}
+
+impl SearchState {
+    /// Wire value, 0 while the line is seen
+    pub fn as_byte(state: Option<Self>) -> u8 {
+        state.map_or(0, |state| state as u8)
+    }
+}
+
+/// Widening-sweep search used when the line is lost.
+///
+/// If the line was lost while centered (e.g. a gap in the tape) the robot
+/// first creeps forward for a moment. Then it pivots one way for T ms, the
+/// other way for 2T, then 3T, etc. Each sweep swings back past the starting
+/// heading and further out the other side, so a line behind the robot is
+/// eventually crossed.
+///
+/// Once the line has been lost for `REVERSE_AFTER_MS` (the first sweep out
+/// and back found nothing) the robot backs up briefly, since after
+/// overshooting a sharp turn the line is behind the sensors, then resumes
+/// the sweep where it left off.
+pub struct LineSearcher {
+    /// Duration of the first sweep (T)
+    sweep_ms: u32,
+    /// Sweeps to try before giving up
+    max_sweeps: u32,
+    /// Pivot speed percentage
+    turn_speed: u8,
+    /// Forward creep speed percentage, used when lost while centered
+    forward_speed: u8,
+    /// Search start time, first sweep direction (-1=left, 1=right), and
+    /// whether to creep forward first
+    active: Option<(Instant, i8, bool)>,
+    /// When the back-up started, if it has
+    reverse_since: Option<Instant>,
+    /// The back-up is done for this search
+    reversed: bool,
+    /// Phase of the last `update`
+    state: SearchState,
+}
+
+/// How long to creep forward before sweeping when lost while centered
+const FORWARD_SEARCH_MS: u32 = 300;
+
+/// Search time before backing up: the first two sweeps (T + 2T at the
+/// default 300ms), i.e. both sides have been looked at
+const REVERSE_AFTER_MS: u32 = 900;
+/// How long to back up
+const REVERSE_MS: u32 = 250;
+/// Back-up speed percentage, low so the line isn't overshot again
+const REVERSE_SPEED: u8 = 35;
+
+impl LineSearcher {
+    pub const fn new(sweep_ms: u32, max_sweeps: u32, turn_speed: u8, forward_speed: u8) -> Self {
+        Self {
+            sweep_ms,
+            max_sweeps,
+            turn_speed,
+            forward_speed,
+            active: None,
+            reverse_since: None,
+            reversed: false,
+            state: SearchState::Searching,
+        }
+    }
+
+    /// Set pivot and forward-creep speeds (percent, clamped to 100).
+    pub fn set_speeds(&mut self, turn_speed: u8, forward_speed: u8) {
+        self.turn_speed = turn_speed.min(100);
+        self.forward_speed = forward_speed.min(100);
+    }
+
+    /// Whether a search is in progress.
+    pub fn is_active(&self) -> bool {
+        self.active.is_some()
+    }
+
+    /// What the last `update` did: creep, back up, sweep, or give up.
+    pub fn state(&self) -> SearchState {
+        self.state
+    }
+
+    /// Begin searching toward `direction` (-1=left, 1=right).
+    /// 0 means the line was centered: creep forward first, then start left.
+    pub fn start(&mut self, now: Instant, direction: i8) {
+        let forward_first = direction == 0;
+        let direction = if direction > 0 { 1 } else { -1 };
+        self.active = Some((now, direction, forward_first));
+        self.reverse_since = None;
+        self.reversed = false;
+    }
+
+    /// Stop searching (line re-acquired or mode changed).
+    pub fn reset(&mut self) {
+        self.active = None;
+        self.reverse_since = None;
+        self.reversed = false;
+    }
+
+    /// Motor command for the current point of the search.
+    /// Returns None once `max_sweeps` have completed without finding the line.
+    pub fn update(&mut self, now: Instant) -> Option<MotorCommand> {
+        let (start, direction, forward_first) = self.active?;
+        let mut elapsed = (now - start).as_millis() as u32;
+
+        // Lost for a while: the line may be behind us, back up once
+        if !self.reversed && elapsed >= REVERSE_AFTER_MS {
+            let since = *self.reverse_since.get_or_insert(now);
+            let reversing = now - since;
+            if reversing.as_millis() < REVERSE_MS as u64 {
+                self.state = SearchState::ReversingToFind;
+                return Some(MotorCommand::Backward(REVERSE_SPEED));
+            }
+            // Resume the sweep where it left off
+            self.reversed = true;
+            self.active = Some((start + reversing, direction, forward_first));
+            elapsed -= reversing.as_millis() as u32;
+        }
+
+        if forward_first {
+            if elapsed < FORWARD_SEARCH_MS {
+                self.state = SearchState::Searching;
+                return Some(MotorCommand::Forward(self.forward_speed));
+            }
+            elapsed -= FORWARD_SEARCH_MS;
+        }
+
+        // Sweep k (1-based) lasts k*T and ends at T * k(k+1)/2
+        let mut sweep = 1;
+        let mut sweep_end = self.sweep_ms;
+        while elapsed >= sweep_end {
+            sweep += 1;
+            if sweep > self.max_sweeps {
+                self.active = None;
+                self.state = SearchState::GaveUp;
+                return None;
+            }
+            sweep_end += sweep * self.sweep_ms;
+        }
+
+        // Odd sweeps go toward the first direction, even sweeps come back
+        self.state = SearchState::Sweeping;
+        let left = (direction < 0) == (sweep % 2 == 1);
+        Some(if left {
+            MotorCommand::TurnLeft(self.turn_speed)
+        } else {
+            MotorCommand::TurnRight(self.turn_speed)
+        })
+    }
+}
+
+impl Default for LineSearcher {
+    /// 300ms first sweep pivoting at 55%, forward creep at 50%,
+    /// giving up after 6 sweeps (~6.3s, plus the 250ms back-up)
+    fn default() -> Self {
+        Self::new(300, 6, 55, 50)
+    }
+}
+
+/// Junction debouncing: a full line or branch only counts once the same
+/// junction has been read on `confirm_ticks` control ticks in a row, so a
+/// single noisy sweep doesn't make the robot drive blind.
+pub struct JunctionFilter {
+    confirm_ticks: u8,
+    /// Junction read on the last tick and for how many ticks in a row
+    pending: Option<(LineEvent, u8)>,
+}
+
+impl JunctionFilter {
+    pub const fn new(confirm_ticks: u8) -> Self {
+        Self { confirm_ticks, pending: None }
+    }
+
+    /// Forget any partially observed junction.
+    pub fn reset(&mut self) {
+        self.pending = None;
+    }
+
+    /// Feed this tick's classification. Returns the junction once confirmed,
+    /// None for an ordinary (or lost) line.
+    pub fn update(&mut self, event: LineEvent) -> Option<LineEvent> {
+        if !matches!(event, LineEvent::FullLine | LineEvent::LeftBranch | LineEvent::RightBranch) {
+            self.pending = None;
+            return None;
+        }
+        let ticks = match self.pending {
+            Some((last, ticks)) if last == event => ticks.saturating_add(1),
+            _ => 1,
+        };
+        self.pending = Some((event, ticks));
+        (ticks >= self.confirm_ticks).then_some(event)
+    }
+}
+
+/// Finish-line detector.
+///
+/// The finish is a thick bar across the track, so every sensor sees the line
+/// for much longer than at a normal cross intersection. The bar only counts
+/// once all sensors have stayed active for `sustain_ms` (0 = never).
+pub struct FinishDetector {
+    sustain_ms: u64,
+    /// When all sensors first became active
+    since: Option<Instant>,
+}
+
+impl FinishDetector {
+    pub const fn new(sustain_ms: u64) -> Self {
+        Self { sustain_ms, since: None }
+    }
+
+    /// Change how long the bar must be seen (ms, 0 = never).
+    pub fn set_sustain(&mut self, sustain_ms: u64) {
+        self.sustain_ms = sustain_ms;
+        self.reset();
+    }
+
+    /// Forget any partially observed bar.
+    pub fn reset(&mut self) {
+        self.since = None;
+    }
+
+    /// Feed the junction state for this tick. Returns true once the finish is confirmed.
+    pub fn update(&mut self, all_active: bool, now: Instant) -> bool {
+        if !all_active || self.sustain_ms == 0 {
+            self.since = None;
+            return false;
+        }
+        let since = *self.since.get_or_insert(now);
+        (now - since).as_millis() >= self.sustain_ms
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn small_errors_still_integrate() {
+        // ki = 1.00, no P or D
+        let mut pid = PidController::new(0, 100, 0);
+        pid.set_kind(ControllerKind::PI);
+        // 50 units off center at a 10 ms period: 500 error x ms per tick
+        let output = (0..400).map(|_| pid.update(50, 10)).last().unwrap();
+        // 4 s x 50 = 200 error x s, x ki 1.00 / OUTPUT_SCALE x 10
+        assert_eq!(output, 20);
+    }
+}
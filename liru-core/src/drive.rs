@@ -0,0 +1,109 @@
+//! Motor commands and the wheel speeds they mean, kept apart from the PWM
+//! driver (`motors.rs` in the firmware) so the state machine's output can be
+//! applied to a simulated robot too.
+
+/// What the wheels are doing, classified from the last commanded speeds
+/// (wire values of `msg::DEBUG`'s motor action byte)
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum MotorAction {
+    Stop = 0,
+    /// Both wheels forward at the same speed
+    Forward = 1,
+    /// Pivot left: left wheel stopped or reversing, right wheel forward
+    Left = 2,
+    /// Pivot right: right wheel stopped or reversing, left wheel forward
+    Right = 3,
+    /// Both wheels reversing
+    Reverse = 4,
+    /// Both wheels forward, right faster
+    ArcLeft = 5,
+    /// Both wheels forward, left faster
+    ArcRight = 6,
+}
+
+/// High-level motor output, as requested by the robot state machine
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MotorCommand {
+    /// Differential drive, -100 to 100 per side
+    Set { left: i8, right: i8 },
+    /// Car mode tank drive (`MOTOR`), -100 to 100 per side: `Set` from the
+    /// driver, which the firmware may ramp to instead of jumping
+    Tank { left: i8, right: i8 },
+    /// Both motors forward at speed percentage
+    Forward(u8),
+    /// Both motors reverse at speed percentage
+    Backward(u8),
+    /// Pivot left at speed percentage
+    TurnLeft(u8),
+    /// Pivot right at speed percentage
+    TurnRight(u8),
+    /// Proportional drive: linear (forward +) and angular (right +), -100 to 100
+    Drive { linear: i8, angular: i8 },
+    /// Differential drive in tenths of a percent, -1000 to 1000 per side;
+    /// the fraction only survives with dithering on (see
+    /// `MotorController::set_dithering` in the firmware)
+    SetFine { left: i16, right: i16 },
+    /// Stop all motors (coast)
+    Stop,
+    /// Stop all motors hard (short brake), e.g. on the line
+    Brake,
+}
+
+impl MotorCommand {
+    /// Wheel speeds (left, right) the command asks for, in tenths of a
+    /// percent (-1000 to 1000), before the driver's limits, dithering and
+    /// reversal guard. Stop and brake both mean 0.
+    pub fn wheel_speeds(self) -> [i16; 2] {
+        let [left, right] = match self {
+            MotorCommand::SetFine { left, right } => return [left.clamp(-1000, 1000), right.clamp(-1000, 1000)],
+            MotorCommand::Set { left, right } | MotorCommand::Tank { left, right } => [left, right],
+            MotorCommand::Forward(speed) => [speed.min(100) as i8; 2],
+            MotorCommand::Backward(speed) => [-(speed.min(100) as i8); 2],
+            MotorCommand::TurnLeft(speed) => [-(speed.min(100) as i8), speed.min(100) as i8],
+            MotorCommand::TurnRight(speed) => [speed.min(100) as i8, -(speed.min(100) as i8)],
+            MotorCommand::Drive { linear, angular } => mix_drive(linear, angular),
+            MotorCommand::Stop | MotorCommand::Brake => [0, 0],
+        };
+        [left.clamp(-100, 100) as i16 * 10, right.clamp(-100, 100) as i16 * 10]
+    }
+}
+
+/// Mix a joystick-style `linear` forward/back and `angular` turn rate
+/// (positive = right), each -100 to 100, into wheel speeds (left, right),
+/// scaled down together if either would exceed 100 so the turn ratio is kept.
+pub fn mix_drive(linear: i8, angular: i8) -> [i8; 2] {
+    let linear = (linear as i32).clamp(-100, 100);
+    let angular = (angular as i32).clamp(-100, 100);
+    let mut left = linear + angular;
+    let mut right = linear - angular;
+
+    let peak = left.abs().max(right.abs());
+    if peak > 100 {
+        left = left * 100 / peak;
+        right = right * 100 / peak;
+    }
+    [left as i8, right as i8]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drive_keeps_the_turn_ratio() {
+        assert_eq!(mix_drive(50, 20), [70, 30]);
+        // 120 / 20 scaled down to 100
+        assert_eq!(mix_drive(70, 50), [100, 16]);
+        assert_eq!(MotorCommand::Drive { linear: 70, angular: 50 }.wheel_speeds(), [1000, 160]);
+    }
+
+    #[test]
+    fn pivots_and_stops() {
+        assert_eq!(MotorCommand::TurnLeft(60).wheel_speeds(), [-600, 600]);
+        assert_eq!(MotorCommand::TurnRight(120).wheel_speeds(), [1000, -1000]);
+        assert_eq!(MotorCommand::Brake.wheel_speeds(), [0, 0]);
+        assert_eq!(MotorCommand::Tank { left: -30, right: 120 }.wheel_speeds(), [-300, 1000]);
+        assert_eq!(MotorCommand::SetFine { left: 455, right: -1200 }.wheel_speeds(), [455, -1000]);
+    }
+}
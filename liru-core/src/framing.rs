@@ -0,0 +1,259 @@
+//! Link framing shared by both directions of the Bluetooth protocol.
+//!
+//! Every message ends with a one-byte XOR checksum of the bytes before it,
+//! then is COBS-encoded and terminated by a 0x00 delimiter, so a dropped
+//! byte only corrupts one frame and the stream resynchronizes at the next
+//! delimiter.
+//!
+//! For poking the robot from a plain serial terminal, a frame starting with
+//! a letter is instead read as a newline-terminated text line. Binary frames
+//! never start with a letter: the COBS code byte of a command frame is at
+//! most its length + 1. They can start with CR (0x0D) or LF (0x0A) though,
+//! so those are only skipped as the rest of a text line's ending.
+
+/// Largest decoded message (command or outgoing message, checksum included)
+/// in bytes
+pub const MAX_FRAME_LEN: usize = 64;
+
+/// Largest COBS-encoded frame, excluding the 0x00 delimiter
+pub const MAX_ENCODED_LEN: usize = MAX_FRAME_LEN + MAX_FRAME_LEN / 254 + 1;
+
+/// Frame delimiter
+pub const FRAME_DELIMITER: u8 = 0x00;
+
+/// A complete frame out of `FrameParser::push_byte`
+#[derive(Debug, PartialEq)]
+pub enum Frame<'a> {
+    /// Decoded binary frame, its checksum verified and stripped
+    Binary(&'a [u8]),
+    /// Text command line, without its line ending
+    Text(&'a [u8]),
+}
+
+/// Reassembles received bytes into frames.
+pub struct FrameParser {
+    /// Encoded bytes of the frame currently being received
+    frame: [u8; MAX_ENCODED_LEN],
+    len: usize,
+    /// Set when a frame overflowed or lost a byte; bytes are dropped until
+    /// the next delimiter
+    overflow: bool,
+    /// The frame being received is a text command line
+    text: bool,
+    /// The last text line ended on CR or LF: more of them are the rest of
+    /// its line ending (the LF of CR LF), not the start of a binary frame
+    prev_was_text_eol: bool,
+    decoded: [u8; MAX_ENCODED_LEN],
+    /// Frames dropped for a checksum mismatch (saturating)
+    checksum_errors: u16,
+}
+
+impl FrameParser {
+    pub const fn new() -> Self {
+        Self {
+            frame: [0; MAX_ENCODED_LEN],
+            len: 0,
+            overflow: false,
+            text: false,
+            prev_was_text_eol: false,
+            decoded: [0; MAX_ENCODED_LEN],
+            checksum_errors: 0,
+        }
+    }
+
+    /// Frames dropped so far because their checksum didn't match
+    pub fn checksum_errors(&self) -> u16 {
+        self.checksum_errors
+    }
+
+    /// Drop the frame being received (e.g. after a UART error corrupted it),
+    /// ignoring bytes until the next delimiter.
+    pub fn drop_frame(&mut self) {
+        if self.len > 0 {
+            self.len = 0;
+            self.overflow = true;
+        }
+    }
+
+    /// Feed one received byte. Returns a frame once a complete, valid one
+    /// has been received.
+    pub fn push_byte(&mut self, byte: u8) -> Option<Frame<'_>> {
+        let eol = byte == b'\r' || byte == b'\n';
+        if self.len == 0 && !self.overflow && !self.text {
+            if self.prev_was_text_eol && eol {
+                return None;
+            }
+            self.text = byte.is_ascii_alphabetic();
+        }
+        self.prev_was_text_eol = false;
+
+        let end_of_frame = byte == FRAME_DELIMITER || (self.text && eol);
+        if !end_of_frame {
+            if self.len < self.frame.len() {
+                self.frame[self.len] = byte;
+                self.len += 1;
+            } else {
+                self.overflow = true;
+            }
+            return None;
+        }
+
+        let len = self.len;
+        let overflow = self.overflow;
+        let text = self.text;
+        self.len = 0;
+        self.overflow = false;
+        self.text = false;
+        self.prev_was_text_eol = text && eol;
+        if len == 0 || overflow {
+            return None;
+        }
+        if text {
+            return Some(Frame::Text(&self.frame[..len]));
+        }
+
+        let n = cobs_decode(&self.frame[..len], &mut self.decoded)?;
+        let (&sum, frame) = self.decoded[..n].split_last()?;
+        if checksum(frame) != sum {
+            // Never act on a corrupted frame: a damaged MOTOR could drive off
+            self.checksum_errors = self.checksum_errors.saturating_add(1);
+            return None;
+        }
+        Some(Frame::Binary(frame))
+    }
+}
+
+impl Default for FrameParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// XOR of every byte, appended to each frame before COBS encoding.
+pub fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &byte| sum ^ byte)
+}
+
+/// COBS-encode `src` into `dst`, without the trailing delimiter.
+/// Returns the encoded length. `dst` must hold at least
+/// `src.len() + src.len() / 254 + 1` bytes.
+pub fn cobs_encode(src: &[u8], dst: &mut [u8]) -> usize {
+    let mut code_idx = 0;
+    let mut out = 1;
+    let mut code: u8 = 1;
+
+    for &byte in src {
+        if byte == 0 {
+            dst[code_idx] = code;
+            code_idx = out;
+            out += 1;
+            code = 1;
+        } else {
+            dst[out] = byte;
+            out += 1;
+            code += 1;
+            if code == 0xFF {
+                dst[code_idx] = code;
+                code_idx = out;
+                out += 1;
+                code = 1;
+            }
+        }
+    }
+    dst[code_idx] = code;
+    out
+}
+
+/// Decode a COBS frame (without delimiter) into `dst`.
+/// Returns the decoded length, or None if the frame is malformed
+/// or does not fit in `dst`.
+pub fn cobs_decode(src: &[u8], dst: &mut [u8]) -> Option<usize> {
+    let mut i = 0;
+    let mut out = 0;
+
+    while i < src.len() {
+        let code = src[i] as usize;
+        if code == 0 {
+            return None;
+        }
+        i += 1;
+
+        let end = i + code - 1;
+        if end > src.len() {
+            return None;
+        }
+        let run = &src[i..end];
+        dst.get_mut(out..out + run.len())?.copy_from_slice(run);
+        out += run.len();
+        i = end;
+
+        // A code below 0xFF implies a zero, except at the very end of the frame
+        if code < 0xFF && i < src.len() {
+            *dst.get_mut(out)? = 0;
+            out += 1;
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `SET_THRESHOLDS` (0x26) with its eight little-endian thresholds
+    fn set_thresholds(thresholds: [u16; 8]) -> [u8; 17] {
+        let mut frame = [0x26; 17];
+        for (i, threshold) in thresholds.iter().enumerate() {
+            frame[1 + i * 2..3 + i * 2].copy_from_slice(&threshold.to_le_bytes());
+        }
+        frame
+    }
+
+    /// Checksum, COBS and the delimiter, as the bridge sends a frame
+    fn encode(frame: &[u8], out: &mut [u8]) -> usize {
+        let mut message = [0u8; MAX_FRAME_LEN];
+        message[..frame.len()].copy_from_slice(frame);
+        message[frame.len()] = checksum(frame);
+        let len = cobs_encode(&message[..frame.len() + 1], out);
+        out[len] = FRAME_DELIMITER;
+        len + 1
+    }
+
+    fn push_all<'a>(parser: &'a mut FrameParser, bytes: &[u8]) -> Option<Frame<'a>> {
+        let (&last, rest) = bytes.split_last()?;
+        for &byte in rest {
+            assert_eq!(parser.push_byte(byte), None);
+        }
+        parser.push_byte(last)
+    }
+
+    #[test]
+    fn frames_starting_with_line_ending_codes() {
+        // First zero at index 9 (code LF) and at index 12 (code CR)
+        let frames = [
+            set_thresholds([1000, 1000, 1000, 1000, 2048, 1000, 1000, 1000]),
+            set_thresholds([1000, 1000, 1000, 1000, 1000, 200, 1000, 1000]),
+        ];
+        // Back to back, as the GUI streams them
+        let mut parser = FrameParser::new();
+        for _ in 0..2 {
+            for (frame, code) in frames.iter().zip([b'\n', b'\r']) {
+                let mut encoded = [0u8; MAX_ENCODED_LEN + 1];
+                let len = encode(frame, &mut encoded);
+                assert_eq!(encoded[0], code);
+                assert_eq!(push_all(&mut parser, &encoded[..len]), Some(Frame::Binary(&frame[..])));
+            }
+        }
+    }
+
+    #[test]
+    fn text_line_endings_are_skipped() {
+        let mut parser = FrameParser::new();
+        assert_eq!(push_all(&mut parser, b"STOP\r"), Some(Frame::Text(b"STOP")));
+        // The LF of CR LF and blank lines
+        for &byte in b"\n\r\n" {
+            assert_eq!(parser.push_byte(byte), None);
+        }
+        assert_eq!(push_all(&mut parser, b"GET SENS\n"), Some(Frame::Text(b"GET SENS")));
+    }
+}
@@ -0,0 +1,76 @@
+//! Status LED patterns for LiRu robot.
+//!
+//! The onboard LED (PA5) encodes the robot mode so its state is visible
+//! without the dashboard, and whether the GUI is connected. Each pattern is a
+//! repeating list of on/off steps.
+
+/// LED pattern shown for the current robot state
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LedPattern {
+    /// Car mode: steady heartbeat pulse once a second
+    Heartbeat,
+    /// Line follower idle: 1 Hz blink
+    SlowBlink,
+    /// Calibrating: 5 Hz blink
+    FastBlink,
+    /// Running: two short flashes then a pause
+    DoubleBlink,
+    /// Running, paused: long on, short off
+    Paused,
+    /// Error (line lost, failed self-test): rapid SOS (... --- ...)
+    Sos,
+    /// Emergency stop latched: three quick flashes then a pause
+    EStop,
+    /// No GUI connected: a brief blip every few seconds
+    Disconnected,
+    /// Shut down and parked: a brief blip every second
+    Parked,
+}
+
+/// Short SOS element (dot) and long element (dash) in ms
+const DOT_MS: u64 = 80;
+const DASH_MS: u64 = 240;
+
+const HEARTBEAT: &[(bool, u64)] = &[(true, 200), (false, 800)];
+const DISCONNECTED: &[(bool, u64)] = &[(true, 50), (false, 2950)];
+const PARKED: &[(bool, u64)] = &[(true, 50), (false, 950)];
+const SLOW_BLINK: &[(bool, u64)] = &[(true, 500), (false, 500)];
+const FAST_BLINK: &[(bool, u64)] = &[(true, 100), (false, 100)];
+const DOUBLE_BLINK: &[(bool, u64)] = &[(true, 100), (false, 100), (true, 100), (false, 700)];
+const PAUSED: &[(bool, u64)] = &[(true, 1500), (false, 300)];
+const ESTOP: &[(bool, u64)] = &[
+    (true, 50), (false, 50), (true, 50), (false, 50), (true, 50), (false, 400),
+];
+const SOS: &[(bool, u64)] = &[
+    (true, DOT_MS), (false, DOT_MS), (true, DOT_MS), (false, DOT_MS), (true, DOT_MS), (false, DASH_MS),
+    (true, DASH_MS), (false, DOT_MS), (true, DASH_MS), (false, DOT_MS), (true, DASH_MS), (false, DASH_MS),
+    (true, DOT_MS), (false, DOT_MS), (true, DOT_MS), (false, DOT_MS), (true, DOT_MS), (false, 600),
+];
+
+impl LedPattern {
+    /// Steps of the pattern as (LED on, duration in ms), repeated forever.
+    pub fn steps(self) -> &'static [(bool, u64)] {
+        match self {
+            LedPattern::Heartbeat => HEARTBEAT,
+            LedPattern::SlowBlink => SLOW_BLINK,
+            LedPattern::FastBlink => FAST_BLINK,
+            LedPattern::DoubleBlink => DOUBLE_BLINK,
+            LedPattern::Paused => PAUSED,
+            LedPattern::Sos => SOS,
+            LedPattern::EStop => ESTOP,
+            LedPattern::Disconnected => DISCONNECTED,
+            LedPattern::Parked => PARKED,
+        }
+    }
+
+    /// Pattern to actually show given the connection state: without a GUI
+    /// only the error and E-stop patterns stay, everything else turns into
+    /// the disconnected blip so a failed pairing is obvious.
+    pub fn with_link(self, connected: bool) -> Self {
+        match self {
+            LedPattern::Sos | LedPattern::EStop => self,
+            _ if !connected => LedPattern::Disconnected,
+            _ => self,
+        }
+    }
+}
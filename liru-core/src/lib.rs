@@ -0,0 +1,66 @@
+//! Hardware-free core of the LiRu firmware.
+//!
+//! Builds for the robot, where the firmware binary (`main.rs`) drives it
+//! from the ADC, motors and Bluetooth, and for the host, where its unit
+//! tests run:
+//!
+//! ```text
+//! cargo test --lib --target x86_64-unknown-linux-gnu
+//! ```
+
+#![cfg_attr(not(test), no_std)]
+
+pub mod array;
+pub mod config;
+pub mod control;
+pub mod drive;
+pub mod framing;
+pub mod state;
+pub mod led;
+pub mod line;
+#[cfg(feature = "obstacle-sensor")]
+pub mod obstacle;
+pub mod protocol;
+
+/// Host tests have no probe to log to: defmt output is discarded.
+#[cfg(test)]
+mod discard_log {
+    #[defmt::global_logger]
+    struct Discard;
+
+    unsafe impl defmt::Logger for Discard {
+        fn acquire() {}
+        unsafe fn flush() {}
+        unsafe fn release() {}
+        unsafe fn write(_bytes: &[u8]) {}
+    }
+
+    defmt::timestamp!("");
+}
+
+/// Host tests have no hardware timer: time jumps straight to the next
+/// wake-up, so timed waits return at once.
+#[cfg(test)]
+mod test_clock {
+    use core::sync::atomic::{AtomicU64, Ordering};
+    use core::task::Waker;
+
+    use embassy_time_driver::Driver;
+
+    struct TestClock {
+        ticks: AtomicU64,
+    }
+
+    impl Driver for TestClock {
+        fn now(&self) -> u64 {
+            self.ticks.load(Ordering::Relaxed)
+        }
+
+        fn schedule_wake(&self, at: u64, waker: &Waker) {
+            self.ticks.fetch_max(at, Ordering::Relaxed);
+            waker.wake_by_ref();
+        }
+    }
+
+    embassy_time_driver::time_driver_impl!(static CLOCK: TestClock = TestClock { ticks: AtomicU64::new(0) });
+}
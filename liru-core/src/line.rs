@@ -0,0 +1,483 @@
+//! Line sensing math, kept apart from the ADC (see `sensors.rs` in the
+//! firmware) so it builds for the host and can be checked against
+//! hand-made readings.
+//!
+//! Raw readings (0-4095) are normalized to 0-1000 between the calibrated
+//! min and max of each channel; the line position is their weighted
+//! average, 1000 per sensor spacing and centered on 0.
+
+/// Number of sensors in the default (HY-S301) array, and the only width the
+/// state machine and the Bluetooth protocol support.
+pub const SENSOR_COUNT: usize = 8;
+
+/// Raw ADC readings (0-4095), one per channel.
+pub type SensorReadings<const N: usize = SENSOR_COUNT> = [u16; N];
+
+/// Minimum raw min/max spread for a channel to count as calibrated.
+/// Anything less means the sensor never saw both the line and the background.
+const MIN_CALIBRATION_RANGE: u16 = 300;
+
+/// Line spread (weighted std dev, 1000 per sensor spacing) at or below
+/// which the line counts as one tight cluster (confidence 100).
+const TIGHT_SPREAD: i64 = 600;
+
+/// Line spread at or above which confidence drops to 0.
+const WIDE_SPREAD: i64 = 2000;
+
+/// Points in a response curve, evenly spaced over the normalized 0-1000 range
+/// (every 125).
+pub const RESPONSE_CURVE_POINTS: usize = 9;
+
+/// Piecewise-linear correction of normalized readings: point `k` is the
+/// corrected value (0-1000) for a normalized reading of `k * 125`.
+pub type ResponseCurve = [u16; RESPONSE_CURVE_POINTS];
+
+/// Normalized reading (of 1000) at or below which a channel sees plain background.
+pub const WHITE_LEVEL: u32 = 100;
+
+/// What the sensor array is over, judged from calibrated readings
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum SurfaceState {
+    /// At least one channel clearly sees the line
+    OnLine,
+    /// Every channel is near its calibrated minimum: only background below
+    OffLineWhite,
+    /// In between (e.g. line between two sensors) or not calibrated yet
+    Unknown,
+}
+
+/// Which calibrated end the threshold margin is measured from
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum ThresholdOrigin {
+    /// Up from the white (min) reading: a low percentage detects the line
+    /// early, at the risk of false detects
+    White,
+    /// Down from the black (max) reading: a low percentage only detects a
+    /// clearly dark line, e.g. on a high-gloss floor that reads grey
+    Black,
+}
+
+impl ThresholdOrigin {
+    /// Wire value used by `cmd::SET_THRESHOLD` (0=white, 1=black)
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(ThresholdOrigin::White),
+            1 => Some(ThresholdOrigin::Black),
+            _ => None,
+        }
+    }
+}
+
+/// Which reads as the line: a dark line on a light mat (high ADC values on
+/// the line) or a light line on a dark mat (low values on the line)
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum LinePolarity {
+    DarkOnLight,
+    LightOnDark,
+}
+
+impl LinePolarity {
+    /// Wire value used by `cmd::SET_POLARITY` (0=dark on light, 1=light on dark)
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Self::DarkOnLight),
+            1 => Some(Self::LightOnDark),
+            _ => None,
+        }
+    }
+}
+
+/// What the array sees, from which channels are over the line
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum LineEvent {
+    /// No line under the array
+    Lost,
+    /// An ordinary line: follow the position
+    Normal { position: i32, intensity: u32 },
+    /// (Nearly) every channel on the line: a crossing, a T seen from its
+    /// stem, or the finish bar
+    FullLine,
+    /// The leftmost channel on the line while the center still tracks: a
+    /// branch joins from the left
+    LeftBranch,
+    /// The rightmost channel on the line while the center still tracks
+    RightBranch,
+}
+
+impl LineEvent {
+    /// Wire value used by `msg::LINE_EVENT` (1=full line, 2=left branch,
+    /// 3=right branch, 0 otherwise)
+    pub fn as_byte(&self) -> u8 {
+        match self {
+            Self::Lost | Self::Normal { .. } => 0,
+            Self::FullLine => 1,
+            Self::LeftBranch => 2,
+            Self::RightBranch => 3,
+        }
+    }
+}
+
+/// Which way round the sensor bar is mounted
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum Orientation {
+    /// Sensor 1 on the robot's left
+    Normal,
+    /// Bar flipped front-to-back: sensor 1 on the robot's right, so the
+    /// channel order is reversed before anything else sees it
+    Reversed,
+}
+
+/// Calibration finished without enough contrast on some channels. Those
+/// channels were given a fallback range and the sensors are still usable,
+/// degraded.
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub struct CalibrationError {
+    /// Bit i set = sensor i+1 range was below `MIN_CALIBRATION_RANGE`
+    pub low_contrast: u16,
+}
+
+/// Current calibration, as reported to the GUI
+#[derive(Clone, Copy, Debug)]
+pub struct CalibrationReport<const N: usize = SENSOR_COUNT> {
+    /// Whether the last calibration succeeded
+    pub calibrated: bool,
+    pub thresholds: SensorReadings<N>,
+    pub min: SensorReadings<N>,
+    pub max: SensorReadings<N>,
+}
+
+/// Raw ADC extremes per channel over a line follower run, kept apart from
+/// the calibration min/max so comparing them shows lighting drift
+#[derive(Clone, Copy, Debug)]
+pub struct RawRange<const N: usize = SENSOR_COUNT> {
+    pub min: SensorReadings<N>,
+    pub max: SensorReadings<N>,
+}
+
+impl<const N: usize> RawRange<N> {
+    /// Widen the range to include `readings`.
+    pub fn update(&mut self, readings: &SensorReadings<N>) {
+        for ((min, max), &value) in self.min.iter_mut().zip(&mut self.max).zip(readings) {
+            *min = (*min).min(value);
+            *max = (*max).max(value);
+        }
+    }
+}
+
+impl<const N: usize> Default for RawRange<N> {
+    /// Empty: min above max until the first reading
+    fn default() -> Self {
+        Self { min: [4095; N], max: [0; N] }
+    }
+}
+
+/// One-shot sensor snapshot for diagnostics: every stage of the pipeline
+/// computed from the same raw read
+#[derive(Clone, Copy, Debug)]
+pub struct SensorDiagnostics<const N: usize = SENSOR_COUNT> {
+    pub raw: SensorReadings<N>,
+    /// Normalized against the calibrated min/max (0-1000)
+    pub normalized: [u16; N],
+    /// Bit i set = sensor i+1 above its threshold
+    pub binary: u16,
+    /// Line position with the center offset applied (0 when the line is lost)
+    pub position: i32,
+    pub intensity: u32,
+}
+
+/// Normalize raw readings to 0-1000 between the calibrated min and max.
+/// Channels with no range (min >= max) read 0 up to min and 1000 above it.
+pub fn normalize<const N: usize>(
+    readings: &SensorReadings<N>,
+    min: &SensorReadings<N>,
+    max: &SensorReadings<N>,
+) -> [u32; N] {
+    let mut values = [0; N];
+
+    for (i, &raw_val) in readings.iter().enumerate() {
+        let min = min[i];
+        let max = max[i];
+
+        // Normalize raw_val to 0-1000
+        values[i] = if raw_val <= min {
+            0
+        } else if raw_val >= max {
+            1000
+        } else {
+            let range = max - min;
+            if range == 0 { 0 } else { (raw_val - min) as u32 * 1000 / range as u32 }
+        };
+    }
+    values
+}
+
+/// Bitmask of the channels over the line (bit i = sensor i+1): above their
+/// threshold for a dark line, below it for a light one. No hysteresis.
+pub fn binary_pattern<const N: usize>(
+    readings: &SensorReadings<N>,
+    thresholds: &SensorReadings<N>,
+    polarity: LinePolarity,
+) -> u16 {
+    readings
+        .iter()
+        .zip(thresholds.iter())
+        .enumerate()
+        .filter(|(_, (value, threshold))| match polarity {
+            LinePolarity::DarkOnLight => value > threshold,
+            LinePolarity::LightOnDark => value < threshold,
+        })
+        .fold(0, |bits, (i, _)| bits | 1 << i)
+}
+
+/// Classify a reading from its binary pattern (bit i = sensor i+1) and its
+/// line position. All but one channel on the line, or both outer channels
+/// with the center, is a full line; one outer channel with the center is a
+/// branch on that side. Single readings are noisy: debounce before acting
+/// (see `control::JunctionFilter`).
+pub fn classify_line<const N: usize>(binary: u16, position: i32, intensity: u32) -> LineEvent {
+    if intensity == 0 {
+        return LineEvent::Lost;
+    }
+    let left = binary & 1 != 0;
+    let right = binary & (1 << (N - 1)) != 0;
+    let center = binary & (0b11 << (N / 2 - 1)) != 0;
+    if binary.count_ones() as usize >= N - 1 || (left && right && center) {
+        LineEvent::FullLine
+    } else if left && center {
+        LineEvent::LeftBranch
+    } else if right && center {
+        LineEvent::RightBranch
+    } else {
+        LineEvent::Normal { position, intensity }
+    }
+}
+
+/// Line position from a binary pattern (bit i = sensor i+1): the average
+/// weight of the active sensors, as if each were fully on the line.
+/// Intensity is 1000 per active sensor and confidence is always 100.
+/// Returns (0, 0, 0) when no sensor is active.
+pub fn fixed_position<const N: usize>(binary: u16, weights: &[i32; N]) -> (i32, u32, u8) {
+    let active = (0..N).filter(|&i| binary & (1 << i) != 0);
+    let (sum, count) = active.fold((0, 0), |(sum, count), i| (sum + weights[i], count + 1));
+    if count == 0 {
+        return (0, 0, 0);
+    }
+    (sum / count, count as u32 * 1000, 100)
+}
+
+/// Share of the array over the line (0-100) from normalized values (0-1000 each).
+pub fn coverage<const N: usize>(values: &[u32; N]) -> u8 {
+    let sum: u32 = values.iter().sum();
+    (sum * 100 / (N as u32 * 1000)).min(100) as u8
+}
+
+/// Map normalized values (0-1000) through a piecewise-linear response curve.
+pub fn apply_response_curve<const N: usize>(values: &mut [u32; N], curve: &ResponseCurve) {
+    const STEP: u32 = 1000 / (RESPONSE_CURVE_POINTS as u32 - 1);
+    for val in values.iter_mut() {
+        let x = (*val).min(1000);
+        let k = ((x / STEP) as usize).min(RESPONSE_CURVE_POINTS - 2);
+        let y0 = curve[k] as i32;
+        let y1 = curve[k + 1] as i32;
+        let dx = (x - k as u32 * STEP) as i32;
+        *val = (y0 + (y1 - y0) * dx / STEP as i32) as u32;
+    }
+}
+
+/// Default position weights: 1000 per sensor spacing, centered on 0
+/// (-3500..3500 for the 8-channel array).
+pub fn linear_weights<const N: usize>() -> [i32; N] {
+    core::array::from_fn(|i| i as i32 * 1000 - (N as i32 - 1) * 500)
+}
+
+/// Weighted line position, intensity and confidence from normalized values
+/// (0-1000 per channel); see `CalibratedSensors::read_line_position_ex`. `weights` maps
+/// each sensor to position units (see `linear_weights`). A nonzero
+/// `expected_width` (tenths of a sensor) limits the position and confidence
+/// to the best matching cluster (see `dominant_cluster`).
+/// Returns (0, 0, 0) when the line is lost.
+pub fn line_position<const N: usize>(
+    mut values: [u32; N],
+    weights: &[i32; N],
+    floor: u16,
+    expected_width: u8,
+) -> (i32, u32, u8) {
+    let total_intensity: u32 = values.iter().sum();
+
+    if total_intensity < 500 {
+         // Line lost (roughly < 0.5 sensor active)
+         return (0, 0, 0);
+    }
+
+    // Stray marks away from the line: only the cluster that looks like it counts
+    if expected_width > 0 {
+        dominant_cluster(&mut values, expected_width as u32);
+    }
+    let line_intensity: u32 = values.iter().sum();
+
+    let floor = floor as u32;
+    let use_floor = values.iter().filter(|&&val| val >= floor).count() >= 2;
+
+    let mut weighted_sum: i64 = 0;
+    let mut centroid_sum: i32 = 0;
+    let mut weight_total: u32 = 0;
+    for (i, &val) in values.iter().enumerate() {
+        if use_floor && val < floor {
+            continue;
+        }
+        weight_total += val;
+        weighted_sum += val as i64 * weights[i] as i64;
+        centroid_sum += val as i32 * (i as i32 * 1000);
+    }
+
+    let position = (weighted_sum / weight_total as i64) as i32;
+
+    // Weighted spread of all channels around the physical centroid
+    // (1000 per sensor spacing, whatever the weights)
+    let centroid = centroid_sum / weight_total as i32;
+    let variance = values.iter().enumerate()
+        .map(|(i, &val)| val as i64 * (i as i64 * 1000 - centroid as i64).pow(2))
+        .sum::<i64>() / line_intensity as i64;
+    let spread = (variance as u64).isqrt() as i64;
+    let confidence = 100 - (spread - TIGHT_SPREAD).clamp(0, WIDE_SPREAD - TIGHT_SPREAD) * 100
+        / (WIDE_SPREAD - TIGHT_SPREAD);
+
+    (position, total_intensity, confidence as u8)
+}
+
+/// Replace each channel in `dead` (bit i = sensor i+1) with the average of
+/// its live neighbors (one neighbor at the ends; 0 if none is live).
+pub fn interpolate_dead<const N: usize>(values: &mut [u32; N], dead: u16) {
+    let live = |i: usize| dead & (1 << i) == 0;
+    let source = *values;
+    for (i, value) in values.iter_mut().enumerate() {
+        if live(i) {
+            continue;
+        }
+        let neighbors = [i.checked_sub(1), Some(i + 1).filter(|&j| j < N)];
+        let (sum, count) = neighbors
+            .into_iter()
+            .flatten()
+            .filter(|&j| live(j))
+            .fold((0, 0), |(sum, count), j| (sum + source[j], count + 1u32));
+        *value = sum.checked_div(count).unwrap_or(0);
+    }
+}
+
+/// Keep only the run of adjacent channels above background (`WHITE_LEVEL`)
+/// whose width, the sum of its normalized values in tenths of a sensor, is
+/// closest to `expected_width`; ties go to the stronger run. The other
+/// channels are zeroed. No change if no channel is above background.
+pub fn dominant_cluster<const N: usize>(values: &mut [u32; N], expected_width: u32) {
+    // (start, end, distance from the expected width, sum)
+    let mut best: Option<(usize, usize, u32, u32)> = None;
+    let mut i = 0;
+    while i < N {
+        if values[i] <= WHITE_LEVEL {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < N && values[i] > WHITE_LEVEL {
+            i += 1;
+        }
+        let sum: u32 = values[start..i].iter().sum();
+        let distance = (sum / 100).abs_diff(expected_width);
+        if best.is_none_or(|(_, _, d, s)| distance < d || (distance == d && sum > s)) {
+            best = Some((start, i, distance, sum));
+        }
+    }
+    if let Some((start, end, _, _)) = best {
+        for (i, value) in values.iter_mut().enumerate() {
+            if !(start..end).contains(&i) {
+                *value = 0;
+            }
+        }
+    }
+}
+
+/// Place each threshold `threshold_pct` of the range in from min (white
+/// origin) or from max (black origin).
+pub fn thresholds<const N: usize>(
+    min: &SensorReadings<N>,
+    max: &SensorReadings<N>,
+    origin: ThresholdOrigin,
+    threshold_pct: u16,
+) -> SensorReadings<N> {
+    core::array::from_fn(|i| {
+        let range = max[i].saturating_sub(min[i]);
+        let margin = (range as u32 * threshold_pct as u32 / 100) as u16;
+        match origin {
+            ThresholdOrigin::White => min[i] + margin,
+            ThresholdOrigin::Black => min[i] + (range - margin),
+        }
+    })
+}
+
+/// Bitmask of channels whose calibrated range is below `MIN_CALIBRATION_RANGE`
+/// (bit i = sensor i+1).
+pub fn low_contrast_mask<const N: usize>(min: &SensorReadings<N>, max: &SensorReadings<N>) -> u16 {
+    let mut low_contrast: u16 = 0;
+    for i in 0..N {
+        let range = max[i].saturating_sub(min[i]);
+        if range < MIN_CALIBRATION_RANGE {
+            low_contrast |= 1 << i;
+        }
+    }
+    low_contrast
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIN: SensorReadings = [200; SENSOR_COUNT];
+    const MAX: SensorReadings = [3000; SENSOR_COUNT];
+
+    fn position(readings: &SensorReadings) -> (i32, u32, u8) {
+        line_position(normalize(readings, &MIN, &MAX), &linear_weights(), 0, 0)
+    }
+
+    #[test]
+    fn centered_line() {
+        let (position, intensity, confidence) = position(&[200, 200, 200, 2600, 2600, 200, 200, 200]);
+        assert_eq!(position, 0);
+        assert_eq!(intensity, 2 * 857);
+        assert_eq!(confidence, 100);
+    }
+
+    #[test]
+    fn far_left_line() {
+        let (position, intensity, _) = position(&[3000, 200, 200, 200, 200, 200, 200, 200]);
+        assert_eq!(position, -3500);
+        assert_eq!(intensity, 1000);
+    }
+
+    #[test]
+    fn line_lost() {
+        assert_eq!(position(&MIN), (0, 0, 0));
+        // Under half a sensor's worth of signal in total is noise, not a line
+        assert_eq!(position(&[400, 200, 200, 200, 200, 200, 200, 400]), (0, 0, 0));
+    }
+
+    #[test]
+    fn zero_range_channels() {
+        let mut min = MIN;
+        let mut max = MAX;
+        min[2] = 1500;
+        max[2] = 1500;
+        min[5] = 1800;
+        max[5] = 1200;
+
+        let below = normalize(&[1000, 1000, 1500, 1000, 1000, 1800, 1000, 1000], &min, &max);
+        assert_eq!((below[2], below[5]), (0, 0));
+        let above = normalize(&[1000, 1000, 1501, 1000, 1000, 1801, 1000, 1000], &min, &max);
+        assert_eq!((above[2], above[5]), (1000, 1000));
+
+        // 40% in from white; a flat channel's threshold sits on its reading
+        let thresholds = thresholds(&min, &max, ThresholdOrigin::White, 40);
+        assert_eq!(thresholds[0], 200 + 1120);
+        assert_eq!((thresholds[2], thresholds[5]), (1500, 1800));
+        assert_eq!(low_contrast_mask(&min, &max), 0b0010_0100);
+    }
+}
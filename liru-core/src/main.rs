@@ -4,65 +4,284 @@
 mod motors;
 mod sensors;
 mod bluetooth;
+mod storage;
+mod selftest;
+#[cfg(feature = "stall-guard")]
+mod stall;
+// The mode logic and everything it runs on is hardware-free (see lib.rs)
+use liru_core::{config, led, state};
 
-use defmt::info;
+use defmt::{info, warn};
 use embassy_executor::Spawner;
 use embassy_stm32::adc::Adc;
+#[cfg(feature = "adc-mux")]
+use embassy_stm32::adc::AdcChannel;
+use embassy_stm32::flash::Flash;
 use embassy_stm32::gpio::{Input, Level, Output, Pull, Speed};
 use embassy_stm32::bind_interrupts;
 use embassy_stm32::usart::{Config as UartConfig, Uart};
+use embassy_stm32::wdg::IndependentWatchdog;
+use embassy_stm32::peripherals::IWDG;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+use embassy_futures::select::{select, select3, Either3};
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
 
 use embassy_stm32::Config;
-use embassy_time::{Timer, Instant};
+use embassy_time::{Duration, Instant, Ticker, Timer};
 use {defmt_rtt as _, panic_probe as _};
 
-use motors::MotorController;
-use sensors::{LineSensors, CalibratedSensors};
-use bluetooth::{Bluetooth, Command};
+use motors::{Motor, MotorCommand, MotorController, StopMode};
+use sensors::{LineSensors, CalibratedSensors, LinePolarity, Orientation, ResponseCurve, SampleTime, SENSOR_COUNT};
+use bluetooth::{cmd, err, Bluetooth, BluetoothRx, Command, LoopPerf, Reply, ReplyQueue};
+use state::{Actions, RobotState};
+use config::RobotConfig;
+use storage::ConfigStore;
+use led::LedPattern;
+use selftest::SelfTestReport;
 
 bind_interrupts!(struct Irqs {
     USART6 => embassy_stm32::usart::InterruptHandler<embassy_stm32::peripherals::USART6>;
 });
 
+/// Stop the robot if no command (or PING) arrives within this window.
+/// The HC-05 STATE pin can stay high after the link silently drops.
+const HEARTBEAT_TIMEOUT_MS: u64 = 1000;
+
+/// Set for HC-05 modules whose STATE pin floats high while unpaired: the
+/// link then only counts as connected once commands arrive, and drops after
+/// the heartbeat timeout.
+const STATE_PIN_FLOATS: bool = false;
+
+/// Independent watchdog timeout. The control loop pets it every iteration
+/// (every 10ms), so only a real hang such as a wedged ADC read lets it
+/// expire. The MCU then resets and the motors come back up at 0 duty.
+const WATCHDOG_TIMEOUT_US: u32 = 500_000;
+
+/// ADC sample time per channel (see `LineSensors::with_sample_time`): longer
+/// settles high-impedance sensor outputs accurately, shorter keeps the sweep fast.
+const ADC_SAMPLE_TIME: SampleTime = SampleTime::CYCLES3;
+
+/// Hysteresis margin (raw ADC counts) around the binary sensor thresholds.
+const BINARY_HYSTERESIS: u16 = 60;
+
+/// Linearization of the sensors' normalized response for the line position
+/// (see `sensors::ResponseCurve`); None keeps the plain normalized values.
+const RESPONSE_CURVE: Option<ResponseCurve> = None;
+
+/// Custom sensor-to-position weights, leftmost sensor first (see
+/// `CalibratedSensors::set_position_weights`); None keeps the linear
+/// -3500..3500 mapping.
+const POSITION_WEIGHTS: Option<[i32; SENSOR_COUNT]> = None;
+
+/// About how many sensors the line covers, in tenths (15 = 1.5 sensors), so
+/// stray marks away from the line are ignored (see
+/// `CalibratedSensors::set_expected_line_sensors`); 0 = off.
+const EXPECTED_LINE_SENSORS: u8 = 0;
+
+/// Keep following with channels the self-test found dead, filling them in
+/// from their neighbors (see `CalibratedSensors::set_dead_channels`)
+/// instead of letting a stuck reading skew the position.
+const INTERPOLATE_DEAD_SENSORS: bool = false;
+
+/// Set to `Reversed` when the sensor bar is mounted front-to-back (sensor 1
+/// on the robot's right); applied on top of `sensors::REMAP`.
+const SENSOR_ORIENTATION: Orientation = Orientation::Normal;
+
+/// `LightOnDark` for a white line on a dark mat (also settable over
+/// Bluetooth, `cmd::SET_POLARITY`).
+const LINE_POLARITY: LinePolarity = LinePolarity::DarkOnLight;
+
+/// Position a truly centered line reads on this robot (sensor bar mounting
+/// misalignment), measure with the `CENTER` command; 0 = bar is centered.
+const CENTER_OFFSET: i32 = 0;
+
+/// Sensor sweeps averaged per calibration update (see
+/// `CalibratedSensors::set_cal_samples`); 1 = a single sweep.
+const CALIBRATION_SAMPLES: u8 = 1;
+
+/// How often battery voltage is pushed to the GUI.
+const BATTERY_REPORT_INTERVAL_MS: u64 = 1000;
+
+/// What motors left at speed 0 do (see `MotorController::set_stop_mode`):
+/// this robot runs on a power bank, so after a few seconds they draw a
+/// 10% keep-alive duty. `StopMode::Coast` for a clean stop on a battery
+/// that doesn't sleep, `StopMode::Brake` for crisp stops.
+const MOTOR_STOP_MODE: StopMode = StopMode::KeepAlive(10);
+
+/// Hardware-protective motor limits (percent): nonzero speeds are raised to
+/// the minimum and everything is capped at the maximum, under whatever the
+/// line follower or GUI asks for. 0/100 = no change.
+const MOTOR_MIN_SPEED: u8 = 0;
+const MOTOR_MAX_SPEED: u8 = 100;
+
+/// Set for a motor whose L298N outputs are wired backwards (it spins in
+/// reverse when driven forward).
+const LEFT_MOTOR_INVERTED: bool = false;
+const RIGHT_MOTOR_INVERTED: bool = false;
+
+/// Dither the line follower's sub-percent wheel speeds across control ticks
+/// (see `MotorController::set_dithering`) for smoother gentle corrections.
+const MOTOR_DITHERING: bool = false;
+
+/// Motor commands never reverse a motor running faster than this (percent)
+/// straight into a reverse faster than this: it stops for one command
+/// first (see `MotorController::set_reversal_guard`). 0 = off.
+const MOTOR_REVERSAL_GUARD: u8 = 0;
+
+/// Ramp Car mode tank drive (`MOTOR` commands, `MotorCommand::Tank`) by at
+/// most this many percent per control loop iteration instead of jumping
+/// straight to the commanded speed (see `MotorController::set_both_target`),
+/// e.g. for a power bank that browns out on a hard start. Stops and the
+/// state machine's own maneuvers (shutdown ramp, recovery arc, calibration
+/// centering) stay immediate. 0 = off.
+const MOTOR_RAMP_STEP: u8 = 0;
+
+/// How often the simple debug view (mode, sensor pattern, motor action) is pushed.
+const DEBUG_REPORT_INTERVAL_MS: u64 = 250;
+
+/// How often the packed status heartbeat is pushed (~5Hz).
+const STATUS_REPORT_INTERVAL_MS: u64 = 200;
+
+/// Supply voltage at or below which the status reports the battery low
+/// (the power bank sagging under USB's 4.75V minimum). Unknown (0) never
+/// counts as low.
+const BATTERY_LOW_MV: u16 = 4600;
+
+/// Supply voltage below which the robot stops and refuses to move (0 =
+/// off), once `BATTERY_CUTOFF_READINGS` reports in a row (a second apart)
+/// read below it, so a brief sag under load doesn't trip it. Motors are
+/// allowed again once a reading is `BATTERY_CUTOFF_HYSTERESIS_MV` above it.
+const BATTERY_CUTOFF_MV: u16 = 4400;
+const BATTERY_CUTOFF_READINGS: u8 = 3;
+const BATTERY_CUTOFF_HYSTERESIS_MV: u16 = 200;
+
+/// Window over which control loop timing is collected for `GET_PERF`.
+const PERF_WINDOW_MS: u64 = 1000;
+
+/// How often the Bluetooth TX task checks the connection state while idle.
+const CONNECTION_POLL_MS: u64 = 10;
+
+/// How long the connection must stay down before the TX task reports the
+/// link as dropped, so a brief STATE pin glitch doesn't stop a run.
+const LINK_DROP_DEBOUNCE_MS: u64 = 100;
+
+/// Commands from the Bluetooth task to the control task.
+static COMMANDS: Channel<CriticalSectionRawMutex, Command, 8> = Channel::new();
+
+/// Replies and telemetry on their way to the Bluetooth TX task. Pushing never
+/// waits; when full, the oldest telemetry is dropped, ACKs and errors never
+/// are (see `ReplyQueue`). 16 entries cover a few control ticks' worth of
+/// replies while the link catches up.
+static REPLIES: ReplyQueue<16> = ReplyQueue::new();
+
+/// Latest LED pattern, published by the control task when it changes.
+static LED_PATTERN: Signal<CriticalSectionRawMutex, LedPattern> = Signal::new();
+
+/// Whether the GUI link is alive, published by the control task when it changes.
+static LINK_CONNECTED: Signal<CriticalSectionRawMutex, bool> = Signal::new();
+
+/// Raised by the TX task when the Bluetooth connection drops (debounced), so
+/// the control task can stop without waiting for the heartbeat timeout.
+static LINK_DROPPED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Current `msg::MODE` value, kept by the control task so the TX task can
+/// announce it to a GUI that connects later.
+static ROBOT_MODE: AtomicU8 = AtomicU8::new(0);
+
+/// Set by the control task once a shutdown starts, so the RX task stops
+/// answering the commands it handles itself.
+static SHUT_DOWN: AtomicBool = AtomicBool::new(false);
+
 defmt::timestamp!("{=u64}", { embassy_time::Instant::now().as_millis() });
 
+/// Plays the current LED pattern, switching as soon as a new pattern or
+/// connection state is signalled.
 #[embassy_executor::task]
 async fn blink_task(mut led: Output<'static>) {
+    let mut pattern = LedPattern::Heartbeat;
+    let mut connected = false;
     loop {
-        led.toggle();
-        Timer::after_millis(500).await;
+        for &(on, ms) in pattern.with_link(connected).steps() {
+            if on {
+                led.set_high();
+            } else {
+                led.set_low();
+            }
+            match select3(Timer::after_millis(ms), LED_PATTERN.wait(), LINK_CONNECTED.wait()).await {
+                Either3::First(()) => continue,
+                Either3::Second(next) => pattern = next,
+                Either3::Third(link) => connected = link,
+            }
+            break;
+        }
     }
 }
 
-#[derive(PartialEq)]
-enum RobotMode {
-    Car,
-    LineFollowerIdle,
-    LineFollowerCalibrating(Instant),
-    LineFollowerRunning,
-}
-
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
     let p = embassy_stm32::init(Config::default());
 
     info!("=== LiRu Robot Controller ===");
 
-    // LED blink task
+    // LED status task
     let led = Output::new(p.PA5, Level::Low, Speed::Low);
     spawner.spawn(blink_task(led)).unwrap();
 
     // Initialize motor controller
     // TIM1: PA8=CH1, PA9=CH2, PA10=CH3, PA11=CH4
+    #[cfg(not(feature = "tb6612"))]
     let mut motors = MotorController::new(p.TIM1, p.PA8, p.PA9, p.PA10, p.PA11);
+    // TB6612: PA8=PWMA, PA9/PB4=AIN1/AIN2, PA10=PWMB, PA11/PB5=BIN1/BIN2
+    #[cfg(feature = "tb6612")]
+    let mut motors = MotorController::new(p.TIM1, p.PA8, p.PA9, p.PB4, p.PA10, p.PA11, p.PB5);
+    motors.set_stop_mode(MOTOR_STOP_MODE);
+    motors.set_limits(MOTOR_MIN_SPEED, MOTOR_MAX_SPEED);
+    motors.set_motor_inverted(Motor::Left, LEFT_MOTOR_INVERTED);
+    motors.set_motor_inverted(Motor::Right, RIGHT_MOTOR_INVERTED);
+    motors.set_dithering(MOTOR_DITHERING);
+    motors.set_reversal_guard(MOTOR_REVERSAL_GUARD);
     info!("Motors initialized");
 
     // Initialize sensors via ADC
     let adc = Adc::new(p.ADC1);
-    let mut sensors = CalibratedSensors::new(LineSensors::new(
+    #[cfg(not(feature = "adc-mux"))]
+    let line_sensors = LineSensors::new(
         adc,
         p.PA0, p.PA1, p.PA4, p.PB0, p.PC1, p.PC0, p.PC3, p.PC2
-    ));
+    );
+    // One CD74HC4067: SIG=PA0, S0-S3=PB12-PB15, sensors 1-8 on C0-C7.
+    // The state machine runs 8 channels, so a wider mux array can't go here
+    #[cfg(feature = "adc-mux")]
+    let line_sensors: LineSensors = LineSensors::from_mux(
+        adc,
+        [
+            Output::new(p.PB12, Level::Low, Speed::Low),
+            Output::new(p.PB13, Level::Low, Speed::Low),
+            Output::new(p.PB14, Level::Low, Speed::Low),
+            Output::new(p.PB15, Level::Low, Speed::Low),
+        ],
+        [p.PA0.degrade_adc()],
+    );
+    let line_sensors = line_sensors.with_sample_time(ADC_SAMPLE_TIME).with_battery(p.PB1);
+    #[cfg(feature = "obstacle-sensor")]
+    let line_sensors = line_sensors.with_obstacle(p.PC4);
+    #[cfg(feature = "stall-guard")]
+    let line_sensors = line_sensors.with_current_sense(p.PA6, p.PA7);
+    let mut sensors = CalibratedSensors::new(line_sensors);
+    sensors.set_hysteresis(BINARY_HYSTERESIS);
+    if let Some(curve) = RESPONSE_CURVE {
+        sensors.set_response_curve(&curve);
+    }
+    if let Some(weights) = POSITION_WEIGHTS {
+        sensors.set_position_weights(&weights);
+    }
+    sensors.set_orientation(SENSOR_ORIENTATION);
+    sensors.set_polarity(LINE_POLARITY);
+    sensors.set_expected_line_sensors(EXPECTED_LINE_SENSORS);
+    sensors.set_center_offset(CENTER_OFFSET);
+    sensors.set_cal_samples(CALIBRATION_SAMPLES);
     info!("Sensors initialized");
 
     // Initialize Bluetooth (USART6)
@@ -81,323 +300,404 @@ async fn main(spawner: Spawner) {
     ).unwrap();
     
     let state_pin = Input::new(p.PB6, Pull::Down);
-    let mut bt = Bluetooth::new(uart, state_pin);
+    let (mut bt, bt_rx) = Bluetooth::new(uart, state_pin);
+    if STATE_PIN_FLOATS {
+        bt.require_activity(Duration::from_millis(HEARTBEAT_TIMEOUT_MS));
+    }
     info!("Bluetooth initialized (9600 baud)");
 
+    // Self-test before accepting commands (motor pulses need the watchdog off)
+    let selftest = selftest::run(&mut motors, &mut sensors).await;
+    if INTERPOLATE_DEAD_SENSORS {
+        sensors.set_dead_channels(selftest.dead_channels as u16);
+    }
+
+    // Saved tunables; a full config sector is erased here, which takes
+    // longer than the watchdog allows
+    let config_store = ConfigStore::new(Flash::new_blocking(p.FLASH));
+    let config = match config_store.load() {
+        Some(config) => {
+            info!("Config loaded from flash");
+            config
+        }
+        None => RobotConfig::default(),
+    };
+
+    // Start the watchdog last so slow peripheral init can't trip it
+    let mut watchdog = IndependentWatchdog::new(p.IWDG, WATCHDOG_TIMEOUT_US);
+    watchdog.unleash();
+    info!("Watchdog armed ({} us)", WATCHDOG_TIMEOUT_US);
+
     info!("Ready! Waiting for commands...");
     info!("Commands: W=forward, S=back, A=left, D=right, Q=stop");
 
-    // Motor speed for keyboard control
-    let speed: u8 = 70;
-    
-    // Default mode
-    let mut mode = RobotMode::Car;
-    
-    // Line follower: remember last direction (0=forward, -1=left, 1=right)
-    let mut last_direction: i8 = 0;
-    
-    // Analog telemetry tracking
-    let mut last_weighted_pos: i32 = 0;
-    let mut last_intensity: u32 = 0;
-    let mut last_steering: i32 = 0;
-    let mut last_left_speed: u8 = 0;
-    let mut last_right_speed: u8 = 0;
-    
-    // Debug: send info every N iterations to avoid spam
-    let mut loop_counter: u32 = 0;
-    let mut last_position: u8 = 0;
+    // Bluetooth RX, TX and the control loop run independently so a slow
+    // telemetry write never delays sensor reads, steering or command reception
+    spawner.spawn(bluetooth_rx_task(bt_rx)).unwrap();
+    spawner.spawn(bluetooth_tx_task(bt, selftest)).unwrap();
+    spawner.spawn(control_task(motors, sensors, watchdog, selftest, config_store, config)).unwrap();
+}
 
+/// Receives commands and forwards them to the control task.
+#[embassy_executor::task]
+async fn bluetooth_rx_task(mut bt_rx: BluetoothRx<'static>) {
     loop {
-        // Check Bluetooth connection
-        if bt.is_connected() {
-            // Use shorter timeout during calibration so we can update sensors
-            // Use longer timeout otherwise to ensure responsiveness
-            let timeout_ms = match mode {
-                RobotMode::LineFollowerCalibrating(_) | RobotMode::LineFollowerRunning => 20,
-                _ => 100,
-            };
-            
-            // Try to read command with timeout (non-blocking)
-            if let Some(cmd) = bt.try_read_command(timeout_ms).await {
-                match cmd {
-                    Command::Motor { left, right } => {
-                        motors.set_both(left, right);
-                    }
-                    Command::Stop => {
-                        motors.stop_all();
-                        // If in Line Follower mode, reset to Idle so user can recalibrate
-                        match mode {
-                            RobotMode::LineFollowerCalibrating(_) | RobotMode::LineFollowerRunning => {
-                                info!("Stop received, resetting to Line Follower Idle");
-                                mode = RobotMode::LineFollowerIdle;
-                            }
-                            _ => {}
-                        }
-                    }
-                    Command::SetMode(m) => {
-                        if m == 1 {
-                            mode = RobotMode::LineFollowerIdle;
-                            info!("Switched to Line Follower Mode (Idle)");
-                            motors.stop_all();
-                        } else {
-                            mode = RobotMode::Car;
-                            info!("Switched to Car Mode");
-                            motors.stop_all();
-                        }
-                    }
-                    Command::Start => {
-                         if let RobotMode::LineFollowerIdle = mode {
-                            info!("Starting Calibration...");
-                            sensors.reset_calibration();
-                            let _ = bt.send_calibration_start().await;
-                            mode = RobotMode::LineFollowerCalibrating(Instant::now());
-                        }
-                    }
-                    Command::GetSensors => {
-                        // Only allow reading sensors if requested, regardless of mode (debug)
-                        let binary = sensors.read_binary();
-                        // Keep infrequent logs or remove if strictly needed, but sensor readout implies we want data
-                        // info!("Sensors: {:08b}", binary); 
-                        let _ = bt.send_sensors(binary).await;
-                    }
-                    Command::GetRawSensors => {
-                         // Only allow reading sensors if requested
-                        let raw = sensors.read_all();
-                        // info!("Raw Sens: {:?}", raw);
-                        let _ = bt.send_raw_sensors(raw).await;
-                    }
-                    Command::Ping => {
-                        let _ = bt.send_pong().await;
-                    }
-                    Command::Unknown(byte) => {
-                        // Handle WASD keyboard input ONLY in Car mode
-                        if let RobotMode::Car = mode {
-                            match byte {
-                                b'W' | b'w' => {
-                                    motors.forward(speed);
-                                }
-                                b'S' | b's' => {
-                                    motors.backward(speed);
-                                }
-                                b'A' | b'a' => {
-                                    motors.turn_left(speed);
-                                }
-                                b'D' | b'd' => {
-                                    motors.turn_right(speed);
-                                }
-                                b'Q' | b'q' | b' ' => {
-                                    motors.stop_all();
-                                }
-                                b'R' | b'r' => {
-                                    // Read sensors - this is manual debug, maybe keep log or remove?
-                                    // Removing log for consistency with speed
-                                    let readings = sensors.read_all();
-                                    let _ = bt.send_raw_sensors(readings).await;
-                                }
-                                _ => {
-                                    // info!("Unknown: {}", byte);
-                                }
-                            }
-                        }
-                    }
+        // Receive errors are counted by the driver; just keep reading
+        let Ok(commands) = bt_rx.read_commands().await else {
+            continue;
+        };
+        // Back-to-back commands arrive in one read; forward them all in order
+        for cmd in commands {
+            match cmd {
+                // The counters live here with the UART, so answer directly
+                // (once shut down the control task drops it with the rest)
+                Command::GetUartErrors if !SHUT_DOWN.load(Ordering::Relaxed) => {
+                    REPLIES.push(Reply::UartErrors(bt_rx.error_stats()))
                 }
+                cmd => COMMANDS.send(cmd).await,
             }
-        } else {
-            // Not connected, just blink and wait by skipping logic
         }
+    }
+}
 
-        // Logic loop based on mode (Non-blocking)
-        match mode {
-            RobotMode::Car => {
-                // Debug: Print raw sensor ADC values every 50 loops (~500ms)
-                if loop_counter % 50 == 0 {
-                    let raw = sensors.read_all();
-                    info!("ADC: {} {} {} {} {} {} {} {}", 
-                        raw[0], raw[1], raw[2], raw[3], 
-                        raw[4], raw[5], raw[6], raw[7]);
-                }
+/// Transmits queued replies and telemetry, and announces the robot when a
+/// GUI connects.
+#[embassy_executor::task]
+async fn bluetooth_tx_task(mut bt: Bluetooth<'static>, selftest: SelfTestReport) {
+    // Debounced connection state, and when it was last seen down
+    let mut link_up = false;
+    let mut down_since: Option<Instant> = None;
+
+    loop {
+        // Announce ourselves when a GUI connects
+        let connected = bt.connection_confirmed();
+        if connected {
+            down_since = None;
+            if !link_up {
+                info!("Bluetooth connected");
+                link_up = true;
+                let _ = bt.send_connected().await;
+                let _ = bt.send_selftest(selftest.dead_channels).await;
+                let _ = bt.send_mode(ROBOT_MODE.load(Ordering::Relaxed)).await;
             }
-            RobotMode::LineFollowerIdle => {
-                // Waiting for Start command
+        } else if link_up {
+            // Only a dropout that outlasts the debounce stops the robot
+            let since = *down_since.get_or_insert_with(Instant::now);
+            if since.elapsed().as_millis() >= LINK_DROP_DEBOUNCE_MS {
+                info!("Bluetooth disconnected");
+                link_up = false;
+                down_since = None;
+                LINK_DROPPED.signal(());
             }
-            RobotMode::LineFollowerCalibrating(start_time) => {
-                let elapsed = start_time.elapsed().as_millis();
-
-                // Calibrate for 10 seconds total
-                if elapsed < 10000 {
-                    
-                    if elapsed < 8000 {
-                        // Phase 1: Sweep for min/max calibration
-                        sensors.update_calibration();
-                        // Speed 70 to overcome friction
-                        let speed = 70;
-                        
-                        // Sweep Sequence (approx 1.4s per full sweep)
-                        if elapsed < 700 {
-                            motors.turn_left(speed);
-                        } else if elapsed < 2100 {
-                            motors.turn_right(speed);
-                        } else if elapsed < 3500 {
-                            motors.turn_left(speed);
-                        } else if elapsed < 4900 {
-                            motors.turn_right(speed);
-                        } else if elapsed < 6300 {
-                            motors.turn_left(speed);
-                        } else if elapsed < 7700 {
-                            motors.turn_right(speed);
-                        } else {
-                            // Turn Left blindly for remaining time (7700..8000)
-                            // This guarantees we are moving towards center/left before tracking starts
-                            motors.turn_left(speed);
-                        }
+        }
+
+        // Transmit whatever was queued; drop it if nobody listens
+        while let Some(reply) = REPLIES.pop() {
+            if connected {
+                let _ = bt.send_reply(reply).await;
+            }
+        }
+
+        // Sleep until something is queued, still watching the STATE pin
+        select(REPLIES.wait(), Timer::after_millis(CONNECTION_POLL_MS)).await;
+    }
+}
+
+/// Owns sensors and motors: runs the mode state machine at a steady rate.
+#[embassy_executor::task]
+async fn control_task(
+    mut motors: MotorController<'static>,
+    mut sensors: CalibratedSensors<'static>,
+    mut watchdog: IndependentWatchdog<'static, IWDG>,
+    selftest: SelfTestReport,
+    mut config_store: ConfigStore<'static>,
+    config: RobotConfig,
+) {
+    // Mode state machine: starts in Car mode
+    let mut state = RobotState::new(Instant::now(), config);
+
+    // The last calibration saved to flash spares a recalibration after a reboot
+    if let Some(calibration) = config_store.load_calibration() {
+        sensors.restore_calibration(&calibration);
+        state.use_saved_calibration();
+    }
+    
+    // Heartbeat: time of the last command received over Bluetooth
+    let mut last_command_at = Instant::now();
+    let mut link_alive = false;
+    
+    // Periodic battery report; the last reading also feeds the status
+    let mut last_battery_at = Instant::now();
+    let mut battery_mv = 0;
+    // Consecutive reports below the cutoff
+    let mut low_battery_readings: u8 = 0;
+
+    // Periodic status heartbeat
+    let mut last_status_at = Instant::now();
+
+    // Parked after a shutdown: no more periodic reports
+    let mut parked = false;
+
+    // Cuts a motor that draws stall current for too long
+    #[cfg(feature = "stall-guard")]
+    let mut stall_guard = stall::StallGuard::default();
+
+    // Periodic simple debug report
+    let mut last_debug_at = Instant::now();
+
+    // A failed self-test shows the error pattern until the GUI connects
+    // and can read the report
+    let mut selftest_failed = !selftest.passed();
+    let status_pattern = |state: &RobotState, selftest_failed: bool| {
+        if selftest_failed { LedPattern::Sos } else { state.led_pattern() }
+    };
+
+    let mut led_pattern = status_pattern(&state, selftest_failed);
+    LED_PATTERN.signal(led_pattern);
+    let mut led_link = link_alive;
+    LINK_CONNECTED.signal(led_link);
+
+    let mut mode = state.mode_report();
+    ROBOT_MODE.store(mode, Ordering::Relaxed);
+
+    // Iteration times for `GET_PERF`
+    let mut loop_timer = LoopTimer::new(Instant::now());
+
+    // Loop period is settable over Bluetooth; the ticker follows it
+    let mut period_ms = state.control_period_ms();
+    let mut ticker = Ticker::every(Duration::from_millis(period_ms));
+
+    loop {
+        let loop_start = Instant::now();
+
+        // Every iteration, including the 10s calibration, must pet the watchdog
+        watchdog.pet();
+
+        // Apply every command that arrived since the last tick
+        while let Ok(cmd) = COMMANDS.try_receive() {
+            last_command_at = Instant::now();
+            link_alive = true;
+            selftest_failed = false;
+            // The loop timer, the flash and the motors live in this task.
+            // Once shut down only PING is answered (by `handle_command`)
+            match cmd {
+                _ if state.is_shut_down() => {}
+                Command::Shutdown => {
+                    apply(state.shutdown(motors.speeds(), Instant::now()), &mut motors);
+                    SHUT_DOWN.store(true, Ordering::Relaxed);
+                    continue;
+                }
+                Command::GetPerf => {
+                    REPLIES.push(Reply::Perf(loop_timer.report()));
+                    continue;
+                }
+                Command::SaveConfig => {
+                    if config_store.save(&state.config()) {
+                        info!("Config saved to flash");
+                        REPLIES.push(Reply::Ack(cmd::SAVE_CONFIG));
                     } else {
-                        // Phase 2: Active Centering Phase (8s to 10s)
-                        // Use collected calibration data to find line center
-                        // Continue updating calibration in case we see new extremes
-                        sensors.update_calibration();
-                        
-                        let (position, intensity) = sensors.read_line_position();
-                        
-                        // Check if we have a line signal
-                        if intensity > 0 {
-                            // P-Control to center (Target position = 0)
-                            // Position range +/- 3500. 
-                            let turn = (position / 20).clamp(-50, 50) as i8;
-                            
-                            // Deadband to avoid jitter
-                            if turn.abs() < 10 {
-                                motors.stop_all();
-                            } else {
-                                // To turn Right (Pos > 0), pivot Right (L fwd, R rev)
-                                motors.set_both(turn, -turn);
-                            }
-                        } else {
-                            // Stop if line is temporarily lost
-                            motors.stop_all();
-                        }
+                        warn!("Config save failed");
+                        REPLIES.push(Reply::Error(err::FLASH));
                     }
-                } else {
-                    motors.stop_all();
-                    info!("Calibration Complete! Running...");
-                    sensors.finalize_calibration();
-                    let _ = bt.send_calibration_end().await;
-                    mode = RobotMode::LineFollowerRunning;
+                    continue;
                 }
+                _ => {}
             }
-            RobotMode::LineFollowerRunning => {
-                // Read weighted position (-3500 to 3500) and intensity
-                let (raw_position, intensity) = sensors.read_line_position();
-                
-                // Use raw position directly - no offset needed
-                // Positive = line on right side, Negative = line on left side
-                let position = raw_position;
-                
-                // Update telemetry (show corrected position)
-                last_weighted_pos = position;
-                last_intensity = intensity;
-                
-                // For debug output
-                let raw_binary = sensors.read_binary(); 
-                last_position = raw_binary;
-
-                if intensity == 0 {
-                    // Lost line - search in last known direction with aggressive turn
-                    match last_direction {
-                        d if d < 0 => motors.set_both(20, 90),  // Pivot left
-                        d if d > 0 => motors.set_both(90, 20),  // Pivot right
-                        _ => motors.forward(60),
-                    }
-                } else {
-                    // Line found - TIERED/ZONED RESPONSE
-                    // Physical orientation: Index 0 = Left side of robot
-                    // Negative position = line on LEFT -> need to turn LEFT
-                    
-                    let abs_pos = if position < 0 { -position } else { position };
-                    let sign = if position < 0 { -1i32 } else { 1i32 };
-                    
-                    // Calculate motor speeds based on zones
-                    let (left_speed, right_speed, steering): (i8, i8, i32) = if abs_pos < 500 {
-                        // === CENTER ZONE: Gentle proportional steering ===
-                        // Line is well centered - max speed on straights
-                        let base_speed: i32 = 90;
-                        let kp: i32 = 40;  // Stronger steering to maintain high speed
-                        let steer = position / kp;
-                        let l = (base_speed + steer).clamp(55, 100) as i8;
-                        let r = (base_speed - steer).clamp(55, 100) as i8;
-                        (l, r, steer)
-                        
-                    } else if abs_pos < 1500 {
-                        // === WARNING ZONE: Stronger proportional steering ===
-                        // Line is drifting - still fast but ready to correct
-                        let base_speed: i32 = 75;
-                        let kp: i32 = 25;  
-                        let steer = position / kp;
-                        let l = (base_speed + steer).clamp(40, 100) as i8;
-                        let r = (base_speed - steer).clamp(40, 100) as i8;
-                        (l, r, steer)
-                        
-                    } else if abs_pos < 2500 {
-                        // === CRITICAL ZONE: Aggressive differential steering ===
-                        // Line is far off - big speed difference to turn sharply
-                        let steer = sign * 35;  // Fixed aggressive steering value
-                        if position < 0 {
-                            // Line on left -> turn left hard (slow left, fast right)
-                            (30, 85, steer)
-                        } else {
-                            // Line on right -> turn right hard (fast left, slow right)
-                            (85, 30, steer)
-                        }
-                        
-                    } else {
-                        // === EMERGENCY ZONE: Pivot turn ===
-                        // Line at extreme edge - near pivot (one motor very slow/stopped)
-                        let steer = sign * 50;  // Maximum steering indication
-                        if position < 0 {
-                            // Line on left -> pivot left aggressively
-                            (20, 95, steer)
-                        } else {
-                            // Line on right -> pivot right aggressively
-                            (95, 20, steer)
-                        }
-                    };
-                    
-                    motors.set_both(left_speed, right_speed);
-                    
-                    // Update telemetry
-                    last_steering = steering;
-                    last_left_speed = left_speed as u8;
-                    last_right_speed = right_speed as u8;
-                    
-                    // Update last direction for when we lose line
-                    if position > 300 {
-                        last_direction = 1;  // Line on right, was turning right
-                    } else if position < -300 {
-                        last_direction = -1; // Line on left, was turning left
-                    } else {
-                        last_direction = 0;  // Centered
-                    }
+            let actions = state.handle_command(cmd, &mut sensors, Instant::now()).await;
+            if actions.calibrated {
+                save_calibration(&mut config_store, &sensors);
+            }
+            apply(actions, &mut motors);
+        }
+
+        // Push battery voltage periodically
+        if !parked && last_battery_at.elapsed().as_millis() >= BATTERY_REPORT_INTERVAL_MS {
+            last_battery_at = Instant::now();
+            battery_mv = sensors.read_battery_mv();
+            REPLIES.push(Reply::Battery(battery_mv));
+
+            // Unknown (0) never counts as flat
+            if battery_mv != 0 && battery_mv < BATTERY_CUTOFF_MV {
+                low_battery_readings = low_battery_readings.saturating_add(1);
+                if low_battery_readings >= BATTERY_CUTOFF_READINGS {
+                    apply(state.set_battery_cutoff(true), &mut motors);
+                }
+            } else {
+                low_battery_readings = 0;
+                if battery_mv >= BATTERY_CUTOFF_MV.saturating_add(BATTERY_CUTOFF_HYSTERESIS_MV) {
+                    apply(state.set_battery_cutoff(false), &mut motors);
                 }
             }
         }
-        
-        // Increment loop counter for periodic debug
-        loop_counter += 1;
-        
-        // Send debug info every 20 loops (~200ms) when in LineFollowerRunning
-        if let RobotMode::LineFollowerRunning = mode {
-            if loop_counter % 20 == 0 {
-                 let _ = bt.send_analog_debug(
-                     last_weighted_pos as i16,
-                     last_intensity as u16,
-                     last_steering as i8,
-                     last_left_speed,
-                     last_right_speed
-                 ).await;
+
+        // Connection dropped: stop now and stay stopped until a new command
+        if LINK_DROPPED.try_take().is_some() {
+            info!("Connection lost, stopping motors");
+            link_alive = false;
+            apply(state.handle_link_lost(), &mut motors);
+        }
+
+        // Heartbeat check: treat the link as dead if the GUI went quiet
+        if link_alive && last_command_at.elapsed().as_millis() > HEARTBEAT_TIMEOUT_MS {
+            info!("Heartbeat lost, stopping motors");
+            link_alive = false;
+            apply(state.handle_link_lost(), &mut motors);
+        }
+
+        // Logic loop based on mode (Non-blocking)
+        let actions = state.tick(&mut sensors, Instant::now()).await;
+        if actions.calibrated {
+            save_calibration(&mut config_store, &sensors);
+        }
+        apply(actions, &mut motors);
+        motors.tick(MOTOR_RAMP_STEP as u32);
+
+        // Telemetry the GUI subscribed to with `STREAM`
+        if !parked && let Some(reply) = state.stream(&mut sensors, Instant::now()).await {
+            REPLIES.push(reply);
+        }
+        // The keep-alive would nudge a parked robot's wheels
+        if !state.is_parked() {
+            motors.service_keepalive();
+        }
+
+        #[cfg(feature = "stall-guard")]
+        if let Some(stalled) = stall_guard.update(sensors.read_motor_current_raw(), Instant::now()) {
+            for (motor, stalled) in [Motor::Left, Motor::Right].into_iter().zip(stalled) {
+                motors.set_stalled(motor, stalled);
+            }
+            warn!("Motor stall: left {}, right {}", stalled[0], stalled[1]);
+        }
+
+        // Simple debug view for the GUI: what the wheels are actually doing
+        if !parked && last_debug_at.elapsed().as_millis() >= DEBUG_REPORT_INTERVAL_MS {
+            last_debug_at = Instant::now();
+            REPLIES.push(Reply::Debug {
+                mode: state.mode_byte(),
+                position: state.last_binary(),
+                action: motors.last_action(),
+            });
+        }
+
+        // Once parked, one last status after everything already queued
+        let just_parked = !parked && state.is_parked();
+        parked |= just_parked;
+        if just_parked || (!parked && last_status_at.elapsed().as_millis() >= STATUS_REPORT_INTERVAL_MS) {
+            last_status_at = Instant::now();
+            let battery_low = battery_mv != 0 && battery_mv <= BATTERY_LOW_MV;
+            #[allow(unused_mut)]
+            let mut status = state.status(link_alive, sensors.is_calibrated(), battery_low);
+            #[cfg(feature = "stall-guard")]
+            {
+                status.stalled = stall_guard.mask();
             }
+            REPLIES.push(Reply::Status(status));
+        }
+
+        // Only signal on change so the blink task doesn't restart its pattern
+        let pattern = status_pattern(&state, selftest_failed);
+        if pattern != led_pattern {
+            led_pattern = pattern;
+            LED_PATTERN.signal(led_pattern);
+        }
+        if link_alive != led_link {
+            led_link = link_alive;
+            LINK_CONNECTED.signal(led_link);
+        }
+
+        // Authoritative mode for the GUI, pushed on every transition
+        if state.mode_report() != mode {
+            mode = state.mode_report();
+            ROBOT_MODE.store(mode, Ordering::Relaxed);
+            REPLIES.push(Reply::Mode(mode));
+        }
+
+        // The PID measures its own dt, but an overrun means the rate is too high
+        let busy = loop_start.elapsed();
+        loop_timer.record(busy, Instant::now());
+        if busy.as_micros() > period_ms * 1000 {
+            warn!("Control loop overran: {}us for a {}ms period", busy.as_micros(), period_ms);
+        }
+        if state.control_period_ms() != period_ms {
+            period_ms = state.control_period_ms();
+            ticker = Ticker::every(Duration::from_millis(period_ms));
+        }
+
+        ticker.next().await;
+    }
+}
+
+/// Min/max/average control loop iteration time over `PERF_WINDOW_MS`
+/// windows, to see whether the ADC or telemetry eats into the period.
+struct LoopTimer {
+    window_start: Instant,
+    min_us: u32,
+    max_us: u32,
+    total_us: u32,
+    count: u32,
+    /// Last complete window
+    last: Option<LoopPerf>,
+}
+
+impl LoopTimer {
+    fn new(now: Instant) -> Self {
+        Self { window_start: now, min_us: u32::MAX, max_us: 0, total_us: 0, count: 0, last: None }
+    }
+
+    /// Add one iteration's busy time.
+    fn record(&mut self, busy: Duration, now: Instant) {
+        let us = busy.as_micros().min(u32::MAX as u64) as u32;
+        self.min_us = self.min_us.min(us);
+        self.max_us = self.max_us.max(us);
+        self.total_us = self.total_us.saturating_add(us);
+        self.count += 1;
+        if (now - self.window_start).as_millis() >= PERF_WINDOW_MS {
+            self.last = Some(self.current());
+            *self = Self { last: self.last, ..Self::new(now) };
+        }
+    }
+
+    /// The last complete window, or the one in progress before the first
+    /// completes. Saturates at 65.535ms.
+    fn report(&self) -> LoopPerf {
+        self.last.unwrap_or_else(|| self.current())
+    }
+
+    fn current(&self) -> LoopPerf {
+        if self.count == 0 {
+            return LoopPerf::default();
         }
-        
-        // Small delay to prevent tight loop hogging if nothing to do? 
-        // W/ Embassy, usually we await something. But here we poll.
-        Timer::after_millis(10).await;
+        let us = |value: u32| value.min(u16::MAX as u32) as u16;
+        LoopPerf {
+            min_us: us(self.min_us),
+            max_us: us(self.max_us),
+            avg_us: us(self.total_us / self.count),
+        }
+    }
+}
+
+/// Persist a calibration that just finished. Only called then, so flash
+/// sees one write per calibration.
+fn save_calibration(config_store: &mut ConfigStore<'_>, sensors: &CalibratedSensors<'_>) {
+    if config_store.save_calibration(&sensors.calibration_report()) {
+        info!("Calibration saved to flash");
+    } else {
+        warn!("Calibration save failed");
+    }
+}
+
+/// Apply state machine actions: drive the motors and queue replies for the
+/// Bluetooth task without waiting on the link.
+fn apply(actions: Actions, motors: &mut MotorController<'_>) {
+    match actions.motors {
+        Some(MotorCommand::Tank { left, right }) if MOTOR_RAMP_STEP != 0 => motors.set_both_target(left, right),
+        Some(command) => motors.apply(command),
+        None => {}
+    }
+    if actions.echo_motors {
+        REPLIES.push(Reply::MotorOutput(motors.speeds()));
+    }
+    for reply in actions.replies {
+        REPLIES.push(reply);
     }
 }
@@ -1,19 +1,39 @@
 //! Motor control module for LiRu robot.
 //!
 //! This module provides a high-level interface for controlling two DC motors
-//! using PWM signals through the L298N motor driver.
+//! using PWM signals through the L298N motor driver (two PWM inputs per
+//! motor), or, with the `tb6612` feature, the TB6612FNG (one PWM input plus
+//! two direction GPIOs per motor). Both expose the same API.
 
 use embedded_hal::Pwm;
+#[cfg(feature = "tb6612")]
+use embassy_stm32::gpio::{Level, Output, Speed};
 use embassy_stm32::gpio::OutputType;
 use embassy_stm32::time::hz;
 use embassy_stm32::timer::Channel;
 use embassy_stm32::timer::simple_pwm::{PwmPin, SimplePwm};
 use embassy_stm32::timer::low_level::CountingMode;
 use embassy_stm32::peripherals::{PA8, PA9, PA10, PA11, TIM1};
+#[cfg(feature = "tb6612")]
+use embassy_stm32::peripherals::{PB4, PB5};
+use embassy_time::{block_for, Duration, Instant};
+
+// The commands and the mixing behind them live in the hardware-free library
+pub use liru_core::drive::*;
 
 /// PWM frequency for motor control (20kHz - inaudible)
 const PWM_FREQUENCY: u32 = 20_000;
 
+/// How long a motor must sit at speed 0 before the keep-alive kicks in,
+/// so ordinary stops are clean and the robot doesn't creep.
+const KEEPALIVE_DELAY: Duration = Duration::from_secs(3);
+
+/// Soft start: a stopped motor reaches its commanded duty in this many
+/// equal steps, `SOFT_START_STEP` apart (1.2ms in total), so the inrush
+/// doesn't dip the 3.3V rail and reset the HC-05.
+const SOFT_START_STEPS: u32 = 4;
+const SOFT_START_STEP: Duration = Duration::from_micros(400);
+
 /// Motor identifier
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Motor {
@@ -28,7 +48,23 @@ pub enum Motor {
 pub enum Direction {
     Forward,
     Reverse,
+    /// Driver outputs off: the motor coasts
     Stop,
+    /// Motor terminals shorted: the motor stops hard
+    Brake,
+}
+
+/// What a motor does at speed 0 (see `MotorController::set_stop_mode`)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StopMode {
+    /// Driver outputs off: the motor coasts to a stop
+    Coast,
+    /// Motor terminals shorted: the motor stops hard and holds
+    Brake,
+    /// Coast, then once stopped for `KEEPALIVE_DELAY` drive forward at this
+    /// duty (percent), too weak to move the robot but enough current to
+    /// keep a power bank from sleeping
+    KeepAlive(u8),
 }
 
 /// Motor controller for dual DC motors via L298N driver.
@@ -38,9 +74,44 @@ pub enum Direction {
 /// - CH2 (PA9): Motor A Reverse  
 /// - CH3 (PA10): Motor B Forward
 /// - CH4 (PA11): Motor B Reverse
+///
+/// With the `tb6612` feature, for a TB6612FNG driver (STBY tied high):
+/// - CH1 (PA8): PWMA, with AIN1 on PA9 and AIN2 on PB4
+/// - CH3 (PA10): PWMB, with BIN1 on PA11 and BIN2 on PB5
 pub struct MotorController<'d> {
     pwm: SimplePwm<'d, TIM1>,
+    /// TB6612 direction inputs (IN1, IN2) of each motor (left, right)
+    #[cfg(feature = "tb6612")]
+    direction_pins: [(Output<'d>, Output<'d>); 2],
     max_duty: u32,
+    /// What a motor does at speed 0
+    stop_mode: StopMode,
+    /// When each motor (left, right) was set to `Direction::Stop`
+    idle_since: [Option<Instant>; 2],
+    /// Last commanded speed of each motor (left, right), negative = reverse
+    speeds: [i8; 2],
+    /// Nonzero speeds are raised to at least this (percent)
+    min_speed: u8,
+    /// Every speed is capped to this (percent)
+    max_speed: u8,
+    /// Motors (left, right) wired backwards: driven in the opposite direction
+    inverted: [bool; 2],
+    /// Dither fine speeds between adjacent percents (see `set_dithering`)
+    dithering: bool,
+    /// Sub-percent remainder (tenths) carried to the next fine command, per motor
+    dither_acc: [u16; 2],
+    /// `set_both` won't reverse a motor running faster than this straight
+    /// into a reverse faster than this (percent, 0 = off)
+    reversal_guard: u8,
+    /// Motors (left, right) cut by the stall guard: held off whatever is commanded
+    #[cfg(feature = "stall-guard")]
+    stalled: [bool; 2],
+    /// Where `tick` is walking the speeds (left, right), while a ramp is in
+    /// progress (see `set_both_target`)
+    ramp_target: Option<[i8; 2]>,
+    /// Ramp position (left, right): kept apart from `speeds`, which the
+    /// limits may hold elsewhere
+    ramp_speeds: [i8; 2],
 }
 
 impl<'d> MotorController<'d> {
@@ -52,6 +123,7 @@ impl<'d> MotorController<'d> {
     /// * `pa9` - PWM pin for Motor A reverse
     /// * `pa10` - PWM pin for Motor B forward
     /// * `pa11` - PWM pin for Motor B reverse
+    #[cfg(not(feature = "tb6612"))]
     pub fn new(
         tim1: TIM1,
         pa8: PA8,
@@ -74,8 +146,6 @@ impl<'d> MotorController<'d> {
             CountingMode::EdgeAlignedUp,
         );
 
-        let max_duty = pwm.get_max_duty();
-
         // Enable all channels
         pwm.enable(Channel::Ch1);
         pwm.enable(Channel::Ch2);
@@ -88,7 +158,148 @@ impl<'d> MotorController<'d> {
         pwm.set_duty(Channel::Ch3, 0);
         pwm.set_duty(Channel::Ch4, 0);
 
-        Self { pwm, max_duty }
+        Self::from_parts(pwm)
+    }
+
+    /// Create a new motor controller for a TB6612FNG driver.
+    ///
+    /// # Arguments
+    /// * `tim1` - TIM1 peripheral
+    /// * `pa8` - PWMA (Motor A speed)
+    /// * `pa9`, `pb4` - AIN1, AIN2 (Motor A direction)
+    /// * `pa10` - PWMB (Motor B speed)
+    /// * `pa11`, `pb5` - BIN1, BIN2 (Motor B direction)
+    #[cfg(feature = "tb6612")]
+    pub fn new(
+        tim1: TIM1,
+        pa8: PA8,
+        pa9: PA9,
+        pb4: PB4,
+        pa10: PA10,
+        pa11: PA11,
+        pb5: PB5,
+    ) -> Self {
+        let pwm_a = PwmPin::new_ch1(pa8, OutputType::PushPull);
+        let pwm_b = PwmPin::new_ch3(pa10, OutputType::PushPull);
+        let mut pwm = SimplePwm::new(
+            tim1,
+            Some(pwm_a),
+            None,
+            Some(pwm_b),
+            None,
+            hz(PWM_FREQUENCY),
+            CountingMode::EdgeAlignedUp,
+        );
+        pwm.enable(Channel::Ch1);
+        pwm.enable(Channel::Ch3);
+        pwm.set_duty(Channel::Ch1, 0);
+        pwm.set_duty(Channel::Ch3, 0);
+
+        // IN1 = IN2 = low: stopped (coasting)
+        Self::from_parts(pwm, [
+            (Output::new(pa9, Level::Low, Speed::Low), Output::new(pb4, Level::Low, Speed::Low)),
+            (Output::new(pa11, Level::Low, Speed::Low), Output::new(pb5, Level::Low, Speed::Low)),
+        ])
+    }
+
+    fn from_parts(
+        pwm: SimplePwm<'d, TIM1>,
+        #[cfg(feature = "tb6612")] direction_pins: [(Output<'d>, Output<'d>); 2],
+    ) -> Self {
+        let max_duty = pwm.get_max_duty();
+        Self {
+            pwm,
+            #[cfg(feature = "tb6612")]
+            direction_pins,
+            max_duty,
+            stop_mode: StopMode::Coast,
+            idle_since: [None; 2],
+            speeds: [0; 2],
+            min_speed: 0,
+            max_speed: 100,
+            inverted: [false; 2],
+            dithering: false,
+            dither_acc: [0; 2],
+            reversal_guard: 0,
+            #[cfg(feature = "stall-guard")]
+            stalled: [false; 2],
+            ramp_target: None,
+            ramp_speeds: [0; 2],
+        }
+    }
+
+    /// Dither fine speed commands (`MotorCommand::SetFine`): each motor
+    /// carries the sub-percent remainder over to the next command, so over
+    /// consecutive control ticks it alternates between adjacent percents
+    /// (e.g. 50.3% runs 50, 50, 51, ...) and averages the fractional duty.
+    /// Off by default: the fraction is truncated.
+    pub fn set_dithering(&mut self, enabled: bool) {
+        self.dithering = enabled;
+        self.dither_acc = [0; 2];
+    }
+
+    /// Guard `set_both` against jumping a motor from fast forward straight
+    /// into fast reverse (or back), e.g. a joystick snapping through center:
+    /// when both speeds are above `speed` the motor is stopped instead, and
+    /// the reverse only goes through with the next command. Separate from
+    /// the soft start. Off (0) by default.
+    pub fn set_reversal_guard(&mut self, speed: u8) {
+        self.reversal_guard = speed.min(100);
+    }
+
+    /// Reverse `motor`'s direction at the driver, for a motor whose driver
+    /// outputs are wired backwards. Everything above `set_motor` keeps
+    /// thinking in forward/reverse.
+    pub fn set_motor_inverted(&mut self, motor: Motor, inverted: bool) {
+        self.inverted[motor as usize] = inverted;
+    }
+
+    /// Cut `motor` while it is stalled (see `stall::StallGuard`): it coasts
+    /// and ignores drive commands until released. On release it picks up
+    /// with the next command.
+    #[cfg(feature = "stall-guard")]
+    pub fn set_stalled(&mut self, motor: Motor, stalled: bool) {
+        self.stalled[motor as usize] = stalled;
+        if stalled {
+            self.cut(motor);
+        }
+    }
+
+    #[cfg(feature = "stall-guard")]
+    fn cut(&mut self, motor: Motor) {
+        self.speeds[motor as usize] = 0;
+        self.idle_since[motor as usize] = None;
+        self.output(motor, Direction::Stop, 0);
+    }
+
+    /// Hardware-protective speed limits applied to every command: nonzero
+    /// speeds below `min` (where the motor would only stall) are raised to
+    /// it, and everything is capped at `max`. The cap wins if they overlap.
+    /// Defaults: 0 and 100 (no change).
+    pub fn set_limits(&mut self, min: u8, max: u8) {
+        self.max_speed = max.min(100);
+        self.min_speed = min.min(self.max_speed);
+    }
+
+    /// Choose what a motor does when commanded to speed 0: coast (the
+    /// default), short-brake, or coast and then run the power bank
+    /// keep-alive. `stop_all` coasts or brakes per the mode but never arms
+    /// the keep-alive: an E-stop or a lost link must stay still.
+    pub fn set_stop_mode(&mut self, mode: StopMode) {
+        self.stop_mode = match mode {
+            StopMode::KeepAlive(pct) => StopMode::KeepAlive(pct.min(100)),
+            mode => mode,
+        };
+    }
+
+    /// Engage the keep-alive on motors that have been idle long enough.
+    /// Call regularly (e.g. every control loop iteration).
+    pub fn service_keepalive(&mut self) {
+        for motor in [Motor::Left, Motor::Right] {
+            if self.idle_since[motor as usize].is_some() {
+                self.set_idle(motor);
+            }
+        }
     }
 
     /// Set motor speed and direction.
@@ -98,36 +309,134 @@ impl<'d> MotorController<'d> {
     /// * `direction` - Direction of rotation
     /// * `speed_percent` - Speed as percentage (0-100)
     pub fn set_motor(&mut self, motor: Motor, direction: Direction, speed_percent: u8) {
-        // Both motors use equal power for now
-        let adjusted_speed = speed_percent as u32;
-        
-        let speed = adjusted_speed.min(100);
+        self.set_motor_fine(motor, direction, speed_percent as u16 * 10);
+    }
+
+    /// `set_motor` with the speed in tenths of a percent (0-1000), dithered
+    /// into whole percents if enabled.
+    fn set_motor_fine(&mut self, motor: Motor, direction: Direction, speed_tenths: u16) {
+        // Every instant setter overrides a ramp in progress
+        self.ramp_target = None;
+        #[cfg(feature = "stall-guard")]
+        if self.stalled[motor as usize] && matches!(direction, Direction::Forward | Direction::Reverse) {
+            self.cut(motor);
+            return;
+        }
+        let mut speed_percent = (speed_tenths / 10).min(100) as u8;
+        if self.dithering && matches!(direction, Direction::Forward | Direction::Reverse) {
+            let acc = &mut self.dither_acc[motor as usize];
+            *acc += speed_tenths % 10;
+            if *acc >= 10 {
+                *acc -= 10;
+                speed_percent = (speed_percent + 1).min(100);
+            }
+        }
+        let speed = match speed_percent {
+            0 => 0,
+            pct => pct.clamp(self.min_speed, self.max_speed) as u32,
+        };
         let duty = self.max_duty * speed / 100;
 
-        let (fwd_ch, rev_ch) = match motor {
-            Motor::Left => (Channel::Ch1, Channel::Ch2),
-            Motor::Right => (Channel::Ch3, Channel::Ch4),
+        let was_stopped = self.speeds[motor as usize] == 0;
+        self.speeds[motor as usize] = match direction {
+            Direction::Forward => speed as i8,
+            Direction::Reverse => -(speed as i8),
+            Direction::Stop | Direction::Brake => 0,
         };
 
         match direction {
-            Direction::Forward => {
-                self.pwm.set_duty(rev_ch, 0);
-                self.pwm.set_duty(fwd_ch, duty);
+            Direction::Forward | Direction::Reverse => {
+                self.idle_since[motor as usize] = None;
+                self.set_duty_from_stop(motor, direction, duty, was_stopped);
             }
-            Direction::Reverse => {
-                self.pwm.set_duty(fwd_ch, 0);
-                self.pwm.set_duty(rev_ch, duty);
+            Direction::Stop => self.set_idle(motor),
+            // Held until the next command; no keep-alive
+            Direction::Brake => {
+                self.idle_since[motor as usize] = None;
+                self.output(motor, Direction::Brake, self.max_duty);
+            }
+        }
+    }
+
+    /// Drive `motor` at `duty`, ramping up over `SOFT_START_STEPS` if the
+    /// motor was stopped. Blocks for at most 1.2ms.
+    fn set_duty_from_stop(&mut self, motor: Motor, direction: Direction, duty: u32, was_stopped: bool) {
+        if was_stopped && duty > 0 {
+            for step in 1..SOFT_START_STEPS {
+                self.output(motor, direction, duty * step / SOFT_START_STEPS);
+                block_for(SOFT_START_STEP);
             }
-            Direction::Stop => {
-                // HACK: Power Bank Keep-Alive
-                // Instead of coasting (0,0), we drive Forward at 10% power.
-                // This draws current to prevent the power bank from sleeping,
-                // but should be too weak to move the motor (below static friction).
-                let keep_alive_duty = self.max_duty * 10 / 100; // 10% Duty Cycle
-                
-                self.pwm.set_duty(rev_ch, 0);
-                self.pwm.set_duty(fwd_ch, keep_alive_duty);
+        }
+        self.output(motor, direction, duty);
+    }
+
+    /// Hold `motor` at speed 0 per the stop mode. With `StopMode::KeepAlive`,
+    /// once the motor has been stopped for `KEEPALIVE_DELAY`, instead of
+    /// coasting (0,0) we drive it forward at the keep-alive duty. This draws
+    /// current to prevent the power bank from sleeping, but should be too
+    /// weak to move the motor (below static friction).
+    fn set_idle(&mut self, motor: Motor) {
+        let since = *self.idle_since[motor as usize].get_or_insert_with(Instant::now);
+        let duty = match self.stop_mode {
+            StopMode::Coast => 0,
+            StopMode::Brake => {
+                self.output(motor, Direction::Brake, self.max_duty);
+                return;
             }
+            StopMode::KeepAlive(pct) if since.elapsed() >= KEEPALIVE_DELAY => self.max_duty * pct as u32 / 100,
+            StopMode::KeepAlive(_) => 0,
+        };
+
+        let direction = if duty > 0 { Direction::Forward } else { Direction::Stop };
+        self.output(motor, direction, duty);
+    }
+
+    /// Set `motor`'s driver inputs: `duty` in `direction` (reversed if the
+    /// motor is inverted), both sides off (coasting) for `Stop`, or both
+    /// sides on (short brake) for `Brake`.
+    fn output(&mut self, motor: Motor, direction: Direction, duty: u32) {
+        let direction = match direction {
+            Direction::Forward if self.inverted[motor as usize] => Direction::Reverse,
+            Direction::Reverse if self.inverted[motor as usize] => Direction::Forward,
+            direction => direction,
+        };
+
+        // L298N: PWM on the forward or the reverse input; the idle side is
+        // cleared first so both never drive at once
+        #[cfg(not(feature = "tb6612"))]
+        {
+            let (fwd_ch, rev_ch) = match motor {
+                Motor::Left => (Channel::Ch1, Channel::Ch2),
+                Motor::Right => (Channel::Ch3, Channel::Ch4),
+            };
+            let (on, off) = match direction {
+                Direction::Forward => (fwd_ch, rev_ch),
+                Direction::Reverse => (rev_ch, fwd_ch),
+                Direction::Stop => (fwd_ch, rev_ch),
+                Direction::Brake => {
+                    self.pwm.set_duty(fwd_ch, self.max_duty);
+                    self.pwm.set_duty(rev_ch, self.max_duty);
+                    return;
+                }
+            };
+            let duty = if direction == Direction::Stop { 0 } else { duty };
+            self.pwm.set_duty(off, 0);
+            self.pwm.set_duty(on, duty);
+        }
+
+        // TB6612: direction on IN1/IN2, speed on the one PWM input
+        #[cfg(feature = "tb6612")]
+        {
+            let channel = match motor {
+                Motor::Left => Channel::Ch1,
+                Motor::Right => Channel::Ch3,
+            };
+            let (in1, in2) = &mut self.direction_pins[motor as usize];
+            let brake = direction == Direction::Brake;
+            in1.set_level((direction == Direction::Forward || brake).into());
+            in2.set_level((direction == Direction::Reverse || brake).into());
+            let duty = if direction == Direction::Stop { 0 } else { duty };
+            self.pwm.set_duty(channel, duty);
         }
     }
 
@@ -136,6 +445,8 @@ impl<'d> MotorController<'d> {
     /// Positive values = forward, negative = reverse, 0 = stop.
     /// Range: -100 to 100 for each motor.
     pub fn set_both(&mut self, left_speed: i8, right_speed: i8) {
+        let left_speed = self.guard_reversal(Motor::Left, left_speed);
+        let right_speed = self.guard_reversal(Motor::Right, right_speed);
         let (left_dir, left_pct) = Self::speed_to_dir(left_speed);
         let (right_dir, right_pct) = Self::speed_to_dir(right_speed);
 
@@ -143,12 +454,84 @@ impl<'d> MotorController<'d> {
         self.set_motor(Motor::Right, right_dir, right_pct);
     }
 
-    /// Stop all motors immediately.
+    /// `speed` for `motor`, or 0 if it would take the motor from above the
+    /// reversal guard speed straight to above it the other way.
+    fn guard_reversal(&self, motor: Motor, speed: i8) -> i8 {
+        let current = self.speeds[motor as usize];
+        let reversing = (current as i16) * (speed as i16) < 0;
+        let guard = self.reversal_guard;
+        if guard > 0 && reversing && current.unsigned_abs() > guard && speed.unsigned_abs() > guard {
+            0
+        } else {
+            speed
+        }
+    }
+
+    /// `set_both` in tenths of a percent (-1000 to 1000 per motor).
+    pub fn set_both_fine(&mut self, left: i16, right: i16) {
+        for (motor, speed) in [(Motor::Left, left), (Motor::Right, right)] {
+            let direction = match speed.signum() {
+                1 => Direction::Forward,
+                -1 => Direction::Reverse,
+                _ => Direction::Stop,
+            };
+            self.set_motor_fine(motor, direction, speed.unsigned_abs().min(1000));
+        }
+    }
+
+    /// Ramped `set_both`: only sets where the motors should end up, and
+    /// each `tick` moves them toward it, so a jump from stop to 70 doesn't
+    /// draw full stall current at once. A motor changing direction ramps
+    /// down to 0 before it ramps up the other way. Any instant setter
+    /// (`set_both`, `forward`, `stop_all`, ...) cancels the ramp.
+    pub fn set_both_target(&mut self, left: i8, right: i8) {
+        if self.ramp_target.is_none() {
+            self.ramp_speeds = self.speeds;
+        }
+        self.ramp_target = Some([left.clamp(-100, 100), right.clamp(-100, 100)]);
+    }
+
+    /// Move the motors toward the `set_both_target` speeds by at most
+    /// `max_step` percent each. Call every control loop iteration; does
+    /// nothing when no ramp is in progress.
+    pub fn tick(&mut self, max_step: u32) {
+        let Some(target) = self.ramp_target else {
+            return;
+        };
+        let step = max_step.clamp(1, 200) as i16;
+        let mut next = self.ramp_speeds;
+        for (speed, target) in next.iter_mut().zip(target) {
+            let (current, target) = (*speed as i16, target as i16);
+            // Never step across 0: a reversal stops at 0 first
+            let goal = if current * target < 0 { 0 } else { target };
+            *speed = if goal > current {
+                (current + step).min(goal)
+            } else {
+                (current - step).max(goal)
+            } as i8;
+        }
+        self.set_both(next[0], next[1]);
+        self.ramp_speeds = next;
+        if next != target {
+            self.ramp_target = Some(target);
+        }
+    }
+
+    /// Stop all motors immediately: coast, or brake in `StopMode::Brake`
+    /// (no keep-alive).
     pub fn stop_all(&mut self) {
-        self.pwm.set_duty(Channel::Ch1, 0);
-        self.pwm.set_duty(Channel::Ch2, 0);
-        self.pwm.set_duty(Channel::Ch3, 0);
-        self.pwm.set_duty(Channel::Ch4, 0);
+        self.ramp_target = None;
+        self.idle_since = [None; 2];
+        self.speeds = [0; 2];
+        let direction = if self.stop_mode == StopMode::Brake { Direction::Brake } else { Direction::Stop };
+        self.output(Motor::Left, direction, self.max_duty);
+        self.output(Motor::Right, direction, self.max_duty);
+    }
+
+    /// Short-brake all motors, held until the next command (no keep-alive).
+    pub fn brake_all(&mut self) {
+        self.set_motor(Motor::Left, Direction::Brake, 0);
+        self.set_motor(Motor::Right, Direction::Brake, 0);
     }
 
     /// Drive forward at given speed percentage.
@@ -175,12 +558,56 @@ impl<'d> MotorController<'d> {
         self.set_motor(Motor::Right, Direction::Reverse, speed_percent);
     }
 
+    /// Proportional drive from a joystick: `linear` forward/back and
+    /// `angular` turn rate (positive = right), each -100 to 100.
+    /// Mixed into wheel speeds, scaled down together if either would exceed
+    /// 100 so the turn ratio is kept.
+    pub fn drive(&mut self, linear: i8, angular: i8) {
+        let [left, right] = mix_drive(linear, angular);
+        self.set_both(left, right);
+    }
+
+    /// Classify what the wheels are currently doing.
+    pub fn last_action(&self) -> MotorAction {
+        let [left, right] = self.speeds;
+        match (left.signum(), right.signum()) {
+            (0, 0) => MotorAction::Stop,
+            (1, 1) if left == right => MotorAction::Forward,
+            (1, 1) if left < right => MotorAction::ArcLeft,
+            (1, 1) => MotorAction::ArcRight,
+            (-1, -1) => MotorAction::Reverse,
+            _ if right > left => MotorAction::Left,
+            _ => MotorAction::Right,
+        }
+    }
+
+    /// Speeds the motors actually got (left, right; percent, + = forward),
+    /// after saturation, the limits and stall cuts. 0 while stopped or braked.
+    pub fn speeds(&self) -> [i8; 2] {
+        self.speeds
+    }
+
+    /// Apply a high-level motor command.
+    pub fn apply(&mut self, command: MotorCommand) {
+        match command {
+            MotorCommand::Set { left, right } | MotorCommand::Tank { left, right } => self.set_both(left, right),
+            MotorCommand::SetFine { left, right } => self.set_both_fine(left, right),
+            MotorCommand::Forward(speed) => self.forward(speed),
+            MotorCommand::Backward(speed) => self.backward(speed),
+            MotorCommand::TurnLeft(speed) => self.turn_left(speed),
+            MotorCommand::TurnRight(speed) => self.turn_right(speed),
+            MotorCommand::Drive { linear, angular } => self.drive(linear, angular),
+            MotorCommand::Stop => self.stop_all(),
+            MotorCommand::Brake => self.brake_all(),
+        }
+    }
+
     /// Convert signed speed (-100 to 100) to direction and absolute percentage.
     fn speed_to_dir(speed: i8) -> (Direction, u8) {
         if speed > 0 {
             (Direction::Forward, speed.min(100) as u8)
         } else if speed < 0 {
-            (Direction::Reverse, (-speed).min(100) as u8)
+            (Direction::Reverse, speed.unsigned_abs().min(100))
         } else {
             (Direction::Stop, 0)
         }
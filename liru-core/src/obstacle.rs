@@ -0,0 +1,59 @@
+//! Sharp IR distance sensor obstacle detection for LiRu robot.
+//!
+//! Optional (`obstacle-sensor` feature): the sensor sits on PC4 (ADC1_IN14)
+//! and is read through `LineSensors::with_obstacle`. Its output voltage
+//! rises as an object gets closer, so a raw reading at or above the
+//! threshold means something is in the way.
+
+/// Default threshold (raw ADC counts), roughly 1.2V: about 20cm for a
+/// GP2Y0A21 looking at a light-colored object.
+const DEFAULT_THRESHOLD: u16 = 1500;
+
+/// The reading must fall this far below the threshold before the path
+/// counts as clear again, so a noisy reading at the edge doesn't stutter.
+const CLEAR_HYSTERESIS: u16 = 100;
+
+/// Obstacle detector with a calibratable distance threshold
+pub struct ObstacleSensor {
+    /// Raw reading at which an obstacle is detected (0 = disabled)
+    threshold: u16,
+    blocked: bool,
+}
+
+impl ObstacleSensor {
+    pub const fn new(threshold: u16) -> Self {
+        Self { threshold, blocked: false }
+    }
+
+    /// Set the detection threshold in raw ADC counts (0 disables detection).
+    pub fn set_threshold(&mut self, threshold: u16) {
+        self.threshold = threshold;
+    }
+
+    /// Whether an obstacle is currently detected.
+    pub fn is_blocked(&self) -> bool {
+        self.blocked
+    }
+
+    /// Feed a raw reading. Returns the new state when it changed.
+    pub fn update(&mut self, raw: u16) -> Option<bool> {
+        let blocked = if self.threshold == 0 {
+            false
+        } else if self.blocked {
+            raw >= self.threshold.saturating_sub(CLEAR_HYSTERESIS)
+        } else {
+            raw >= self.threshold
+        };
+        if blocked == self.blocked {
+            return None;
+        }
+        self.blocked = blocked;
+        Some(blocked)
+    }
+}
+
+impl Default for ObstacleSensor {
+    fn default() -> Self {
+        Self::new(DEFAULT_THRESHOLD)
+    }
+}
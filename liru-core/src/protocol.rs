@@ -0,0 +1,917 @@
+//! LiRu Bluetooth protocol: what the GUI sends and what the robot answers.
+//!
+//! - Commands from GUI: [CMD_BYTE, DATA...]
+//! - Data to GUI: [MSG_TYPE, DATA...]
+//!
+//! Both directions are framed as described in `framing.rs`. For poking the
+//! robot from a plain serial terminal, text commands such as `M 50 -50`,
+//! `STOP`, `MODE 1` or `GET SENS` are parsed into the same commands (see
+//! `parse_text_command`). Replies are always binary.
+//!
+//! Kept apart from the UART driver (`bluetooth.rs` in the firmware) so the
+//! state machine that consumes commands and produces replies builds for the
+//! host too.
+
+use crate::config::RobotConfig;
+use crate::drive::MotorAction;
+use crate::line::{CalibrationReport, LineEvent, RawRange, SensorDiagnostics};
+
+/// Firmware identification reported to the GUI
+pub mod version {
+    /// Semantic version [major, minor, patch], taken from Cargo.toml
+    pub const FIRMWARE: [u8; 3] = [
+        parse_u8(env!("CARGO_PKG_VERSION_MAJOR")),
+        parse_u8(env!("CARGO_PKG_VERSION_MINOR")),
+        parse_u8(env!("CARGO_PKG_VERSION_PATCH")),
+    ];
+
+    /// Capability bits
+    pub mod caps {
+        /// Sends analog debug telemetry (`msg::DEBUG_ANALOG`)
+        pub const ANALOG_TELEMETRY: u8 = 1 << 0;
+        /// PID gains tunable via `cmd::SET_PID`
+        pub const PID_TUNABLE: u8 = 1 << 1;
+        /// Base speed tunable via `cmd::SET_SPEED`
+        pub const SPEED_TUNABLE: u8 = 1 << 2;
+        /// Reports battery voltage (`msg::BATTERY`)
+        pub const BATTERY: u8 = 1 << 3;
+        /// Reports the power-up self-test result (`msg::SELFTEST`)
+        pub const SELFTEST: u8 = 1 << 4;
+        /// Binary sensor patterns carry sensors 9-16 in a second byte (see
+        /// `pattern_bytes`)
+        pub const WIDE_PATTERNS: u8 = 1 << 5;
+    }
+
+    /// Capabilities of this build
+    pub const CAPABILITIES: u8 = caps::ANALOG_TELEMETRY
+        | caps::PID_TUNABLE
+        | caps::SPEED_TUNABLE
+        | caps::BATTERY
+        | caps::SELFTEST
+        | caps::WIDE_PATTERNS;
+
+    const fn parse_u8(s: &str) -> u8 {
+        let bytes = s.as_bytes();
+        let mut value: u8 = 0;
+        let mut i = 0;
+        while i < bytes.len() {
+            value = value * 10 + (bytes[i] - b'0');
+            i += 1;
+        }
+        value
+    }
+}
+
+/// A binary sensor pattern (bit i = sensor i+1) as `[low, high]`. Every
+/// message carrying one (`msg::SENSORS`, `msg::DEBUG`, `msg::DIAG`,
+/// `msg::DEBUG_ANALOG`, `err::LOW_CONTRAST`) keeps the low byte where it
+/// always was and appends the high byte, so a GUI that only knows 8 sensors
+/// reads the messages as before.
+pub fn pattern_bytes(pattern: u16) -> [u8; 2] {
+    let [high, low] = pattern.to_be_bytes();
+    [low, high]
+}
+
+/// Command bytes from GUI
+pub mod cmd {
+    /// Set motor speeds: [CMD_MOTOR, left_speed_i8, right_speed_i8]
+    pub const MOTOR: u8 = 0x01;
+    /// Stop all motors
+    pub const STOP: u8 = 0x02;
+    /// Request sensor data
+    pub const GET_SENSORS: u8 = 0x03;
+    /// Ping (for connection check)
+    pub const PING: u8 = 0x04;
+    /// Request raw sensor data (16-bit)
+    pub const GET_RAW_SENSORS: u8 = 0x05;
+    /// Set robot mode: [CMD_SET_MODE, mode_byte] (0=Car, 1=Line)
+    pub const SET_MODE: u8 = 0x06;
+    /// Start (or restart) Line Follower calibration; stops a run in progress.
+    /// Rejected with `err::WRONG_MODE` in Car mode
+    pub const START: u8 = 0x07;
+    /// Set PID gains: [CMD_SET_PID, Kp_H, Kp_L, Ki_H, Ki_L, Kd_H, Kd_L] (u16, x100).
+    /// Kp applies to both sides of center (see `SET_SIDE_KP`)
+    pub const SET_PID: u8 = 0x08;
+    /// Set base speed: [CMD_SET_SPEED, speed_percent, (turn_percent)] (clamped to 0-100).
+    /// The optional second byte sets the Car mode pivot speed separately.
+    pub const SET_SPEED: u8 = 0x09;
+    /// Request battery voltage
+    pub const GET_BATTERY: u8 = 0x0A;
+    /// Request firmware version and capabilities
+    pub const GET_VERSION: u8 = 0x0B;
+    /// Emergency stop: latches until `CLEAR_ESTOP`, ignoring motor/start commands
+    pub const ESTOP: u8 = 0x0C;
+    /// Release a latched emergency stop
+    pub const CLEAR_ESTOP: u8 = 0x0D;
+    /// Set calibration threshold: [CMD_SET_THRESHOLD, percent, (origin)]
+    /// (1-99, default 40); the margin is measured from white/min (origin 0,
+    /// default) or from black/max (origin 1)
+    pub const SET_THRESHOLD: u8 = 0x0E;
+    /// Proportional drive: [CMD_DRIVE, linear_i8, angular_i8] (-100 to 100, angular + = right)
+    pub const DRIVE: u8 = 0x0F;
+    /// Set line search speeds: [CMD_SET_SEARCH, turn_percent, forward_percent] (clamped to 0-100)
+    pub const SET_SEARCH: u8 = 0x10;
+    /// Set how far the line follower's inner wheel may reverse in sharp corrections:
+    /// [CMD_SET_REVERSE_LIMIT, percent] (clamped to 0-100, 0 = never reverse)
+    pub const SET_REVERSE_LIMIT: u8 = 0x11;
+    /// Set what the line follower does when it loses the line:
+    /// [CMD_SET_RECOVERY, strategy] (0=pivot search, 1=arc then pivot)
+    pub const SET_RECOVERY: u8 = 0x12;
+    /// Request the calibration thresholds and min/max
+    pub const GET_CALIBRATION: u8 = 0x13;
+    /// Request UART receive error counters (answered with `err::UART_ERRORS`)
+    pub const GET_UART_ERRORS: u8 = 0x14;
+    /// Set the obstacle sensor threshold: [CMD_SET_OBSTACLE_THRESHOLD, (raw_H, raw_L)].
+    /// Without a value, the current reading becomes the threshold (place an
+    /// object at the wanted stopping distance first); 0 disables the stop.
+    #[cfg(feature = "obstacle-sensor")]
+    pub const SET_OBSTACLE_THRESHOLD: u8 = 0x15;
+    /// Two-point calibration, white point: all sensors over plain background
+    pub const CAL_WHITE: u8 = 0x16;
+    /// Two-point calibration, black point: all sensors over the line.
+    /// Once both points are captured the robot starts running (`msg::CALIBRATION_END`)
+    pub const CAL_BLACK: u8 = 0x17;
+    /// Set the control loop period: [CMD_SET_LOOP_PERIOD, ms] (2-50, default 10)
+    pub const SET_LOOP_PERIOD: u8 = 0x18;
+    /// Drive straight for a set time, then stop (Car mode, for motor balance
+    /// and distance tests): [CMD_DRIVE_TIMED, speed_percent, duration_x10ms].
+    /// ACKed when the run completes; STOP/E-stop abort it
+    pub const DRIVE_TIMED: u8 = 0x19;
+    /// With the robot centered over the line (Line Follower Idle, calibrated),
+    /// store the position it reads as the center offset
+    pub const CAL_CENTER: u8 = 0x1A;
+    /// Choose the active steering terms: [CMD_SET_CONTROLLER, kind]
+    /// (0=P, 1=PI, 2=PD, 3=PID, default); the gains themselves are kept
+    pub const SET_CONTROLLER: u8 = 0x1B;
+    /// Request a one-shot diagnostic snapshot (answered with `msg::DIAG`)
+    pub const GET_DIAG: u8 = 0x1C;
+    /// Constant line follower steering bias: [CMD_SET_STEERING_BIAS, percent_i8]
+    /// (-20 to 20, + = right), for a robot that pulls to one side
+    pub const SET_STEERING_BIAS: u8 = 0x1D;
+    /// Single short low-speed tap for fine positioning (Car mode or Line
+    /// Follower Idle): [CMD_NUDGE, direction] (0=forward, 1=back, 2=pivot
+    /// left, 3=pivot right)
+    pub const NUDGE: u8 = 0x1E;
+    /// Give up on a lost line after this long: [CMD_SET_SEARCH_TIMEOUT,
+    /// duration_x100ms] (default 30 = 3s; 0 = when the search runs out of sweeps)
+    pub const SET_SEARCH_TIMEOUT: u8 = 0x1F;
+    /// Low-pass the line position before the PID: [CMD_SET_POSITION_FILTER,
+    /// time_constant_ms] (0 = off, default; up to 200)
+    pub const SET_POSITION_FILTER: u8 = 0x20;
+    /// Store the dead-reckoned turn timing and pivot 90 degrees right with it
+    /// (Car mode): [CMD_TURN_CAL, ms_per_90:2, speed], big-endian
+    pub const TURN_CAL: u8 = 0x21;
+    /// Timed pivot by an angle (Car mode): [CMD_TURN, degrees_i16:2],
+    /// big-endian, + = right
+    pub const TURN: u8 = 0x22;
+    /// Largest line follower steering correction, after the bias:
+    /// [CMD_SET_STEERING_LIMIT, percent] (clamped to 0-100, 100 = no limit)
+    pub const SET_STEERING_LIMIT: u8 = 0x23;
+    /// Pause a line follower run: motors stop, calibration and controller
+    /// state are kept (Line Follower Running only)
+    pub const PAUSE: u8 = 0x24;
+    /// Continue a paused run without recalibrating
+    pub const RESUME: u8 = 0x25;
+    /// Skip calibration with manual thresholds: [CMD_SET_THRESHOLDS, 8 x u16]
+    /// (raw ADC counts, little-endian); not while calibrating
+    pub const SET_THRESHOLDS: u8 = 0x26;
+    /// Answer MOTOR with the applied speeds (`msg::MOTOR_OUTPUT`) instead of
+    /// a plain ACK: [CMD_SET_MOTOR_ECHO, enabled] (0 = off, default)
+    pub const SET_MOTOR_ECHO: u8 = 0x27;
+    /// Follow the line with a fixed raw threshold, skipping calibration:
+    /// [CMD_SET_FIXED_THRESHOLD, threshold:2] (big-endian raw ADC counts,
+    /// 0 = back to the calibrated position, default)
+    pub const SET_FIXED_THRESHOLD: u8 = 0x28;
+    /// Request control loop timing (`msg::PERF`)
+    pub const GET_PERF: u8 = 0x29;
+    /// Most the line follower's outer wheel may be raised to keep the turn
+    /// when the inner wheel is floored: [CMD_SET_TURN_BOOST, percent]
+    /// (clamped to 0-100, 0 = off, default)
+    pub const SET_TURN_BOOST: u8 = 0x2A;
+    /// Request every tunable at once (`msg::CONFIG`)
+    pub const GET_CONFIG: u8 = 0x2B;
+    /// Set every tunable at once: [CMD_LOAD_CONFIG, config (25 bytes)] in
+    /// the `RobotConfig` layout; all or nothing
+    pub const LOAD_CONFIG: u8 = 0x2C;
+    /// Save the live tunables to flash, loaded again at power-up
+    pub const SAVE_CONFIG: u8 = 0x2D;
+    /// Car mode inactivity stop: [CMD_SET_CAR_TIMEOUT, duration] in 100ms
+    /// units (0 = never, default 7)
+    pub const SET_CAR_TIMEOUT: u8 = 0x2E;
+    /// Request the raw ADC extremes of the last (or current) line follower
+    /// run (`msg::RUN_RANGE`)
+    pub const GET_RUN_RANGE: u8 = 0x2F;
+    /// Separate proportional gains for a line left and right of center:
+    /// [CMD_SET_SIDE_KP, left:2, right:2] (u16, x100)
+    pub const SET_SIDE_KP: u8 = 0x30;
+    /// Orderly shutdown: ramp the motors to zero, send a last `msg::STATUS`
+    /// and park, answering only PING until the next power-up
+    pub const SHUTDOWN: u8 = 0x31;
+    /// Scale the line follower's base speed continuously with how centered
+    /// and strong the line is: [CMD_SET_SPEED_SCALE, min_percent] (share of
+    /// the base speed kept at worst, 100 = off, default)
+    pub const SET_SPEED_SCALE: u8 = 0x32;
+    /// Pivot onto the line center before each line follower run:
+    /// [CMD_SET_CENTER_ON_START, enabled] (0 = off, default)
+    pub const SET_CENTER_ON_START: u8 = 0x33;
+    /// Which reads as the line: [CMD_SET_POLARITY, polarity]
+    /// (0 = dark line on a light mat, default; 1 = light line on a dark mat)
+    pub const SET_POLARITY: u8 = 0x34;
+    /// Stream telemetry without being asked: [CMD_STREAM, kind, period]
+    /// (kind 0 = none, 1 = binary pattern, 2 = raw readings, 3 = analog
+    /// telemetry; period in 10ms units, 0 = off)
+    pub const STREAM: u8 = 0x35;
+    /// How long every sensor must see the line before it counts as the
+    /// finish bar: [CMD_SET_FINISH_SUSTAIN, duration] in 100ms units
+    /// (0 = never stop at a bar, default 3)
+    pub const SET_FINISH_SUSTAIN: u8 = 0x36;
+}
+
+/// Message types to GUI
+pub mod msg {
+    /// Sensor data: [MSG_SENSORS, pattern_low, pattern_high]
+    pub const SENSORS: u8 = 0x10;
+    /// Pong response
+    pub const PONG: u8 = 0x11;
+    /// Connection established
+    pub const CONNECTED: u8 = 0x12;
+    /// Raw sensor data: [MSG_RAW_SENSORS, 16 bytes of data]
+    pub const RAW_SENSORS: u8 = 0x13;
+    /// Debug message: [MSG_DEBUG, mode_byte, pattern_low, motor_action_byte,
+    /// pattern_high] (mode 0=Car, 1=Idle, 2=Calibrating, 3=Running; the
+    /// pattern is the binary sensor pattern; action is a `MotorAction`)
+    pub const DEBUG: u8 = 0x14;
+    /// Calibration started
+    pub const CALIBRATION_START: u8 = 0x15;
+    /// Calibration ended
+    pub const CALIBRATION_END: u8 = 0x16;
+    /// Analog debug data: [MSG_DEBUG_ANALOG, PosH, PosL, IntH, IntL, Steer, L_Speed, R_Speed, Confidence,
+    /// ..., Binary] (see `TelemetryFrame`)
+    pub const DEBUG_ANALOG: u8 = 0x17;
+    /// PID gains applied: [MSG_PID, Kp_H, Kp_L, Ki_H, Ki_L, Kd_H, Kd_L]
+    pub const PID: u8 = 0x18;
+    /// Battery voltage: [MSG_BATTERY, mV_H, mV_L] (0 = unknown)
+    pub const BATTERY: u8 = 0x19;
+    /// Firmware version: [MSG_VERSION, major, minor, patch, capabilities]
+    pub const VERSION: u8 = 0x1A;
+    /// Line follower reached the finish line and stopped
+    pub const FINISHED: u8 = 0x1B;
+    /// Power-up self-test result: [MSG_SELFTEST, passed (0/1), dead sensor bitmask]
+    pub const SELFTEST: u8 = 0x1C;
+    /// Emergency stop state changed: [MSG_ESTOP, latched (0/1)]
+    pub const ESTOP: u8 = 0x1D;
+    /// Command handled: [MSG_ACK, command_byte], for state-changing commands
+    /// without a dedicated reply
+    pub const ACK: u8 = 0x1E;
+    /// Calibration report: [MSG_CALIBRATION, calibrated (0/1), 8 x threshold,
+    /// 8 x min, 8 x max], each u16 little-endian
+    pub const CALIBRATION: u8 = 0x1F;
+    /// Obstacle ahead (line follower halted) or cleared: [MSG_OBSTACLE, blocked (0/1)]
+    #[cfg(feature = "obstacle-sensor")]
+    pub const OBSTACLE: u8 = 0x20;
+    /// Line follower run ended: stats for the run (see `RunStats`)
+    pub const RUN_STATS: u8 = 0x21;
+    /// Calibration progress, once a second while calibrating:
+    /// [MSG_CALIBRATION_PROGRESS, elapsed_s, 8 x max-min spread], each u16 little-endian
+    pub const CALIBRATION_PROGRESS: u8 = 0x22;
+    /// Compact robot status heartbeat, ~5Hz (see `StatusFlags`)
+    pub const STATUS: u8 = 0x23;
+    /// Diagnostic snapshot from one sensor read: [MSG_DIAG, 8 x raw, 8 x
+    /// normalized (0-1000), binary_low, position (i16), intensity,
+    /// binary_high], multi-byte values little-endian
+    pub const DIAG: u8 = 0x24;
+    /// Mode changed, and once when a GUI connects: [MSG_MODE, mode]
+    /// (0=Car, 1=Idle, 2=Calibrating, 3=Running, 4=Paused, 5=E-stop latched,
+    /// 6=Shut down)
+    pub const MODE: u8 = 0x25;
+    /// Reply to MOTOR with echo on: the speeds the motors actually got, after
+    /// saturation, the speed limits and stall cuts: [MSG_MOTOR_OUTPUT,
+    /// left_i8, right_i8]
+    pub const MOTOR_OUTPUT: u8 = 0x26;
+    /// Control loop iteration time over the last window: [MSG_PERF, min:2,
+    /// max:2, avg:2] (microseconds, big-endian)
+    pub const PERF: u8 = 0x27;
+    /// Every tunable, reply to GET_CONFIG: [MSG_CONFIG, config (25 bytes)]
+    /// in the `RobotConfig` layout
+    pub const CONFIG: u8 = 0x28;
+    /// Raw ADC extremes over the last line follower run, reply to
+    /// GET_RUN_RANGE: [MSG_RUN_RANGE, 8 x min, 8 x max], each u16 little-endian
+    pub const RUN_RANGE: u8 = 0x29;
+    /// Line follower read a junction (confirmed over consecutive ticks):
+    /// [MSG_LINE_EVENT, event] (1 = full line, 2 = left branch, 3 = right branch)
+    pub const LINE_EVENT: u8 = 0x2A;
+    /// Error message: [MSG_ERROR, error_code, detail...] (detail depends on the code)
+    pub const ERROR: u8 = 0xFF;
+}
+
+/// Error codes carried by `msg::ERROR`
+pub mod err {
+    /// A command parameter was out of range and the command was rejected
+    pub const INVALID_PARAM: u8 = 0x01;
+    /// Line follower searched for the lost line and gave up
+    pub const LINE_LOST: u8 = 0x02;
+    /// Command ignored because the emergency stop is latched
+    pub const ESTOP_LATCHED: u8 = 0x03;
+    /// Calibration saw too little contrast; followed by a bitmask of the bad
+    /// channels (low byte, high byte). A warning: they run on a fallback range
+    pub const LOW_CONTRAST: u8 = 0x04;
+    /// Only background seen for a while during the line search: left the mat
+    pub const OFF_MAT: u8 = 0x05;
+    /// Command not accepted in the current mode (e.g. `MOTOR` outside Car mode)
+    pub const WRONG_MODE: u8 = 0x06;
+    /// UART receive error counters, not a failure by itself (reply to
+    /// `cmd::GET_UART_ERRORS`): [overrun:2] [framing:2] [parity:2] [noise:2]
+    /// [checksum:2], big-endian
+    pub const UART_ERRORS: u8 = 0x07;
+    /// Saving the config to flash failed (or the config sector is full
+    /// until the next power-up)
+    pub const FLASH: u8 = 0x08;
+    /// Battery below the cutoff: stopped, and anything that would move the
+    /// robot is refused until it recovers
+    pub const BATTERY_CUTOFF: u8 = 0x09;
+}
+
+/// Parsed command from GUI
+#[derive(Debug, Clone, Copy)]
+pub enum Command {
+    /// Set motor speeds (left, right) from -100 to 100
+    Motor { left: i8, right: i8 },
+    /// Proportional joystick drive (linear, angular) from -100 to 100
+    Drive { linear: i8, angular: i8 },
+    /// Stop all motors
+    Stop,
+    /// Request sensor readings
+    GetSensors,
+    /// Request raw sensor readings
+    GetRawSensors,
+    /// Ping request
+    Ping,
+    /// Set Robot Mode
+    SetMode(u8),
+    /// Start calibration/run
+    Start,
+    /// Set PID gains (fixed-point x100)
+    SetPid { kp: u16, ki: u16, kd: u16 },
+    /// Proportional gains per side of center (x100)
+    SetSideKp { left: u16, right: u16 },
+    /// Ramp down and park; handled by the control task itself
+    Shutdown,
+    /// Continuous speed scaling floor in percent (100 = off)
+    SetSpeedScale(u8),
+    /// Pre-run centering on or off
+    SetCenterOnStart(bool),
+    /// Line polarity (`cmd::SET_POLARITY` value)
+    SetPolarity(u8),
+    /// Streamed telemetry (`cmd::STREAM` kind) and its period in 10ms units
+    SetStream { kind: u8, period: u8 },
+    /// Finish bar sustain time in 100ms units (0 = never)
+    SetFinishSustain(u8),
+    /// Set base/drive speed and optionally the Car mode turn speed (0-100)
+    SetSpeed { drive: u8, turn: Option<u8> },
+    /// Request battery voltage
+    GetBattery,
+    /// Request firmware version
+    GetVersion,
+    /// Latch the emergency stop
+    EStop,
+    /// Release the emergency stop
+    ClearEStop,
+    /// Set calibration threshold percentage and origin (0=white, 1=black)
+    SetThreshold { pct: u8, origin: u8 },
+    /// Set line search pivot and forward-creep speeds (0-100)
+    SetSearch { turn: u8, forward: u8 },
+    /// Set the line follower's inner-wheel reverse limit (0-100)
+    SetReverseLimit(u8),
+    /// Set the line recovery strategy (0=pivot, 1=arc)
+    SetRecovery(u8),
+    /// Set the control loop period in ms
+    SetLoopPeriod(u8),
+    /// Drive both motors forward at `speed` for `ds` x 10ms, then stop
+    DriveTimed { speed: u8, ds: u8 },
+    /// Measure the sensor bar's center offset
+    CalCenter,
+    /// Set the active steering terms (0=P, 1=PI, 2=PD, 3=PID)
+    SetController(u8),
+    /// Request a diagnostic snapshot
+    GetDiag,
+    /// Set the line follower's steering bias (percent, + = right)
+    SetSteeringBias(i8),
+    /// Tap the motors in a direction (0=fwd, 1=back, 2=left, 3=right)
+    Nudge(u8),
+    /// Set the lost-line recovery timeout in 100ms units (0 = no cap)
+    SetSearchTimeout(u8),
+    /// Position filter time constant in ms (0 = off)
+    SetPositionFilter(u8),
+    /// Dead-reckoned pivot, degrees (+ = right)
+    Turn(i16),
+    /// Turn timing: pivot time for 90 degrees and the pivot speed
+    TurnCal { ms_per_90: u16, speed: u8 },
+    /// Steering magnitude cap in percent
+    SetSteeringLimit(u8),
+    Pause,
+    Resume,
+    /// Per-channel binary thresholds (raw ADC counts)
+    SetThresholds([u16; 8]),
+    /// Echo the applied motor output for MOTOR commands
+    SetMotorEcho(bool),
+    /// Fixed raw line threshold (0 = use the calibration)
+    SetFixedThreshold(u16),
+    /// Request control loop timing; answered by the control task itself
+    GetPerf,
+    /// Outer wheel boost cap in percent
+    SetTurnBoost(u8),
+    /// Request the tunables
+    GetConfig,
+    /// Replace the tunables (encoded `RobotConfig`, validated by the state machine)
+    LoadConfig([u8; RobotConfig::LEN]),
+    /// Save the tunables to flash; handled by the control task itself
+    SaveConfig,
+    /// Car mode inactivity stop in 100ms units (0 = never)
+    SetCarTimeout(u8),
+    /// Request the raw ADC extremes of the last run
+    GetRunRange,
+    /// Request calibration thresholds
+    GetCalibration,
+    /// Two-point calibration: capture white (min)
+    CalWhite,
+    /// Two-point calibration: capture black (max)
+    CalBlack,
+    /// Request UART receive error counters; answered by the receive task itself
+    GetUartErrors,
+    /// Set the obstacle threshold (raw ADC), or None to use the current reading
+    #[cfg(feature = "obstacle-sensor")]
+    SetObstacleThreshold(Option<u16>),
+    /// Unknown command
+    Unknown(u8),
+}
+
+/// Outgoing message to GUI
+#[derive(Debug, Clone, Copy)]
+pub enum Reply {
+    /// Binary sensor pattern
+    Sensors(u16),
+    /// Raw sensor readings
+    RawSensors([u16; 8]),
+    /// Calibration thresholds and min/max
+    Calibration(CalibrationReport),
+    /// Pong response
+    Pong,
+    /// Calibration started
+    CalibrationStart,
+    /// Calibration ended
+    CalibrationEnd,
+    /// Calibration progress: seconds elapsed and each channel's max-min spread so far
+    CalibrationProgress { elapsed_s: u8, spread: [u16; 8] },
+    /// Applied PID gains (x100)
+    Pid { kp: u16, ki: u16, kd: u16 },
+    /// Battery voltage in millivolts
+    Battery(u16),
+    /// Firmware version and capabilities
+    Version,
+    /// Finish line reached
+    Finished,
+    /// Emergency stop latched (true) or cleared (false)
+    EStop(bool),
+    /// Command with the given `cmd` byte was handled
+    Ack(u8),
+    /// Analog line follower telemetry
+    Telemetry(TelemetryFrame),
+    /// Error with one of the `err` codes
+    Error(u8),
+    /// Calibration contrast too low (`err::LOW_CONTRAST`) on the channels in the bitmask
+    LowContrast(u16),
+    /// UART receive error counters (`err::UART_ERRORS`)
+    UartErrors(UartErrorStats),
+    /// Simple debug view: mode byte, binary sensor pattern, motor action
+    Debug { mode: u8, position: u16, action: MotorAction },
+    /// Obstacle detected (true) or cleared (false)
+    #[cfg(feature = "obstacle-sensor")]
+    Obstacle(bool),
+    /// Line follower run ended
+    RunStats(RunStats),
+    /// Periodic status heartbeat
+    Status(StatusFlags),
+    /// Diagnostic sensor snapshot
+    Diag(SensorDiagnostics),
+    /// Mode changed (`msg::MODE` value)
+    Mode(u8),
+    /// Applied motor speeds (left, right)
+    MotorOutput([i8; 2]),
+    /// Control loop timing
+    Perf(LoopPerf),
+    /// Live tunables
+    Config(RobotConfig),
+    /// Raw ADC extremes over the last run
+    RunRange(RawRange),
+    /// Junction under the array (`msg::LINE_EVENT`)
+    LineEvent(LineEvent),
+}
+
+impl Reply {
+    /// Whether the reply may be dropped when the link is busy (periodic or
+    /// debug data); false for replies that must go out.
+    pub fn is_droppable(&self) -> bool {
+        matches!(
+            self,
+            Reply::Sensors(_)
+                | Reply::RawSensors(_)
+                | Reply::Battery(_)
+                | Reply::Telemetry(_)
+                | Reply::Debug { .. }
+                | Reply::CalibrationProgress { .. }
+        )
+    }
+}
+
+/// Line follower telemetry sent as `msg::DEBUG_ANALOG`.
+///
+/// Wire layout (big-endian), new fields are only ever appended:
+/// [Type 0x17] [Pos_H] [Pos_L] [Int_H] [Int_L] [Steer] [L_Speed] [R_Speed] [Confidence] [Coverage]
+/// [Controller] [Saturated] [Binary_L] [Limited] [Search] [Binary_H]
+#[derive(Debug, Clone, Copy)]
+pub struct TelemetryFrame {
+    /// Weighted line position (-3500..3500)
+    pub position: i16,
+    /// Sum of normalized sensor values
+    pub intensity: u16,
+    /// Effective steering correction: PID output plus bias, after the
+    /// steering limit (percent)
+    pub steering: i8,
+    /// Left motor speed (percent, negative = reverse)
+    pub left_speed: i8,
+    /// Right motor speed (percent, negative = reverse)
+    pub right_speed: i8,
+    /// Line confidence (0-100)
+    pub confidence: u8,
+    /// Share of the array over the line (0-100)
+    pub coverage: u8,
+    /// Active steering terms (`cmd::SET_CONTROLLER` value)
+    pub controller: u8,
+    /// A wheel hit its speed clamp on the last control tick
+    pub saturated: bool,
+    /// Sensors over the line (bit i = sensor i+1), as in `msg::SENSORS`
+    pub binary: u16,
+    /// The steering limit cut the correction on the last control tick
+    pub steering_limited: bool,
+    /// Lost-line recovery phase (`SearchState` value, 0 = on the line)
+    pub search: u8,
+}
+
+impl TelemetryFrame {
+    /// Encoded length including the message type byte
+    pub const LEN: usize = 16;
+
+    /// Serialize to the wire layout, message type byte first
+    pub fn encode(&self) -> [u8; Self::LEN] {
+        let pos = self.position.to_be_bytes();
+        let int = self.intensity.to_be_bytes();
+        let [binary_low, binary_high] = pattern_bytes(self.binary);
+        [
+            msg::DEBUG_ANALOG,
+            pos[0], pos[1],
+            int[0], int[1],
+            self.steering as u8,
+            self.left_speed as u8,
+            self.right_speed as u8,
+            self.confidence,
+            self.coverage,
+            self.controller,
+            self.saturated as u8,
+            binary_low,
+            self.steering_limited as u8,
+            self.search,
+            binary_high,
+        ]
+    }
+}
+
+/// Post-run tuning numbers, sent as `msg::RUN_STATS` when a line follower
+/// run ends (stop, finish, line lost, E-stop...).
+///
+/// Wire layout (big-endian):
+/// [Type 0x21] [Err_H] [Err_L] [Loss_H] [Loss_L] [Time (4)] [Max_Speed] [Sat_H] [Sat_L]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RunStats {
+    /// Peak absolute line position while on the line (0..3500)
+    pub peak_error: u16,
+    /// Times the line was lost during the run
+    pub line_losses: u16,
+    /// Time spent following, in ms (obstacle pauses excluded)
+    pub run_ms: u32,
+    /// Highest wheel speed commanded by the controller (percent)
+    pub max_speed: u8,
+    /// Times a wheel started hitting its speed clamp
+    pub saturations: u16,
+}
+
+impl RunStats {
+    /// Encoded length including the message type byte
+    pub const LEN: usize = 12;
+
+    /// Serialize to the wire layout, message type byte first
+    pub fn encode(&self) -> [u8; Self::LEN] {
+        let err = self.peak_error.to_be_bytes();
+        let losses = self.line_losses.to_be_bytes();
+        let time = self.run_ms.to_be_bytes();
+        let saturations = self.saturations.to_be_bytes();
+        [
+            msg::RUN_STATS,
+            err[0], err[1],
+            losses[0], losses[1],
+            time[0], time[1], time[2], time[3],
+            self.max_speed,
+            saturations[0], saturations[1],
+        ]
+    }
+}
+
+/// Control loop iteration time (work only, not the wait for the next tick)
+/// over a window, sent as `msg::PERF`.
+///
+/// Wire layout (big-endian):
+/// [Type 0x27] [Min_H] [Min_L] [Max_H] [Max_L] [Avg_H] [Avg_L]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LoopPerf {
+    /// Fastest iteration (us)
+    pub min_us: u16,
+    /// Slowest iteration (us)
+    pub max_us: u16,
+    /// Mean iteration (us)
+    pub avg_us: u16,
+}
+
+impl LoopPerf {
+    /// Encoded length including the message type byte
+    pub const LEN: usize = 7;
+
+    /// Serialize to the wire layout, message type byte first
+    pub fn encode(&self) -> [u8; Self::LEN] {
+        let min = self.min_us.to_be_bytes();
+        let max = self.max_us.to_be_bytes();
+        let avg = self.avg_us.to_be_bytes();
+        [msg::PERF, min[0], min[1], max[0], max[1], avg[0], avg[1]]
+    }
+}
+
+/// Robot status packed into two bytes, sent as `msg::STATUS`.
+///
+/// Wire layout: [Type 0x23] [Flags] [Stalled]; in Flags, bits 0-1 are
+/// the mode (as in `msg::DEBUG`), then one bit each from bit 2: connected,
+/// E-stop latched, calibrated, line lost, battery low, paused. Stalled has
+/// bit 0 (left) and bit 1 (right); its upper bits take any future flags.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StatusFlags {
+    /// 0=Car, 1=Idle, 2=Calibrating, 3=Running
+    pub mode: u8,
+    /// The GUI has sent a command within the heartbeat timeout
+    pub connected: bool,
+    pub estop: bool,
+    pub calibrated: bool,
+    /// Running and currently searching for the line
+    pub line_lost: bool,
+    pub battery_low: bool,
+    /// Running, paused by `cmd::PAUSE`
+    pub paused: bool,
+    /// Motors cut by the stall guard (bit 0 = left, bit 1 = right)
+    pub stalled: u8,
+}
+
+impl StatusFlags {
+    const CONNECTED: u8 = 1 << 2;
+    const ESTOP: u8 = 1 << 3;
+    const CALIBRATED: u8 = 1 << 4;
+    const LINE_LOST: u8 = 1 << 5;
+    const BATTERY_LOW: u8 = 1 << 6;
+    const PAUSED: u8 = 1 << 7;
+
+    /// Packed flags, without the message type byte
+    pub fn bits(&self) -> [u8; 2] {
+        let flag = |set: bool, bit: u8| if set { bit } else { 0 };
+        let flags = (self.mode & 0b11)
+            | flag(self.connected, Self::CONNECTED)
+            | flag(self.estop, Self::ESTOP)
+            | flag(self.calibrated, Self::CALIBRATED)
+            | flag(self.line_lost, Self::LINE_LOST)
+            | flag(self.battery_low, Self::BATTERY_LOW)
+            | flag(self.paused, Self::PAUSED);
+        [flags, self.stalled & 0b11]
+    }
+}
+
+/// UART receive errors seen since power-up (saturating counters)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UartErrorStats {
+    pub overrun: u16,
+    pub framing: u16,
+    pub parity: u16,
+    pub noise: u16,
+    /// Frames dropped for a checksum mismatch
+    pub checksum: u16,
+}
+
+/// Parse a decoded command frame
+pub fn parse_command(frame: &[u8]) -> Option<Command> {
+    let (&cmd_byte, payload) = frame.split_first()?;
+    let byte_at = |i: usize| payload.get(i).copied();
+    let u16_at = |i: usize| Some(u16::from_be_bytes([byte_at(i)?, byte_at(i + 1)?]));
+
+    let command = match cmd_byte {
+        cmd::MOTOR => Command::Motor {
+            left: byte_at(0)? as i8,
+            right: byte_at(1)? as i8,
+        },
+        cmd::DRIVE => Command::Drive {
+            linear: byte_at(0)? as i8,
+            angular: byte_at(1)? as i8,
+        },
+        cmd::STOP => Command::Stop,
+        cmd::GET_SENSORS => Command::GetSensors,
+        cmd::GET_RAW_SENSORS => Command::GetRawSensors,
+        cmd::PING => Command::Ping,
+        cmd::SET_MODE => Command::SetMode(byte_at(0)?),
+        cmd::START => Command::Start,
+        cmd::SET_PID => Command::SetPid {
+            kp: u16_at(0)?,
+            ki: u16_at(2)?,
+            kd: u16_at(4)?,
+        },
+        cmd::SET_SPEED => Command::SetSpeed {
+            drive: byte_at(0)?.min(100),
+            turn: byte_at(1).map(|turn| turn.min(100)),
+        },
+        cmd::GET_BATTERY => Command::GetBattery,
+        cmd::GET_VERSION => Command::GetVersion,
+        cmd::ESTOP => Command::EStop,
+        cmd::CLEAR_ESTOP => Command::ClearEStop,
+        cmd::SET_THRESHOLD => Command::SetThreshold {
+            pct: byte_at(0)?,
+            origin: byte_at(1).unwrap_or(0),
+        },
+        cmd::SET_SEARCH => Command::SetSearch {
+            turn: byte_at(0)?.min(100),
+            forward: byte_at(1)?.min(100),
+        },
+        cmd::SET_REVERSE_LIMIT => Command::SetReverseLimit(byte_at(0)?.min(100)),
+        cmd::SET_RECOVERY => Command::SetRecovery(byte_at(0)?),
+        cmd::GET_CALIBRATION => Command::GetCalibration,
+        cmd::GET_UART_ERRORS => Command::GetUartErrors,
+        cmd::CAL_WHITE => Command::CalWhite,
+        cmd::CAL_BLACK => Command::CalBlack,
+        cmd::SET_LOOP_PERIOD => Command::SetLoopPeriod(byte_at(0)?),
+        cmd::CAL_CENTER => Command::CalCenter,
+        cmd::SET_CONTROLLER => Command::SetController(byte_at(0)?),
+        cmd::GET_DIAG => Command::GetDiag,
+        cmd::SET_STEERING_BIAS => Command::SetSteeringBias(byte_at(0)? as i8),
+        cmd::NUDGE => Command::Nudge(byte_at(0)?),
+        cmd::SET_SEARCH_TIMEOUT => Command::SetSearchTimeout(byte_at(0)?),
+        cmd::SET_POSITION_FILTER => Command::SetPositionFilter(byte_at(0)?),
+        cmd::TURN => Command::Turn(u16_at(0)? as i16),
+        cmd::SET_STEERING_LIMIT => Command::SetSteeringLimit(byte_at(0)?.min(100)),
+        cmd::PAUSE => Command::Pause,
+        cmd::RESUME => Command::Resume,
+        cmd::SET_MOTOR_ECHO => Command::SetMotorEcho(byte_at(0)? != 0),
+        cmd::SET_FIXED_THRESHOLD => Command::SetFixedThreshold(u16_at(0)?),
+        cmd::GET_PERF => Command::GetPerf,
+        cmd::SET_TURN_BOOST => Command::SetTurnBoost(byte_at(0)?.min(100)),
+        cmd::GET_CONFIG => Command::GetConfig,
+        cmd::SAVE_CONFIG => Command::SaveConfig,
+        cmd::SET_CAR_TIMEOUT => Command::SetCarTimeout(byte_at(0)?),
+        cmd::GET_RUN_RANGE => Command::GetRunRange,
+        cmd::SET_SIDE_KP => Command::SetSideKp { left: u16_at(0)?, right: u16_at(2)? },
+        cmd::SHUTDOWN => Command::Shutdown,
+        cmd::SET_SPEED_SCALE => Command::SetSpeedScale(byte_at(0)?.min(100)),
+        cmd::SET_CENTER_ON_START => Command::SetCenterOnStart(byte_at(0)? != 0),
+        cmd::SET_POLARITY => Command::SetPolarity(byte_at(0)?),
+        cmd::STREAM => Command::SetStream {
+            kind: byte_at(0)?,
+            period: byte_at(1)?,
+        },
+        cmd::SET_FINISH_SUSTAIN => Command::SetFinishSustain(byte_at(0)?),
+        cmd::LOAD_CONFIG => Command::LoadConfig(payload.get(..RobotConfig::LEN)?.try_into().ok()?),
+        cmd::SET_THRESHOLDS => {
+            let mut thresholds = [0u16; 8];
+            for (i, threshold) in thresholds.iter_mut().enumerate() {
+                *threshold = u16::from_le_bytes([byte_at(i * 2)?, byte_at(i * 2 + 1)?]);
+            }
+            Command::SetThresholds(thresholds)
+        }
+        cmd::TURN_CAL => Command::TurnCal {
+            ms_per_90: u16_at(0)?,
+            speed: byte_at(2)?,
+        },
+        cmd::DRIVE_TIMED => Command::DriveTimed {
+            speed: byte_at(0)?.min(100),
+            ds: byte_at(1)?,
+        },
+        #[cfg(feature = "obstacle-sensor")]
+        cmd::SET_OBSTACLE_THRESHOLD => Command::SetObstacleThreshold(u16_at(0)),
+        other => Command::Unknown(other),
+    };
+    Some(command)
+}
+
+/// Parse a text command line into the binary frame it stands for, then into
+/// a `Command` exactly like a received frame (same ranges and clamping).
+///
+/// Keywords are case-insensitive, numbers are decimal and may be negative:
+/// `M l r`, `D linear angular`, `STOP`, `PING`, `MODE n`, `START`,
+/// `PID kp ki kd`, `SPEED drive [turn]`, `ESTOP`, `CLEAR`, `THRESHOLD pct [origin]`,
+/// `SEARCH turn forward`, `REVERSE pct`, `RECOVERY n`, `WHITE`, `BLACK`,
+/// `LOOP ms`, `TIMED speed ds`, `CENTER`, `CTRL n`, `BIAS pct`, `NUDGE dir`, `GIVEUP ds`, `SMOOTH ms`,
+/// `TURN degrees`, `TURNCAL ms speed`, `LIMIT pct`, `PAUSE`, `RESUME`, `ECHO 0|1`, `FIXED raw`, `BOOST pct`, `SAVE`, `AUTOSTOP ds`, `KP left right`, `SHUTDOWN`, `SCALE pct`, `PRECENTER 0|1`, `POLARITY 0|1`, `STREAM kind period`, `FINISH ds`, and `GET SENS|RAW|BAT|VER|CAL|ERR|DIAG|PERF|CONFIG|RANGE`.
+pub fn parse_text_command(line: &[u8]) -> Option<Command> {
+    const COMMANDS: &[(&str, u8)] = &[
+        ("M", cmd::MOTOR),
+        ("D", cmd::DRIVE),
+        ("STOP", cmd::STOP),
+        ("PING", cmd::PING),
+        ("MODE", cmd::SET_MODE),
+        ("START", cmd::START),
+        ("PID", cmd::SET_PID),
+        ("SPEED", cmd::SET_SPEED),
+        ("ESTOP", cmd::ESTOP),
+        ("CLEAR", cmd::CLEAR_ESTOP),
+        ("THRESHOLD", cmd::SET_THRESHOLD),
+        ("SEARCH", cmd::SET_SEARCH),
+        ("REVERSE", cmd::SET_REVERSE_LIMIT),
+        ("RECOVERY", cmd::SET_RECOVERY),
+        ("WHITE", cmd::CAL_WHITE),
+        ("BLACK", cmd::CAL_BLACK),
+        ("LOOP", cmd::SET_LOOP_PERIOD),
+        ("TIMED", cmd::DRIVE_TIMED),
+        ("CENTER", cmd::CAL_CENTER),
+        ("CTRL", cmd::SET_CONTROLLER),
+        ("BIAS", cmd::SET_STEERING_BIAS),
+        ("NUDGE", cmd::NUDGE),
+        ("GIVEUP", cmd::SET_SEARCH_TIMEOUT),
+        ("SMOOTH", cmd::SET_POSITION_FILTER),
+        ("TURN", cmd::TURN),
+        ("TURNCAL", cmd::TURN_CAL),
+        ("LIMIT", cmd::SET_STEERING_LIMIT),
+        ("PAUSE", cmd::PAUSE),
+        ("RESUME", cmd::RESUME),
+        ("ECHO", cmd::SET_MOTOR_ECHO),
+        ("FIXED", cmd::SET_FIXED_THRESHOLD),
+        ("BOOST", cmd::SET_TURN_BOOST),
+        ("SAVE", cmd::SAVE_CONFIG),
+        ("AUTOSTOP", cmd::SET_CAR_TIMEOUT),
+        ("KP", cmd::SET_SIDE_KP),
+        ("SHUTDOWN", cmd::SHUTDOWN),
+        ("SCALE", cmd::SET_SPEED_SCALE),
+        ("PRECENTER", cmd::SET_CENTER_ON_START),
+        ("POLARITY", cmd::SET_POLARITY),
+        ("STREAM", cmd::STREAM),
+        ("FINISH", cmd::SET_FINISH_SUSTAIN),
+    ];
+    const GETS: &[(&str, u8)] = &[
+        ("SENS", cmd::GET_SENSORS),
+        ("RAW", cmd::GET_RAW_SENSORS),
+        ("BAT", cmd::GET_BATTERY),
+        ("VER", cmd::GET_VERSION),
+        ("CAL", cmd::GET_CALIBRATION),
+        ("ERR", cmd::GET_UART_ERRORS),
+        ("DIAG", cmd::GET_DIAG),
+        ("PERF", cmd::GET_PERF),
+        ("CONFIG", cmd::GET_CONFIG),
+        ("RANGE", cmd::GET_RUN_RANGE),
+    ];
+    let lookup = |table: &[(&str, u8)], word: &str| {
+        table.iter().find(|(name, _)| name.eq_ignore_ascii_case(word)).map(|&(_, byte)| byte)
+    };
+
+    let line = core::str::from_utf8(line).ok()?;
+    let mut words = line.split_ascii_whitespace();
+    let keyword = words.next()?;
+    let cmd_byte = if keyword.eq_ignore_ascii_case("GET") {
+        lookup(GETS, words.next()?)?
+    } else {
+        lookup(COMMANDS, keyword)?
+    };
+
+    // PID gains, the turn angle, the turn timing and the fixed threshold are
+    // 16-bit on the wire, every other argument is one byte
+    let mut frame = [0u8; 8];
+    frame[0] = cmd_byte;
+    let mut len = 1;
+    for (index, word) in words.enumerate() {
+        let value: i32 = word.parse().ok()?;
+        let wide = matches!(cmd_byte, cmd::SET_PID | cmd::SET_SIDE_KP)
+            || (index == 0 && matches!(cmd_byte, cmd::TURN | cmd::TURN_CAL | cmd::SET_FIXED_THRESHOLD));
+        if wide {
+            let bytes = if cmd_byte == cmd::TURN {
+                i16::try_from(value).ok()?.to_be_bytes()
+            } else {
+                u16::try_from(value).ok()?.to_be_bytes()
+            };
+            frame.get_mut(len..len + 2)?.copy_from_slice(&bytes);
+            len += 2;
+        } else {
+            if !(-128..=255).contains(&value) {
+                return None;
+            }
+            *frame.get_mut(len)? = value as u8;
+            len += 1;
+        }
+    }
+    parse_command(&frame[..len])
+}
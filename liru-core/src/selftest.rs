@@ -0,0 +1,75 @@
+//! Power-up self-test for LiRu robot.
+//!
+//! Runs once before the robot accepts commands:
+//! - Pulses each motor forward then reverse at low duty so wiring faults are
+//!   visible on the bench (only with the `selftest-motors` feature, which is on
+//!   by default; racers can build with `--no-default-features` to skip it).
+//! - Does one ADC sweep and flags channels stuck at either rail.
+
+use defmt::{info, warn};
+
+use crate::motors::MotorController;
+use crate::sensors::{CalibratedSensors, SENSOR_COUNT};
+
+/// A reading of exactly this looks like a channel shorted to ground / unplugged.
+const STUCK_LOW_RAW: u16 = 0;
+
+/// Readings at or above this look like a channel stuck at the supply rail.
+const STUCK_HIGH_RAW: u16 = 4095;
+
+/// Outcome of the self-test
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SelfTestReport {
+    /// Bit i set = sensor i looked dead (stuck at 0 or 4095)
+    pub dead_channels: u8,
+}
+
+impl SelfTestReport {
+    pub fn passed(&self) -> bool {
+        self.dead_channels == 0
+    }
+}
+
+/// Run the self-test. Takes well under a second.
+pub async fn run(motors: &mut MotorController<'_>, sensors: &mut CalibratedSensors<'_>) -> SelfTestReport {
+    #[cfg(feature = "selftest-motors")]
+    pulse_motors(motors).await;
+    #[cfg(not(feature = "selftest-motors"))]
+    let _ = motors;
+
+    let readings = sensors.read_all().await;
+    let mut dead_channels = 0u8;
+    for (i, &raw) in readings.iter().enumerate().take(SENSOR_COUNT) {
+        if raw == STUCK_LOW_RAW || raw >= STUCK_HIGH_RAW {
+            warn!("Self-test: sensor {} looks dead (raw={})", i + 1, raw);
+            dead_channels |= 1 << i;
+        }
+    }
+
+    let report = SelfTestReport { dead_channels };
+    if report.passed() {
+        info!("Self-test passed");
+    }
+    report
+}
+
+/// Pulse each motor forward then reverse for 100ms at low duty.
+#[cfg(feature = "selftest-motors")]
+async fn pulse_motors(motors: &mut MotorController<'_>) {
+    use embassy_time::Timer;
+
+    use crate::motors::{Direction, Motor};
+
+    /// Duty used for the pulses: enough to twitch the wheel, not to drive off
+    const PULSE_DUTY: u8 = 40;
+    const PULSE_MS: u64 = 100;
+
+    for motor in [Motor::Left, Motor::Right] {
+        for direction in [Direction::Forward, Direction::Reverse] {
+            motors.set_motor(motor, direction, PULSE_DUTY);
+            Timer::after_millis(PULSE_MS).await;
+            motors.stop_all();
+            Timer::after_millis(PULSE_MS / 2).await;
+        }
+    }
+}
@@ -14,31 +14,101 @@
 //! - Line 6: PC0 (ADC1_IN10)
 //! - Line 7: PC3 (ADC1_IN13)
 //! - Line 8: PC2 (ADC1_IN12)
+//!
+//! Other arrays (up to 16 channels) can be built with
+//! `LineSensors::from_channels`; `N` defaults to the 8-channel layout above.
+//! With the `adc-mux` feature, `LineSensors::from_mux` replaces the direct
+//! wiring and reads the array through CD74HC4067 analog multiplexers sharing
+//! four select lines, so e.g. 16 sensors fit on two ADC pins.
+//!
+//! Only 8 logical channels (`SENSOR_COUNT`) can drive the robot: the state
+//! machine and the Bluetooth protocol (raw readings, calibration, thresholds,
+//! diagnostics) are fixed at 8. A wider array reads, calibrates and computes
+//! its position through `CalibratedArray<_, N>`, but `RobotState` can't run
+//! it. The firmware therefore wires one multiplexer for the 8 channels:
+//! - SIG: PA0 (ADC1_IN0)
+//! - S0-S3: PB12, PB13, PB14, PB15
+//! - Line 1-8: mux inputs C0-C7
+//!
+//! Battery voltage (optional, through a resistor divider):
+//! - PB1 (ADC1_IN9)
+//!
+//! Obstacle IR distance sensor (optional, `obstacle-sensor` feature):
+//! - PC4 (ADC1_IN14)
+//!
+//! Motor current sense (optional, `stall-guard` feature):
+//! - Left: PA6 (ADC1_IN6)
+//! - Right: PA7 (ADC1_IN7)
+
+use embassy_futures::yield_now;
+#[cfg(feature = "adc-mux")]
+use embassy_time::Duration;
+use embassy_stm32::adc::{Adc, AnyAdcChannel};
+#[cfg(not(feature = "adc-mux"))]
+use embassy_stm32::adc::AdcChannel;
+#[cfg(feature = "adc-mux")]
+use embassy_stm32::gpio::Output;
+use heapless::Vec;
+pub use embassy_stm32::adc::SampleTime;
 
-use embassy_stm32::adc::Adc;
-use embassy_stm32::peripherals::{ADC1, PA0, PA1, PA4, PB0, PC0, PC1, PC2, PC3};
+// The pure math, the calibration over it and the types they work on live in
+// the hardware-free library
+pub use liru_core::array::*;
+pub use liru_core::line::*;
+use embassy_stm32::peripherals::{ADC1, PB1};
+#[cfg(not(feature = "adc-mux"))]
+use embassy_stm32::peripherals::{PA0, PA1, PA4, PB0, PC0, PC1, PC2, PC3};
+#[cfg(feature = "obstacle-sensor")]
+use embassy_stm32::peripherals::PC4;
+#[cfg(feature = "stall-guard")]
+use embassy_stm32::peripherals::{PA6, PA7};
 
-/// Number of sensors in the array.
-pub const SENSOR_COUNT: usize = 8;
+/// Time for a multiplexed channel to settle after the mux address changes,
+/// before the ADC samples it.
+#[cfg(feature = "adc-mux")]
+const MUX_SETTLE_US: u64 = 10;
 
-/// Raw ADC readings (0-4095).
-pub type SensorReadings = [u16; SENSOR_COUNT];
+/// ADC reference voltage in millivolts.
+const ADC_VREF_MV: u32 = 3300;
 
-/// HY-S301 Line sensor array controller using ADC.
-pub struct LineSensors<'d> {
+/// Battery divider ratio (2:1 divider -> pin sees half the pack voltage).
+const BATTERY_DIVIDER_RATIO: u32 = 2;
+
+/// Raw readings below this are treated as "divider not wired".
+const BATTERY_MIN_RAW: u16 = 100;
+
+/// Physical left-to-right order of the HY-S301 channels on this PCB:
+/// physical position `j` is read from ADC channel `REMAP[j]` (indices into
+/// the `LineSensors::new` pin order PA0, PA1, PA4, PB0, PC1, PC0, PC3, PC2).
+/// Other board revisions change this table to match their routing.
+#[cfg(not(feature = "adc-mux"))]
+pub const REMAP: [usize; SENSOR_COUNT] = [0, 1, 2, 3, 4, 5, 6, 7];
+
+/// Line sensor array controller using ADC.
+///
+/// `N` is the number of channels (up to 16, so the binary pattern fits a
+/// `u16`). Readings come out in physical left-to-right order (index 0 =
+/// leftmost sensor) after applying the remap table.
+pub struct LineSensors<'d, const N: usize = SENSOR_COUNT> {
     adc: Adc<'d, ADC1>,
-    pin_l1: PA0,
-    pin_l2: PA1,
-    pin_l3: PA4,
-    pin_l4: PB0,
-    pin_l5: PC1,
-    pin_l6: PC0,
-    pin_l7: PC3,
-    pin_l8: PC2,
+    /// ADC inputs: one per channel, or one per multiplexer with `mux`
+    channels: Vec<AnyAdcChannel<ADC1>, 16>,
+    /// Physical position -> logical channel
+    remap: [usize; N],
+    /// Multiplexers in front of `channels`
+    #[cfg(feature = "adc-mux")]
+    mux: Option<AdcMux<'d>>,
+    battery_pin: Option<PB1>,
+    #[cfg(feature = "obstacle-sensor")]
+    obstacle_pin: Option<PC4>,
+    #[cfg(feature = "stall-guard")]
+    current_pins: Option<(PA6, PA7)>,
 }
 
+#[cfg(not(feature = "adc-mux"))]
 impl<'d> LineSensors<'d> {
-    /// Create a new line sensor array with ADC.
+    /// Create the 8-channel HY-S301 array with its default wiring,
+    /// remapped to physical order with `REMAP`.
     pub fn new(
         adc: Adc<'d, ADC1>,
         pa0: PA0,
@@ -50,167 +120,214 @@ impl<'d> LineSensors<'d> {
         pc3: PC3,
         pc2: PC2,
     ) -> Self {
+        Self::from_channels(adc, [
+            pa0.degrade_adc(),
+            pa1.degrade_adc(),
+            pa4.degrade_adc(),
+            pb0.degrade_adc(),
+            pc1.degrade_adc(),
+            pc0.degrade_adc(),
+            pc3.degrade_adc(),
+            pc2.degrade_adc(),
+        ]).with_remap(REMAP)
+    }
+}
+
+impl<'d, const N: usize> LineSensors<'d, N> {
+    /// Create an array from arbitrary ADC channels, in physical order.
+    #[cfg(not(feature = "adc-mux"))]
+    pub fn from_channels(adc: Adc<'d, ADC1>, channels: [AnyAdcChannel<ADC1>; N]) -> Self {
+        const { assert!(N > 0 && N <= 16, "binary pattern is a u16 bitmask") };
         Self {
             adc,
-            pin_l1: pa0,
-            pin_l2: pa1,
-            pin_l3: pa4,
-            pin_l4: pb0,
-            pin_l5: pc1,
-            pin_l6: pc0,
-            pin_l7: pc3,
-            pin_l8: pc2,
+            channels: channels.into_iter().collect(),
+            remap: core::array::from_fn(|i| i),
+            #[cfg(feature = "adc-mux")]
+            mux: None,
+            battery_pin: None,
+            #[cfg(feature = "obstacle-sensor")]
+            obstacle_pin: None,
+            #[cfg(feature = "stall-guard")]
+            current_pins: None,
         }
     }
 
-    /// Read all 8 sensors and return raw ADC values (0-4095).
-    pub fn read_all(&mut self) -> SensorReadings {
-        [
-            self.adc.blocking_read(&mut self.pin_l1),
-            self.adc.blocking_read(&mut self.pin_l2),
-            self.adc.blocking_read(&mut self.pin_l3),
-            self.adc.blocking_read(&mut self.pin_l4),
-            self.adc.blocking_read(&mut self.pin_l5),
-            self.adc.blocking_read(&mut self.pin_l6),
-            self.adc.blocking_read(&mut self.pin_l7),
-            self.adc.blocking_read(&mut self.pin_l8),
-        ]
-    }
-
-    /// Read sensors and convert to binary using a threshold.
-    /// Returns u8 where bit 0 = sensor 1, bit 7 = sensor 8.
-    /// 1 = above threshold (line detected), 0 = below threshold.
-    pub fn read_binary(&mut self, threshold: u16) -> u8 {
-        let readings = self.read_all();
-        let mut result: u8 = 0;
-
-        for (i, &value) in readings.iter().enumerate() {
-            if value > threshold {
-                result |= 1 << i;
-            }
+    /// Create an array read through CD74HC4067 multiplexers, one per ADC
+    /// input, all addressed by the same `select` lines (S0-S3). Each
+    /// multiplexer serves `N / P` consecutive channels, in physical order:
+    /// channel `k` is mux input `k % (N / P)` on `inputs[k / (N / P)]`. Two
+    /// muxes on two pins give 16 channels (S3 unused, as each only needs 8
+    /// inputs), though only 8 can run the robot (see the module docs).
+    #[cfg(feature = "adc-mux")]
+    pub fn from_mux<const P: usize>(
+        adc: Adc<'d, ADC1>,
+        select: [Output<'d>; 4],
+        inputs: [AnyAdcChannel<ADC1>; P],
+    ) -> Self {
+        const {
+            assert!(N > 0 && N <= 16, "binary pattern is a u16 bitmask");
+            assert!(P > 0 && N.is_multiple_of(P), "every multiplexer serves the same number of channels");
+        };
+        Self {
+            adc,
+            channels: inputs.into_iter().collect(),
+            remap: core::array::from_fn(|i| i),
+            mux: Some(AdcMux { select, ways: N / P }),
+            battery_pin: None,
+            #[cfg(feature = "obstacle-sensor")]
+            obstacle_pin: None,
+            #[cfg(feature = "stall-guard")]
+            current_pins: None,
         }
+    }
 
-        result
+    /// Read physical position `j` from logical channel `remap[j]`.
+    /// Panics if `remap` is not a permutation of `0..N`.
+    #[cfg(not(feature = "adc-mux"))]
+    pub fn with_remap(mut self, remap: [usize; N]) -> Self {
+        let mut seen = [false; N];
+        for &index in &remap {
+            assert!(index < N && !seen[index], "sensor remap must be a permutation");
+            seen[index] = true;
+        }
+        self.remap = remap;
+        self
     }
 
-    /// Get the average reading across all sensors.
-    pub fn read_average(&mut self) -> u16 {
-        let readings = self.read_all();
-        let sum: u32 = readings.iter().map(|&v| v as u32).sum();
-        (sum / SENSOR_COUNT as u32) as u16
+    /// Set how long the ADC samples each channel before converting.
+    ///
+    /// The default `CYCLES3` is the fastest (about 2us per channel at the
+    /// default 8MHz ADC clock) but only settles from a low-impedance source.
+    /// High-impedance sensor outputs or dividers read low until the sample
+    /// capacitor has time to charge: try `CYCLES56`-`CYCLES144`, or
+    /// `CYCLES480` (about 60us per channel, 0.5ms per 8-channel sweep) if a
+    /// channel's readings still depend on the one read before it. Applies to
+    /// every channel, battery and obstacle sensor included.
+    pub fn with_sample_time(mut self, sample_time: SampleTime) -> Self {
+        self.adc.set_sample_time(sample_time);
+        self
     }
-}
 
-/// Line sensor controller with calibration support
-pub struct CalibratedSensors<'d> {
-    sensors: LineSensors<'d>,
-    min_readings: SensorReadings,
-    max_readings: SensorReadings,
-    thresholds: SensorReadings,
-    calibrated: bool,
-}
+    /// Attach the battery voltage divider on PB1.
+    pub fn with_battery(mut self, pb1: PB1) -> Self {
+        self.battery_pin = Some(pb1);
+        self
+    }
 
-impl<'d> CalibratedSensors<'d> {
-    pub fn new(sensors: LineSensors<'d>) -> Self {
-        Self {
-            sensors,
-            min_readings: [4095; SENSOR_COUNT],
-            max_readings: [0; SENSOR_COUNT],
-            thresholds: [2000; SENSOR_COUNT], // Default safe value
-            calibrated: false,
+    /// Read battery voltage in millivolts.
+    /// Returns 0 if no divider is attached or the channel reads as unwired.
+    pub fn read_battery_mv(&mut self) -> u16 {
+        let Some(pin) = self.battery_pin.as_mut() else {
+            return 0;
+        };
+        let raw = self.adc.blocking_read(pin);
+        if raw < BATTERY_MIN_RAW {
+            return 0;
         }
+        (raw as u32 * ADC_VREF_MV * BATTERY_DIVIDER_RATIO / 4095) as u16
     }
 
-    pub fn read_all(&mut self) -> SensorReadings {
-        self.sensors.read_all()
+    /// Attach the obstacle IR distance sensor on PC4.
+    #[cfg(feature = "obstacle-sensor")]
+    pub fn with_obstacle(mut self, pc4: PC4) -> Self {
+        self.obstacle_pin = Some(pc4);
+        self
     }
 
-    pub fn reset_calibration(&mut self) {
-        self.min_readings = [4095; SENSOR_COUNT];
-        self.max_readings = [0; SENSOR_COUNT];
-        self.calibrated = false;
-        defmt::info!("Calibration reset");
+    /// Raw obstacle sensor reading (higher = closer), 0 if none is attached.
+    #[cfg(feature = "obstacle-sensor")]
+    pub fn read_obstacle_raw(&mut self) -> u16 {
+        match self.obstacle_pin.as_mut() {
+            Some(pin) => self.adc.blocking_read(pin),
+            None => 0,
+        }
     }
 
-    pub fn update_calibration(&mut self) {
-        let readings = self.sensors.read_all();
-        for (i, &val) in readings.iter().enumerate() {
-            if val < self.min_readings[i] {
-                self.min_readings[i] = val;
-            }
-            if val > self.max_readings[i] {
-                self.max_readings[i] = val;
-            }
-        }
+    /// Attach the motor current-sense resistors on PA6 (left) and PA7 (right).
+    #[cfg(feature = "stall-guard")]
+    pub fn with_current_sense(mut self, pa6: PA6, pa7: PA7) -> Self {
+        self.current_pins = Some((pa6, pa7));
+        self
     }
 
-    pub fn finalize_calibration(&mut self) {
-        defmt::info!("Calibration min: {:?}", self.min_readings);
-        defmt::info!("Calibration max: {:?}", self.max_readings);
-        
-        for i in 0..SENSOR_COUNT {
-            // Threshold is midpoint between min and max
-            // Add some hysteresis margin (40% from min towards max)
-            let range = self.max_readings[i].saturating_sub(self.min_readings[i]);
-            self.thresholds[i] = self.min_readings[i] + (range * 40 / 100);
+    /// Raw motor current readings (left, right), 0 if not attached.
+    #[cfg(feature = "stall-guard")]
+    pub fn read_motor_current_raw(&mut self) -> [u16; 2] {
+        match self.current_pins.as_mut() {
+            Some((left, right)) => [self.adc.blocking_read(left), self.adc.blocking_read(right)],
+            None => [0; 2],
         }
-        
-        defmt::info!("Calibration thresholds: {:?}", self.thresholds);
-        self.calibrated = true;
-    }
-
-    /// Read binary using calibrated thresholds
-    pub fn read_binary(&mut self) -> u8 {
-        let readings = self.sensors.read_all();
-        let mut result: u8 = 0;
-
-        for (i, &value) in readings.iter().enumerate() {
-             // For these sensors (black line on white background):
-             // High value = Black (Line), Low value = White (Background)
-             // So if value > threshold, it's a line.
-            if value > self.thresholds[i] {
-                result |= 1 << i;
-            }
+    }
+
+    /// Read all sensors and return raw ADC values (0-4095),
+    /// in physical left-to-right order.
+    ///
+    /// The F4 ADC driver only offers polled single conversions (its DMA mode
+    /// is free-running), so this yields to the executor after every channel:
+    /// a full sweep no longer blocks the Bluetooth task for all 8 conversions.
+    pub async fn read_all(&mut self) -> SensorReadings<N> {
+        let mut readings = [0; N];
+        for (reading, index) in readings.iter_mut().zip(self.remap) {
+            *reading = self.read_channel(index);
+            yield_now().await;
         }
-        result
-    }
-
-    /// Calculate weighted line position using calibrated values.
-    /// Returns (position, intensity)
-    /// position: -3500 (Right/Index0) to 3500 (Left/Index7), 0 is Center.
-    /// intensity: Sum of calibrated sensor values (0-8000), useful for line loss detection.
-    pub fn read_line_position(&mut self) -> (i32, u32) {
-        let readings = self.sensors.read_all();
-        let mut weighted_sum: i32 = 0;
-        let mut total_intensity: u32 = 0;
-
-        for (i, &raw_val) in readings.iter().enumerate() {
-            let min = self.min_readings[i];
-            let max = self.max_readings[i];
-            
-            // Normalize raw_val to 0-1000
-            let val = if raw_val <= min {
-                0
-            } else if raw_val >= max {
-                1000
-            } else {
-                let range = max - min;
-                if range == 0 { 0 } else { ((raw_val - min) as u32 * 1000 / range as u32) as u32 }
-            };
+        readings
+    }
 
-            total_intensity += val;
-            weighted_sum += val as i32 * (i as i32 * 1000);
+    /// Convert logical channel `index`, addressing its multiplexer first.
+    fn read_channel(&mut self, index: usize) -> u16 {
+        #[cfg(feature = "adc-mux")]
+        if let Some(mux) = self.mux.as_mut() {
+            mux.select(index % mux.ways);
+            return self.adc.blocking_read(&mut self.channels[index / mux.ways]);
         }
+        self.adc.blocking_read(&mut self.channels[index])
+    }
+}
 
-        if total_intensity < 500 {
-             // Line lost (roughly < 0.5 sensor active)
-             return (0, 0); 
+/// CD74HC4067 select lines shared by every multiplexer of an array
+#[cfg(feature = "adc-mux")]
+struct AdcMux<'d> {
+    /// S0-S3, least significant first
+    select: [Output<'d>; 4],
+    /// Channels served by each multiplexer
+    ways: usize,
+}
+
+#[cfg(feature = "adc-mux")]
+impl AdcMux<'_> {
+    /// Route mux input `address` to the ADC and wait for it to settle.
+    fn select(&mut self, address: usize) {
+        for (bit, pin) in self.select.iter_mut().enumerate() {
+            if address & (1 << bit) != 0 {
+                pin.set_high();
+            } else {
+                pin.set_low();
+            }
         }
+        embassy_time::block_for(Duration::from_micros(MUX_SETTLE_US));
+    }
+}
+
+impl<const N: usize> RawSensorSource<N> for LineSensors<'_, N> {
+    async fn read_raw(&mut self) -> SensorReadings<N> {
+        self.read_all().await
+    }
+
+    fn read_battery_mv(&mut self) -> u16 {
+        LineSensors::read_battery_mv(self)
+    }
+
+    #[cfg(feature = "obstacle-sensor")]
+    fn read_obstacle_raw(&mut self) -> u16 {
+        LineSensors::read_obstacle_raw(self)
+    }
 
-        let position = weighted_sum / total_intensity as i32;
-        // Shift to be centered around 0
-        // Range 0..7000 -> -3500..3500
-        (position - 3500, total_intensity)
+    #[cfg(feature = "stall-guard")]
+    fn read_motor_current_raw(&mut self) -> [u16; 2] {
+        LineSensors::read_motor_current_raw(self)
     }
 }
 
+/// Calibrated line sensing on the robot's own ADC array
+pub type CalibratedSensors<'d, const N: usize = SENSOR_COUNT> = CalibratedArray<LineSensors<'d, N>, N>;
@@ -0,0 +1,74 @@
+//! Motor stall protection from measured current for LiRu robot.
+//!
+//! Optional (`stall-guard` feature): current-sense resistors on the L298N
+//! SENSA/SENSB pins feed PA6 (ADC1_IN6, left) and PA7 (ADC1_IN7, right),
+//! read through `LineSensors::with_current_sense`. A motor drawing more than
+//! the threshold for a while is stalled (e.g. pushing against a wall): its
+//! duty is cut for a cooldown, then it is allowed to try again.
+
+use embassy_time::{Duration, Instant};
+
+/// Default stall threshold (raw ADC counts), about 1V: 2A through the
+/// L298N's 0.5 ohm sense resistors.
+const DEFAULT_THRESHOLD: u16 = 1240;
+
+/// Over-threshold time before a motor counts as stalled, so the inrush of a
+/// normal start doesn't trip it.
+const STALL_HOLD: Duration = Duration::from_millis(150);
+
+/// How long a stalled motor stays cut before it may drive again.
+const STALL_COOLDOWN: Duration = Duration::from_millis(1000);
+
+/// Per-motor (left, right) stall detector
+pub struct StallGuard {
+    /// Raw reading at which a motor is over-current (0 = disabled)
+    threshold: u16,
+    /// When each motor went over the threshold, while it stays over
+    over_since: [Option<Instant>; 2],
+    /// When each cut motor may drive again
+    cut_until: [Option<Instant>; 2],
+}
+
+impl StallGuard {
+    pub const fn new(threshold: u16) -> Self {
+        Self { threshold, over_since: [None; 2], cut_until: [None; 2] }
+    }
+
+    /// Stalled motors as a bitmask (bit 0 = left, bit 1 = right).
+    pub fn mask(&self) -> u8 {
+        (self.cut_until[0].is_some() as u8) | (self.cut_until[1].is_some() as u8) << 1
+    }
+
+    /// Feed the raw current readings (left, right). Returns which motors are
+    /// stalled when that changed.
+    pub fn update(&mut self, raw: [u16; 2], now: Instant) -> Option<[bool; 2]> {
+        let before = self.mask();
+        let motors = raw.iter().zip(self.over_since.iter_mut()).zip(self.cut_until.iter_mut());
+        for ((&raw, over_since), cut_until) in motors {
+            if let Some(until) = *cut_until {
+                // Cut motors draw nothing, so only time can release them
+                if now >= until {
+                    *cut_until = None;
+                }
+                continue;
+            }
+            if self.threshold == 0 || raw < self.threshold {
+                *over_since = None;
+                continue;
+            }
+            let since = *over_since.get_or_insert(now);
+            if now - since >= STALL_HOLD {
+                *over_since = None;
+                *cut_until = Some(now + STALL_COOLDOWN);
+            }
+        }
+        let after = self.mask();
+        (after != before).then_some([after & 1 != 0, after & 2 != 0])
+    }
+}
+
+impl Default for StallGuard {
+    fn default() -> Self {
+        Self::new(DEFAULT_THRESHOLD)
+    }
+}
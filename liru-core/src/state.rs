@@ -0,0 +1,1661 @@
+//! Robot mode state machine for LiRu robot.
+//!
+//! Owns the current mode and the line follower's control state.
+//! Commands and periodic ticks are turned into `Actions` that the main loop
+//! applies to the motors and the Bluetooth link, so the mode logic does not
+//! touch hardware directly and can be driven by mock sensors: the sensor
+//! array is a `CalibratedArray` over any `RawSensorSource`.
+
+use defmt::{info, warn};
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+
+use crate::array::{CalibratedArray, RawSensorSource};
+use crate::protocol::{cmd, err, Command, Reply, RunStats, StatusFlags, TelemetryFrame};
+use crate::config::{RobotConfig, MAX_CONTROL_PERIOD_MS, MAX_STEERING_BIAS, MIN_CONTROL_PERIOD_MS};
+use crate::control::{
+    ControllerKind, FinishDetector, LineSearcher, MAX_POSITION_TAU_MS, PidController, PositionFilter,
+    JunctionFilter, LineSpeedScale, RecoveryStrategy, SearchState, SpeedModulator, TurnCalibration,
+};
+use crate::led::LedPattern;
+use crate::drive::MotorCommand;
+#[cfg(feature = "obstacle-sensor")]
+use crate::obstacle::ObstacleSensor;
+use crate::line::{
+    classify_line, LineEvent, LinePolarity, RawRange, SensorReadings, SurfaceState,
+    ThresholdOrigin, SENSOR_COUNT,
+};
+
+/// Maximum number of replies produced by a single command or tick.
+pub const MAX_REPLIES: usize = 4;
+
+/// How often running telemetry is sent, whatever the loop rate.
+const TELEMETRY_INTERVAL_MS: u64 = 200;
+
+/// How often raw ADC values are logged in Car mode.
+const ADC_LOG_INTERVAL_MS: u64 = 500;
+
+/// Control ticks a junction must be read in a row before the robot acts on
+/// it, so one noisy sweep isn't taken for a crossing.
+const JUNCTION_CONFIRM_TICKS: u8 = 2;
+
+/// Normalized reading (of 1000) below which a channel is treated as
+/// background noise when computing the running line position.
+const POSITION_FLOOR: u16 = 100;
+
+/// How long the array may see nothing but background while searching before
+/// the robot assumes it has left the mat and stops.
+const OFF_MAT_MS: u64 = 2000;
+
+/// Line confidence (0-100) below which the position is treated as unreliable
+/// (split or smeared line): hold the current steering and slow down.
+const LOW_CONFIDENCE: u8 = 40;
+
+/// Base speed cap while confidence is low.
+const LOW_CONFIDENCE_SPEED: i32 = 45;
+
+/// Arc recovery: how long to keep arcing on the last steering before
+/// falling back to the pivot search.
+const ARC_RECOVERY_MS: u64 = 400;
+
+/// Nudge: a single short tap at low speed, for placing the robot precisely.
+const NUDGE_SPEED: u8 = 40;
+const NUDGE_MS: u64 = 80;
+
+/// Pre-run centering: pivot speed, how close to zero the position must get,
+/// and how long to try before starting the run anyway.
+const CENTERING_SPEED: u8 = 35;
+const CENTERING_TOLERANCE: i32 = 150;
+const CENTERING_TIMEOUT_MS: u64 = 1500;
+
+/// Shutdown: how long the wheels take to ramp from their current speed to zero.
+const SHUTDOWN_RAMP_MS: u64 = 500;
+
+/// Arc recovery: reduced base speed; the last steering is capped to this
+/// so both wheels keep turning forward.
+const ARC_RECOVERY_SPEED: i32 = 40;
+
+/// One line follower control tick, logged as a structured defmt event with
+/// the `defmt-telemetry` feature so runs can be decoded into CSV offline.
+#[cfg(feature = "defmt-telemetry")]
+#[derive(defmt::Format)]
+struct ControlSample {
+    position: i32,
+    intensity: u32,
+    steering: i32,
+    left_speed: i8,
+    right_speed: i8,
+}
+
+/// Robot operating mode
+#[derive(Clone, Copy, PartialEq)]
+pub enum RobotMode {
+    Car,
+    LineFollowerIdle,
+    LineFollowerCalibrating(Instant),
+    LineFollowerRunning,
+}
+
+/// What the robot streams to the GUI unprompted (`cmd::STREAM`)
+#[derive(Clone, Copy, PartialEq, Debug, defmt::Format)]
+pub enum StreamKind {
+    /// Binary sensor pattern, as the `GET_SENSORS` reply
+    Binary,
+    /// Raw readings, as the `GET_RAW_SENSORS` reply
+    Raw,
+    /// Analog line follower telemetry frames
+    Telemetry,
+}
+
+impl StreamKind {
+    /// Wire value used by `cmd::STREAM` (1=binary, 2=raw, 3=telemetry);
+    /// 0 (none) is handled by the caller
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            1 => Some(Self::Binary),
+            2 => Some(Self::Raw),
+            3 => Some(Self::Telemetry),
+            _ => None,
+        }
+    }
+}
+
+/// Side effects requested by the state machine
+#[derive(Default)]
+pub struct Actions {
+    /// New motor output, if it should change
+    pub motors: Option<MotorCommand>,
+    /// Messages to send to the GUI, in order
+    pub replies: Vec<Reply, MAX_REPLIES>,
+    /// Report the speeds the motors actually got (`Reply::MotorOutput`)
+    /// once `motors` is applied
+    pub echo_motors: bool,
+    /// A calibration just finished: save it so it survives a reboot
+    pub calibrated: bool,
+}
+
+impl Actions {
+    fn motors(command: MotorCommand) -> Self {
+        Self { motors: Some(command), ..Self::default() }
+    }
+
+    fn reply(reply: Reply) -> Self {
+        let mut actions = Self::default();
+        actions.push(reply);
+        actions
+    }
+
+    /// Acknowledge a state-changing command that has no dedicated reply
+    fn acked(mut self, command: u8) -> Self {
+        self.push(Reply::Ack(command));
+        self
+    }
+
+    fn push(&mut self, reply: Reply) {
+        // Replies beyond capacity are dropped; telemetry is best effort
+        let _ = self.replies.push(reply);
+    }
+}
+
+/// Robot state: current mode plus line follower control state
+pub struct RobotState {
+    mode: RobotMode,
+    /// Tunables (settable over Bluetooth one at a time or all at once).
+    /// The gains, search speeds and filter constant are mirrored into the
+    /// controllers below; the control loop period is only a target, the
+    /// PID still uses the measured time between ticks
+    config: RobotConfig,
+    /// Line follower steering controller
+    pid: PidController,
+    /// Base speed from recent path straightness
+    speed_modulator: SpeedModulator,
+    /// Low-pass on the position fed to the PID (off by default)
+    position_filter: PositionFilter,
+    last_control_at: Instant,
+    /// Line follower: remember last direction (0=forward, -1=left, 1=right)
+    last_direction: i8,
+    /// Widening sweep search used while the line is lost
+    searcher: LineSearcher,
+    /// When the line was lost, while recovering
+    lost_since: Option<Instant>,
+    /// What the last running tick did about a lost line (None = on the line)
+    search_state: Option<SearchState>,
+    /// Start of the pre-run centering pivot, while it lasts
+    centering_since: Option<Instant>,
+    /// Sustained all-sensors-active detection for the finish bar
+    finish: FinishDetector,
+    /// Debounces crossings and branches
+    junctions: JunctionFilter,
+    /// Confirmed junction under the array (None = ordinary line)
+    junction: Option<LineEvent>,
+    /// Accumulated over the current run, sent when it ends
+    run_stats: RunStats,
+    /// Raw ADC extremes seen while following, kept for `GetRunRange` until
+    /// the next run starts
+    run_range: RawRange,
+    /// When the array started seeing only background during a search
+    white_since: Option<Instant>,
+    /// Car mode: when the current timed maneuver (`DriveTimed`, `Turn`,
+    /// `TurnCal`) ends, and the command to acknowledge then
+    timed_drive_until: Option<(Instant, u8)>,
+    /// Car mode: when the last manual drive command (MOTOR, DRIVE, WASD)
+    /// arrived, while the motors still run from it
+    car_drive_at: Option<Instant>,
+    /// Car mode: pivot timing for dead-reckoned turns
+    turn_calibration: TurnCalibration,
+    /// Car mode / Line Follower Idle: when the current nudge ends
+    nudge_until: Option<Instant>,
+    /// Last whole second of calibration reported to the GUI
+    calibration_progress_s: u8,
+    /// Line lost or calibration failed; shown on the LED until the mode changes again
+    fault: bool,
+    /// Emergency stop latched: motors forced off until explicitly cleared
+    estop: bool,
+    /// Battery below the cutoff: motors forced off like the E-stop, until
+    /// the voltage recovers
+    battery_cutoff: bool,
+    /// Line Follower Running, paused: motors stopped, controller and
+    /// calibration kept for `Resume`
+    paused: bool,
+    /// Line follower: follow with this fixed raw threshold instead of the
+    /// calibrated position, skipping calibration on Start (None = calibrated)
+    fixed_threshold: Option<u16>,
+    /// The sensors hold a calibration restored from flash: the next Start
+    /// runs on it instead of calibrating
+    saved_calibration: bool,
+    /// Answer MOTOR with the applied speeds instead of a plain ACK
+    motor_echo: bool,
+    /// Shutting down: when the ramp to zero started, and the wheel speeds
+    /// it ramps down from
+    parking: Option<(Instant, [i8; 2])>,
+    /// Shut down: motors off and only PING answered until the next power-up
+    parked: bool,
+    /// Line follower halts while this sees something close ahead
+    #[cfg(feature = "obstacle-sensor")]
+    obstacle: ObstacleSensor,
+
+    // Analog telemetry tracking
+    last_weighted_pos: i32,
+    last_intensity: u32,
+    last_steering: i32,
+    last_left_speed: i8,
+    last_right_speed: i8,
+    last_confidence: u8,
+    /// Binary pattern behind the last control tick's reading
+    last_binary: u16,
+    /// A wheel hit its clamp on the last running tick
+    last_saturated: bool,
+    /// The steering limit cut the correction on the last running tick
+    last_steering_limited: bool,
+
+    /// Last running telemetry frame and Car mode ADC log
+    last_telemetry_at: Instant,
+    last_adc_log_at: Instant,
+    /// Streamed telemetry and its period in ms, if the GUI subscribed
+    stream: Option<(StreamKind, u64)>,
+    /// When the next streamed reply is due
+    next_stream_at: Instant,
+}
+
+impl RobotState {
+    pub fn new(now: Instant, config: RobotConfig) -> Self {
+        let mut pid = PidController::new(config.kp_left, config.ki, config.kd);
+        pid.set_side_gains(config.kp_left, config.kp_right);
+        pid.set_kind(config.controller);
+        let mut searcher = LineSearcher::default();
+        searcher.set_speeds(config.search_turn, config.search_forward);
+        Self {
+            mode: RobotMode::Car,
+            config,
+            pid,
+            speed_modulator: SpeedModulator::default(),
+            position_filter: PositionFilter::new(config.position_tau_ms as u32),
+            last_control_at: now,
+            last_direction: 0,
+            searcher,
+            lost_since: None,
+            search_state: None,
+            centering_since: None,
+            finish: FinishDetector::new(config.finish_sustain_ds as u64 * 100),
+            junctions: JunctionFilter::new(JUNCTION_CONFIRM_TICKS),
+            junction: None,
+            run_stats: RunStats::default(),
+            run_range: RawRange::default(),
+            white_since: None,
+            calibration_progress_s: 0,
+            timed_drive_until: None,
+            car_drive_at: None,
+            turn_calibration: TurnCalibration::default(),
+            nudge_until: None,
+            fault: false,
+            paused: false,
+            fixed_threshold: None,
+            saved_calibration: false,
+            motor_echo: false,
+            parking: None,
+            parked: false,
+            estop: false,
+            battery_cutoff: false,
+            #[cfg(feature = "obstacle-sensor")]
+            obstacle: ObstacleSensor::default(),
+            last_weighted_pos: 0,
+            last_intensity: 0,
+            last_steering: 0,
+            last_left_speed: 0,
+            last_right_speed: 0,
+            last_confidence: 0,
+            last_binary: 0,
+            last_saturated: false,
+            last_steering_limited: false,
+            last_telemetry_at: now,
+            last_adc_log_at: now,
+            stream: None,
+            next_stream_at: now,
+        }
+    }
+
+    /// Live tunables.
+    pub fn config(&self) -> RobotConfig {
+        self.config
+    }
+
+    /// Replace every tunable at once, pushing them into the controllers.
+    fn apply_config(&mut self, config: RobotConfig) {
+        self.config = config;
+        self.pid.set_gains(config.kp_left, config.ki, config.kd);
+        self.pid.set_side_gains(config.kp_left, config.kp_right);
+        self.pid.set_kind(config.controller);
+        self.searcher.set_speeds(config.search_turn, config.search_forward);
+        self.position_filter.set_tau(config.position_tau_ms as u32);
+        self.finish.set_sustain(config.finish_sustain_ds as u64 * 100);
+    }
+
+    /// Control loop period the control task should run at.
+    pub fn control_period_ms(&self) -> u64 {
+        self.config.control_period_ms as u64
+    }
+
+    /// Mode as reported in `msg::DEBUG` (0=Car, 1=Idle, 2=Calibrating, 3=Running)
+    pub fn mode_byte(&self) -> u8 {
+        match self.mode {
+            RobotMode::Car => 0,
+            RobotMode::LineFollowerIdle => 1,
+            RobotMode::LineFollowerCalibrating(_) => 2,
+            RobotMode::LineFollowerRunning => 3,
+        }
+    }
+
+    /// Binary sensor pattern behind the last control tick's reading (0
+    /// before the first), for reports that shouldn't sweep the array again.
+    pub fn last_binary(&self) -> u16 {
+        self.last_binary
+    }
+
+    /// Mode as reported by `msg::MODE`: `mode_byte`, except 4 for a paused
+    /// run, 5 while the E-stop is latched and 6 once shutting down.
+    pub fn mode_report(&self) -> u8 {
+        if self.is_shut_down() {
+            6
+        } else if self.estop {
+            5
+        } else if self.paused {
+            4
+        } else {
+            self.mode_byte()
+        }
+    }
+
+    /// Status heartbeat for the GUI; the caller fills in what it owns
+    /// (link, calibration, battery).
+    pub fn status(&self, connected: bool, calibrated: bool, battery_low: bool) -> StatusFlags {
+        StatusFlags {
+            mode: self.mode_byte(),
+            connected,
+            estop: self.estop,
+            calibrated,
+            line_lost: self.mode == RobotMode::LineFollowerRunning && self.lost_since.is_some(),
+            battery_low,
+            paused: self.paused,
+            stalled: 0,
+        }
+    }
+
+    /// Whether a shutdown started: the motors are ramping down or off for good.
+    pub fn is_shut_down(&self) -> bool {
+        self.parked || self.parking.is_some()
+    }
+
+    /// The sensors were given a calibration saved before the last reboot:
+    /// the first Start runs on it, later ones calibrate as usual.
+    pub fn use_saved_calibration(&mut self) {
+        self.saved_calibration = true;
+    }
+
+    /// Whether the shutdown ramp is over and the robot is parked.
+    pub fn is_parked(&self) -> bool {
+        self.parked
+    }
+
+    /// Start an orderly shutdown from the wheels' current `speeds`: leave
+    /// any run (reporting its stats) and ramp the motors down over
+    /// `SHUTDOWN_RAMP_MS` instead of stopping dead, then park. Acknowledged
+    /// once parked.
+    pub fn shutdown(&mut self, speeds: [i8; 2], now: Instant) -> Actions {
+        if self.is_shut_down() {
+            return Actions::default();
+        }
+        info!("Shutting down");
+        let mut actions = self.enter_mode(RobotMode::Car);
+        actions.motors = None;
+        self.parking = Some((now, speeds));
+        actions
+    }
+
+    /// LED pattern for the current mode.
+    pub fn led_pattern(&self) -> LedPattern {
+        match self.mode {
+            _ if self.is_shut_down() => LedPattern::Parked,
+            _ if self.estop => LedPattern::EStop,
+            _ if self.fault => LedPattern::Sos,
+            RobotMode::Car => LedPattern::Heartbeat,
+            RobotMode::LineFollowerIdle => LedPattern::SlowBlink,
+            RobotMode::LineFollowerCalibrating(_) => LedPattern::FastBlink,
+            RobotMode::LineFollowerRunning if self.paused => LedPattern::Paused,
+            RobotMode::LineFollowerRunning => LedPattern::DoubleBlink,
+        }
+    }
+
+    /// Apply a command received from the GUI.
+    pub async fn handle_command<S: RawSensorSource<SENSOR_COUNT>>(&mut self, cmd: Command, sensors: &mut CalibratedArray<S>, now: Instant) -> Actions {
+        // Shut down: only show that the robot is still alive
+        if self.is_shut_down() {
+            return match cmd {
+                Command::Ping => Actions::reply(Reply::Pong),
+                _ => Actions::default(),
+            };
+        }
+
+        // A latched E-stop or a flat battery rejects anything that would
+        // move the robot
+        let moves = matches!(
+            cmd,
+            Command::Motor { .. }
+                | Command::Drive { .. }
+                | Command::DriveTimed { .. }
+                | Command::Turn(_)
+                | Command::TurnCal { .. }
+                | Command::Nudge(_)
+                | Command::Resume
+                | Command::Start
+                | Command::CalWhite
+                | Command::CalBlack
+        );
+        if moves && self.estop {
+            return Actions::reply(Reply::Error(err::ESTOP_LATCHED));
+        }
+        if moves && self.battery_cutoff {
+            return Actions::reply(Reply::Error(err::BATTERY_CUTOFF));
+        }
+
+        match cmd {
+            // Only Car mode takes direct motor output; a stray joystick must not
+            // fight the line follower
+            Command::Motor { left, right } if self.mode == RobotMode::Car => {
+                self.timed_drive_until = None;
+                self.nudge_until = None;
+                self.car_drive_at = Some(now);
+                let actions = Actions::motors(MotorCommand::Tank { left, right });
+                if self.motor_echo {
+                    Actions { echo_motors: true, ..actions }
+                } else {
+                    actions.acked(cmd::MOTOR)
+                }
+            }
+            Command::Drive { linear, angular } if self.mode == RobotMode::Car => {
+                self.timed_drive_until = None;
+                self.nudge_until = None;
+                self.car_drive_at = Some(now);
+                Actions::motors(MotorCommand::Drive { linear, angular }).acked(cmd::DRIVE)
+            }
+            // Acked once the run is over (see `tick`)
+            Command::DriveTimed { speed, ds } if self.mode == RobotMode::Car => {
+                info!("Timed drive: {}% for {}ms", speed, ds as u64 * 10);
+                self.timed_drive_until = Some((now + Duration::from_millis(ds as u64 * 10), cmd::DRIVE_TIMED));
+                self.car_drive_at = None;
+                self.nudge_until = None;
+                Actions::motors(MotorCommand::Forward(speed))
+            }
+            // Acked once the pivot is over, like `DriveTimed`
+            Command::Turn(degrees) if self.mode == RobotMode::Car => {
+                self.nudge_until = None;
+                self.car_drive_at = None;
+                match self.turn_calibration.dead_reckon_turn(degrees) {
+                    Some((command, ms)) => {
+                        info!("Turn: {} degrees in {}ms", degrees, ms);
+                        self.timed_drive_until = Some((now + Duration::from_millis(ms as u64), cmd::TURN));
+                        Actions::motors(command)
+                    }
+                    None => {
+                        self.timed_drive_until = None;
+                        Actions::motors(MotorCommand::Stop).acked(cmd::TURN)
+                    }
+                }
+            }
+            // Store the timing, then pivot 90 degrees right with it so the
+            // result can be checked and the timing adjusted
+            Command::TurnCal { ms_per_90, speed } if self.mode == RobotMode::Car => {
+                if !self.turn_calibration.set(ms_per_90, speed) {
+                    return Actions::reply(Reply::Error(err::INVALID_PARAM));
+                }
+                info!("Turn calibration: {}ms per 90 degrees at {}%", ms_per_90, speed);
+                self.nudge_until = None;
+                self.car_drive_at = None;
+                self.timed_drive_until = Some((now + Duration::from_millis(ms_per_90 as u64), cmd::TURN_CAL));
+                Actions::motors(MotorCommand::TurnRight(speed))
+            }
+            Command::Motor { .. }
+            | Command::Drive { .. }
+            | Command::DriveTimed { .. }
+            | Command::Turn(_)
+            | Command::TurnCal { .. } => Actions::reply(Reply::Error(err::WRONG_MODE)),
+            // Also in Line Follower Idle, for lining up on the start line;
+            // never while calibrating or running
+            Command::Nudge(direction) if matches!(self.mode, RobotMode::Car | RobotMode::LineFollowerIdle) => {
+                let command = match direction {
+                    0 => MotorCommand::Forward(NUDGE_SPEED),
+                    1 => MotorCommand::Backward(NUDGE_SPEED),
+                    2 => MotorCommand::TurnLeft(NUDGE_SPEED),
+                    3 => MotorCommand::TurnRight(NUDGE_SPEED),
+                    _ => return Actions::reply(Reply::Error(err::INVALID_PARAM)),
+                };
+                self.timed_drive_until = None;
+                self.car_drive_at = None;
+                self.nudge_until = Some(now + Duration::from_millis(NUDGE_MS));
+                Actions::motors(command).acked(cmd::NUDGE)
+            }
+            Command::Nudge(_) => Actions::reply(Reply::Error(err::WRONG_MODE)),
+            Command::Stop => {
+                // If in Line Follower mode, reset to Idle so user can recalibrate
+                self.leave_autonomous().acked(cmd::STOP)
+            }
+            // Stop where we are, keeping the run going for `Resume`
+            Command::Pause if self.mode == RobotMode::LineFollowerRunning => {
+                info!("Run paused");
+                self.paused = true;
+                self.last_left_speed = 0;
+                self.last_right_speed = 0;
+                Actions::motors(MotorCommand::Brake).acked(cmd::PAUSE)
+            }
+            // Time the next control step from now, so the pause doesn't
+            // count as one long tick; a search interrupted by the pause
+            // starts over with a fresh timeout
+            Command::Resume if self.paused => {
+                info!("Run resumed");
+                self.paused = false;
+                self.last_control_at = now;
+                self.searcher.reset();
+                self.lost_since = None;
+                self.white_since = None;
+                Actions::default().acked(cmd::RESUME)
+            }
+            Command::Pause | Command::Resume => Actions::reply(Reply::Error(err::WRONG_MODE)),
+            Command::SetMode(m) => {
+                self.fault = false;
+                let mode = if m == 1 {
+                    info!("Switched to Line Follower Mode (Idle)");
+                    RobotMode::LineFollowerIdle
+                } else {
+                    info!("Switched to Car Mode");
+                    RobotMode::Car
+                };
+                self.enter_mode(mode).acked(cmd::SET_MODE)
+            }
+            // From any line follower mode: a run (or a calibration already in
+            // progress) is stopped and calibration starts over
+            Command::Start => {
+                if self.mode == RobotMode::Car {
+                    return Actions::reply(Reply::Error(err::WRONG_MODE));
+                }
+                if self.fixed_threshold.is_some() {
+                    info!("Fixed threshold, running without calibration");
+                    self.fault = false;
+                    return self.start_running(now).acked(cmd::START);
+                }
+                if core::mem::take(&mut self.saved_calibration) && self.mode == RobotMode::LineFollowerIdle {
+                    info!("Running on the saved calibration");
+                    self.fault = false;
+                    return self.start_running(now).acked(cmd::START);
+                }
+                info!("Starting Calibration...");
+                sensors.reset_calibration();
+                self.fault = false;
+                self.calibration_progress_s = 0;
+                let mut actions = self.enter_mode(RobotMode::LineFollowerCalibrating(now));
+                actions.push(Reply::CalibrationStart);
+                actions.acked(cmd::START)
+            }
+            // Two-point calibration, a faster alternative to the sweep under
+            // controlled lighting; runs as soon as both points are captured
+            Command::CalWhite | Command::CalBlack => {
+                if self.mode != RobotMode::LineFollowerIdle {
+                    return Actions::reply(Reply::Error(err::WRONG_MODE));
+                }
+                let (code, result) = if let Command::CalWhite = cmd {
+                    (cmd::CAL_WHITE, sensors.calibrate_white().await)
+                } else {
+                    (cmd::CAL_BLACK, sensors.calibrate_black().await)
+                };
+                match result {
+                    None => Actions::default().acked(code),
+                    Some(result) => {
+                        info!("Two-point calibration complete! Running...");
+                        self.fault = false;
+                        let mut actions = self.start_running(now).acked(code);
+                        // Low contrast: running degraded on fallback ranges
+                        if let Err(e) = result {
+                            actions.push(Reply::LowContrast(e.low_contrast));
+                        }
+                        actions.push(Reply::CalibrationEnd);
+                        actions.calibrated = true;
+                        actions
+                    }
+                }
+            }
+            // Robot placed centered over the line: measure the bar's misalignment
+            Command::CalCenter => {
+                if self.mode != RobotMode::LineFollowerIdle {
+                    return Actions::reply(Reply::Error(err::WRONG_MODE));
+                }
+                match sensors.calibrate_center().await {
+                    Some(_) => Actions::default().acked(cmd::CAL_CENTER),
+                    None => Actions::reply(Reply::Error(err::LINE_LOST)),
+                }
+            }
+            // Sensor reads are allowed regardless of mode (debug)
+            Command::GetSensors => Actions::reply(Reply::Sensors(self.sensor_pattern(sensors).await)),
+            Command::GetRawSensors => Actions::reply(Reply::RawSensors(self.sensor_readings(sensors).await)),
+            Command::GetDiag => Actions::reply(Reply::Diag(sensors.diagnostics().await)),
+            Command::Ping => Actions::reply(Reply::Pong),
+            // Answered by the Bluetooth task, which owns the counters
+            Command::GetUartErrors => Actions::default(),
+            // Handled by the control task, which times the loop and owns the
+            // flash and the motors (see `shutdown`)
+            Command::GetPerf | Command::SaveConfig | Command::Shutdown => Actions::default(),
+            Command::GetBattery => Actions::reply(Reply::Battery(sensors.read_battery_mv())),
+            Command::GetVersion => Actions::reply(Reply::Version),
+            Command::GetCalibration => Actions::reply(Reply::Calibration(sensors.calibration_report())),
+            Command::GetRunRange => Actions::reply(Reply::RunRange(self.run_range)),
+            Command::SetPid { kp, ki, kd } => {
+                if self.pid.set_gains(kp, ki, kd) {
+                    (self.config.kp_left, self.config.kp_right) = (kp, kp);
+                    (self.config.ki, self.config.kd) = (ki, kd);
+                    info!("PID gains set: kp={} ki={} kd={}", kp, ki, kd);
+                    Actions::reply(Reply::Pid { kp, ki, kd })
+                } else {
+                    info!("PID gains rejected: kp={} ki={} kd={}", kp, ki, kd);
+                    Actions::reply(Reply::Error(err::INVALID_PARAM))
+                }
+            }
+            Command::SetSideKp { left, right } => {
+                if self.pid.set_side_gains(left, right) {
+                    (self.config.kp_left, self.config.kp_right) = (left, right);
+                    info!("Side kp set: left={} right={}", left, right);
+                    Actions::default().acked(cmd::SET_SIDE_KP)
+                } else {
+                    Actions::reply(Reply::Error(err::INVALID_PARAM))
+                }
+            }
+            Command::SetSpeed { drive, turn } => {
+                self.config.speed = drive;
+                info!("Base speed set: {}", drive);
+                if let Some(turn) = turn {
+                    self.config.turn_speed = turn;
+                    info!("Turn speed set: {}", turn);
+                }
+                Actions::default().acked(cmd::SET_SPEED)
+            }
+            Command::SetThreshold { pct, origin } => match ThresholdOrigin::from_byte(origin) {
+                Some(origin) if (1..=99).contains(&pct) => {
+                    sensors.set_threshold(origin, pct as u16);
+                    info!("Calibration threshold set: {}% from {}", pct, origin);
+                    Actions::default().acked(cmd::SET_THRESHOLD)
+                }
+                _ => Actions::reply(Reply::Error(err::INVALID_PARAM)),
+            },
+            Command::SetPolarity(byte) => match LinePolarity::from_byte(byte) {
+                Some(polarity) => {
+                    sensors.set_polarity(polarity);
+                    info!("Line polarity set: {}", polarity);
+                    Actions::default().acked(cmd::SET_POLARITY)
+                }
+                None => Actions::reply(Reply::Error(err::INVALID_PARAM)),
+            },
+            Command::SetStream { kind: 0, .. } | Command::SetStream { period: 0, .. } => {
+                info!("Streaming off");
+                self.stream = None;
+                Actions::default().acked(cmd::STREAM)
+            }
+            Command::SetStream { kind, period } => match StreamKind::from_byte(kind) {
+                Some(kind) => {
+                    let period_ms = period as u64 * 10;
+                    info!("Streaming {} every {}ms", kind, period_ms);
+                    self.stream = Some((kind, period_ms));
+                    self.next_stream_at = now;
+                    Actions::default().acked(cmd::STREAM)
+                }
+                None => Actions::reply(Reply::Error(err::INVALID_PARAM)),
+            },
+            // Not while calibrating: the sweep would overwrite them when it ends
+            Command::SetThresholds(_) if matches!(self.mode, RobotMode::LineFollowerCalibrating(_)) => {
+                Actions::reply(Reply::Error(err::WRONG_MODE))
+            }
+            Command::SetThresholds(thresholds) => {
+                if thresholds.iter().any(|&threshold| threshold > 4095) {
+                    return Actions::reply(Reply::Error(err::INVALID_PARAM));
+                }
+                sensors.set_thresholds(&thresholds);
+                Actions::default().acked(cmd::SET_THRESHOLDS)
+            }
+            Command::SetFixedThreshold(threshold) => {
+                if threshold > 4095 {
+                    return Actions::reply(Reply::Error(err::INVALID_PARAM));
+                }
+                self.fixed_threshold = (threshold > 0).then_some(threshold);
+                sensors.set_fixed_threshold(self.fixed_threshold);
+                info!("Fixed threshold set: {}", threshold);
+                Actions::default().acked(cmd::SET_FIXED_THRESHOLD)
+            }
+            Command::SetSearch { turn, forward } => {
+                self.searcher.set_speeds(turn, forward);
+                (self.config.search_turn, self.config.search_forward) = (turn, forward);
+                info!("Search speeds set: turn={} forward={}", turn, forward);
+                Actions::default().acked(cmd::SET_SEARCH)
+            }
+            Command::SetReverseLimit(limit) => {
+                self.config.reverse_limit = limit;
+                info!("Reverse limit set: {}", limit);
+                Actions::default().acked(cmd::SET_REVERSE_LIMIT)
+            }
+            Command::SetSteeringBias(bias) => {
+                if !(-MAX_STEERING_BIAS..=MAX_STEERING_BIAS).contains(&bias) {
+                    return Actions::reply(Reply::Error(err::INVALID_PARAM));
+                }
+                self.config.steering_bias = bias;
+                info!("Steering bias set: {}", bias);
+                Actions::default().acked(cmd::SET_STEERING_BIAS)
+            }
+            Command::SetMotorEcho(enabled) => {
+                self.motor_echo = enabled;
+                info!("Motor echo: {}", enabled);
+                Actions::default().acked(cmd::SET_MOTOR_ECHO)
+            }
+            Command::SetTurnBoost(boost) => {
+                self.config.turn_boost = boost;
+                info!("Turn boost set: {}", boost);
+                Actions::default().acked(cmd::SET_TURN_BOOST)
+            }
+            Command::SetCenterOnStart(enabled) => {
+                self.config.center_on_start = enabled;
+                info!("Center on start: {}", enabled);
+                Actions::default().acked(cmd::SET_CENTER_ON_START)
+            }
+            Command::SetSpeedScale(min_pct) => {
+                self.config.speed_scale_min = min_pct;
+                info!("Speed scale set: {}%", min_pct);
+                Actions::default().acked(cmd::SET_SPEED_SCALE)
+            }
+            Command::SetFinishSustain(ds) => {
+                self.finish.set_sustain(ds as u64 * 100);
+                self.config.finish_sustain_ds = ds;
+                info!("Finish sustain set: {}ms", ds as u64 * 100);
+                Actions::default().acked(cmd::SET_FINISH_SUSTAIN)
+            }
+            Command::SetCarTimeout(ds) => {
+                self.config.car_timeout_ds = ds;
+                info!("Car timeout set: {}ms", ds as u64 * 100);
+                Actions::default().acked(cmd::SET_CAR_TIMEOUT)
+            }
+            Command::SetSteeringLimit(limit) => {
+                self.config.steering_limit = limit;
+                info!("Steering limit set: {}", limit);
+                Actions::default().acked(cmd::SET_STEERING_LIMIT)
+            }
+            Command::GetConfig => Actions::reply(Reply::Config(self.config)),
+            Command::LoadConfig(bytes) => match RobotConfig::decode(&bytes) {
+                Some(config) => {
+                    self.apply_config(config);
+                    info!("Config loaded");
+                    Actions::default().acked(cmd::LOAD_CONFIG)
+                }
+                None => Actions::reply(Reply::Error(err::INVALID_PARAM)),
+            },
+            Command::SetSearchTimeout(ds) => {
+                self.config.search_timeout_ds = ds;
+                info!("Search timeout set: {}ms", ds as u64 * 100);
+                Actions::default().acked(cmd::SET_SEARCH_TIMEOUT)
+            }
+            Command::SetPositionFilter(tau_ms) => {
+                if tau_ms > MAX_POSITION_TAU_MS {
+                    return Actions::reply(Reply::Error(err::INVALID_PARAM));
+                }
+                self.position_filter.set_tau(tau_ms as u32);
+                self.config.position_tau_ms = tau_ms;
+                info!("Position filter set: {}ms", tau_ms);
+                Actions::default().acked(cmd::SET_POSITION_FILTER)
+            }
+            Command::SetLoopPeriod(ms) => {
+                if !(MIN_CONTROL_PERIOD_MS..=MAX_CONTROL_PERIOD_MS).contains(&ms) {
+                    return Actions::reply(Reply::Error(err::INVALID_PARAM));
+                }
+                self.config.control_period_ms = ms;
+                info!("Control loop period set: {}ms", ms);
+                Actions::default().acked(cmd::SET_LOOP_PERIOD)
+            }
+            Command::SetRecovery(byte) => match RecoveryStrategy::from_byte(byte) {
+                Some(strategy) => {
+                    self.config.recovery = strategy;
+                    info!("Recovery strategy set: {}", byte);
+                    Actions::default().acked(cmd::SET_RECOVERY)
+                }
+                None => Actions::reply(Reply::Error(err::INVALID_PARAM)),
+            },
+            Command::SetController(byte) => match ControllerKind::from_byte(byte) {
+                Some(kind) => {
+                    self.pid.set_kind(kind);
+                    self.config.controller = kind;
+                    info!("Controller set: {}", byte);
+                    Actions::default().acked(cmd::SET_CONTROLLER)
+                }
+                None => Actions::reply(Reply::Error(err::INVALID_PARAM)),
+            },
+            #[cfg(feature = "obstacle-sensor")]
+            Command::SetObstacleThreshold(threshold) => {
+                // No value: an object placed at the wanted distance sets it
+                let threshold = threshold.unwrap_or_else(|| sensors.read_obstacle_raw());
+                self.obstacle.set_threshold(threshold);
+                info!("Obstacle threshold set: {}", threshold);
+                Actions::default().acked(cmd::SET_OBSTACLE_THRESHOLD)
+            }
+            Command::EStop => {
+                info!("E-stop latched");
+                self.estop = true;
+                let mut actions = self.leave_autonomous();
+                actions.push(Reply::EStop(true));
+                actions
+            }
+            Command::ClearEStop => {
+                if self.estop {
+                    info!("E-stop cleared");
+                }
+                self.estop = false;
+                Actions::reply(Reply::EStop(false))
+            }
+            Command::Unknown(byte) => self.handle_key(byte, sensors, now).await,
+        }
+    }
+
+    /// Handle WASD keyboard input, ONLY in Car mode
+    async fn handle_key<S: RawSensorSource<SENSOR_COUNT>>(&mut self, byte: u8, sensors: &mut CalibratedArray<S>, now: Instant) -> Actions {
+        if self.mode != RobotMode::Car {
+            return Actions::default();
+        }
+
+        // Drive keys are motor commands too
+        if matches!(byte, b'W' | b'w' | b'S' | b's' | b'A' | b'a' | b'D' | b'd') {
+            if self.estop {
+                return Actions::reply(Reply::Error(err::ESTOP_LATCHED));
+            }
+            if self.battery_cutoff {
+                return Actions::reply(Reply::Error(err::BATTERY_CUTOFF));
+            }
+        }
+
+        let speed = self.config.speed;
+        let turn_speed = self.config.turn_speed;
+        if matches!(byte, b'W' | b'w' | b'S' | b's' | b'A' | b'a' | b'D' | b'd' | b'Q' | b'q' | b' ') {
+            self.timed_drive_until = None;
+            self.nudge_until = None;
+            self.car_drive_at = Some(now);
+        }
+        match byte {
+            b'W' | b'w' => Actions::motors(MotorCommand::Forward(speed)),
+            b'S' | b's' => Actions::motors(MotorCommand::Backward(speed)),
+            b'A' | b'a' => Actions::motors(MotorCommand::TurnLeft(turn_speed)),
+            b'D' | b'd' => Actions::motors(MotorCommand::TurnRight(turn_speed)),
+            b'Q' | b'q' | b' ' => Actions::motors(MotorCommand::Stop),
+            // Manual debug read of raw sensors
+            b'R' | b'r' => Actions::reply(Reply::RawSensors(sensors.read_all().await)),
+            _ => Actions::default(),
+        }
+    }
+
+    /// Called when the Bluetooth connection drops or its heartbeat times out:
+    /// stop and leave any autonomous mode so the robot can't run away.
+    pub fn handle_link_lost(&mut self) -> Actions {
+        self.leave_autonomous()
+    }
+
+    /// Called as the battery crosses the cutoff (the caller debounces it):
+    /// on the way down stop, leave any autonomous mode and refuse to move
+    /// until it is called with `false` again, so a dying pack isn't driven
+    /// into erratic motor behavior.
+    pub fn set_battery_cutoff(&mut self, cutoff: bool) -> Actions {
+        if cutoff == self.battery_cutoff {
+            return Actions::default();
+        }
+        self.battery_cutoff = cutoff;
+        if !cutoff {
+            info!("Battery recovered, motors allowed again");
+            return Actions::default();
+        }
+        warn!("Battery below the cutoff, stopping");
+        let mut actions = self.leave_autonomous();
+        actions.push(Reply::Error(err::BATTERY_CUTOFF));
+        actions
+    }
+
+    /// The one way to change modes: stop the motors before anything else
+    /// (the stop is applied ahead of any later command), and clear controller
+    /// and search state so nothing from the old mode carries over.
+    /// Leaving Running reports the run's stats.
+    fn enter_mode(&mut self, mode: RobotMode) -> Actions {
+        let mut actions = Actions::motors(MotorCommand::Stop);
+        if self.mode == RobotMode::LineFollowerRunning && mode != RobotMode::LineFollowerRunning {
+            info!("Run ended: peak error {}, {} line losses, {}ms, max speed {}, {} saturations",
+                self.run_stats.peak_error, self.run_stats.line_losses,
+                self.run_stats.run_ms, self.run_stats.max_speed, self.run_stats.saturations);
+            actions.push(Reply::RunStats(self.run_stats));
+        }
+        self.pid.reset();
+        self.speed_modulator.reset();
+        self.position_filter.reset();
+        self.searcher.reset();
+        self.finish.reset();
+        self.junctions.reset();
+        self.junction = None;
+        self.white_since = None;
+        self.lost_since = None;
+        self.search_state = None;
+        self.centering_since = None;
+        self.timed_drive_until = None;
+        self.car_drive_at = None;
+        self.nudge_until = None;
+        self.paused = false;
+        self.mode = mode;
+        actions
+    }
+
+    /// Enter Line Follower Running, with the first control step timed from
+    /// `now` and, if enabled, the centering pivot first.
+    fn start_running(&mut self, now: Instant) -> Actions {
+        self.last_control_at = now;
+        self.run_stats = RunStats::default();
+        self.run_range = RawRange::default();
+        self.last_saturated = false;
+        let actions = self.enter_mode(RobotMode::LineFollowerRunning);
+        self.centering_since = self.config.center_on_start.then_some(now);
+        actions
+    }
+
+    /// Pre-run centering: pivot gently toward the line until it is under
+    /// the middle of the array, so the run starts without a swerve. Gives up
+    /// after `CENTERING_TIMEOUT_MS`, or right away if no line is seen (the
+    /// run's own search takes over). None once done.
+    async fn tick_centering<S: RawSensorSource<SENSOR_COUNT>>(&mut self, sensors: &mut CalibratedArray<S>, now: Instant) -> Option<Actions> {
+        let since = self.centering_since?;
+        let (position, intensity, _) = sensors.read_line_position_ex(POSITION_FLOOR).await;
+        self.last_binary = sensors.binary_pattern(&sensors.last_raw());
+        let timed_out = (now - since).as_millis() >= CENTERING_TIMEOUT_MS;
+        if intensity == 0 || position.abs() <= CENTERING_TOLERANCE || timed_out {
+            info!("Centering done: position {}, timed out {}", position, timed_out);
+            self.centering_since = None;
+            // Time the run from here, without the centering
+            self.last_control_at = now;
+            return Some(Actions::motors(MotorCommand::Stop));
+        }
+        // Positive position = line on the right
+        Some(Actions::motors(if position > 0 {
+            MotorCommand::TurnRight(CENTERING_SPEED)
+        } else {
+            MotorCommand::TurnLeft(CENTERING_SPEED)
+        }))
+    }
+
+    /// Stop, dropping out of calibration/running back to Line Follower Idle.
+    /// Out of calibration/running the robot brakes, so it stops on the line
+    /// instead of coasting past it; Car mode keeps the normal stop.
+    fn leave_autonomous(&mut self) -> Actions {
+        self.timed_drive_until = None;
+        self.car_drive_at = None;
+        self.nudge_until = None;
+        if let RobotMode::LineFollowerCalibrating(_) | RobotMode::LineFollowerRunning = self.mode {
+            info!("Stopping, back to Line Follower Idle");
+            let mut actions = self.enter_mode(RobotMode::LineFollowerIdle);
+            actions.motors = Some(MotorCommand::Brake);
+            return actions;
+        }
+        Actions::motors(MotorCommand::Stop)
+    }
+
+    /// Run one iteration of the mode logic (non-blocking).
+    pub async fn tick<S: RawSensorSource<SENSOR_COUNT>>(&mut self, sensors: &mut CalibratedArray<S>, now: Instant) -> Actions {
+        // Shutdown: ramp both wheels linearly to zero, then stay parked
+        if let Some((start, [left, right])) = self.parking {
+            let elapsed = (now - start).as_millis();
+            if elapsed >= SHUTDOWN_RAMP_MS || self.estop {
+                info!("Parked");
+                self.parking = None;
+                self.parked = true;
+                return Actions::motors(MotorCommand::Stop).acked(cmd::SHUTDOWN);
+            }
+            let remaining = (SHUTDOWN_RAMP_MS - elapsed) as i32;
+            let scale = |speed: i8| (speed as i32 * remaining / SHUTDOWN_RAMP_MS as i32) as i8;
+            return Actions::motors(MotorCommand::Set { left: scale(left), right: scale(right) });
+        }
+        if self.parked {
+            return Actions::default();
+        }
+
+        // Latched E-stop or flat battery: force the motors off every
+        // iteration, whatever the mode
+        if self.estop || self.battery_cutoff {
+            return Actions::motors(MotorCommand::Stop);
+        }
+
+        // A nudge is a single tap: stop once it's over, in whichever mode allows it
+        if let Some(until) = self.nudge_until
+            && now >= until
+        {
+            self.nudge_until = None;
+            return Actions::motors(MotorCommand::Stop);
+        }
+
+        let mut actions = match self.mode {
+            RobotMode::Car => {
+                // Debug: Print raw sensor ADC values every 500ms
+                if (now - self.last_adc_log_at).as_millis() >= ADC_LOG_INTERVAL_MS {
+                    self.last_adc_log_at = now;
+                    let raw = sensors.read_all().await;
+                    self.last_binary = sensors.binary_pattern(&raw);
+                    info!("ADC: {} {} {} {} {} {} {} {}",
+                        raw[0], raw[1], raw[2], raw[3],
+                        raw[4], raw[5], raw[6], raw[7]);
+                }
+                // Manual driving needs a steady stream of commands: stop if it
+                // dries up (phone locked, app in the background) even while
+                // PINGs keep the link alive
+                let timeout_ms = self.config.car_timeout_ds as u64 * 100;
+                if let Some(at) = self.car_drive_at
+                    && timeout_ms > 0
+                    && (now - at).as_millis() >= timeout_ms
+                {
+                    info!("No drive command for {}ms, stopping", timeout_ms);
+                    self.car_drive_at = None;
+                    Actions::motors(MotorCommand::Stop)
+                } else {
+                    match self.timed_drive_until {
+                        Some((until, command)) if now >= until => {
+                            info!("Timed drive done");
+                            self.timed_drive_until = None;
+                            Actions::motors(MotorCommand::Stop).acked(command)
+                        }
+                        _ => Actions::default(),
+                    }
+                }
+            }
+            RobotMode::LineFollowerIdle => {
+                // Waiting for Start command
+                Actions::default()
+            }
+            RobotMode::LineFollowerCalibrating(start_time) => {
+                self.tick_calibrating(sensors, now, (now - start_time).as_millis()).await
+            }
+            RobotMode::LineFollowerRunning if self.paused => Actions::default(),
+            RobotMode::LineFollowerRunning => match self.tick_centering(sensors, now).await {
+                Some(actions) => actions,
+                None => self.tick_running(sensors, now).await,
+            },
+        };
+
+        #[cfg(feature = "defmt-telemetry")]
+        if self.mode == RobotMode::LineFollowerRunning && !self.paused {
+            info!("{}", ControlSample {
+                position: self.last_weighted_pos,
+                intensity: self.last_intensity,
+                steering: self.last_steering,
+                left_speed: self.last_left_speed,
+                right_speed: self.last_right_speed,
+            });
+        }
+
+        // Send debug info every 200ms when in LineFollowerRunning
+        if self.mode == RobotMode::LineFollowerRunning
+            && !self.paused
+            && (now - self.last_telemetry_at).as_millis() >= TELEMETRY_INTERVAL_MS
+        {
+            self.last_telemetry_at = now;
+            actions.push(Reply::Telemetry(self.telemetry_frame(sensors)));
+        }
+
+        actions
+    }
+
+    /// The streamed reply, once it is due. Runs on its own schedule in every
+    /// mode, alongside the on-demand sensor requests.
+    pub async fn stream<S: RawSensorSource<SENSOR_COUNT>>(&mut self, sensors: &mut CalibratedArray<S>, now: Instant) -> Option<Reply> {
+        let (kind, period_ms) = self.stream?;
+        if now < self.next_stream_at {
+            return None;
+        }
+        // Keep to the period however the loop drifts, but don't burst to
+        // catch up after a long iteration (a calibration)
+        self.next_stream_at += Duration::from_millis(period_ms);
+        if self.next_stream_at <= now {
+            self.next_stream_at = now + Duration::from_millis(period_ms);
+        }
+        Some(match kind {
+            StreamKind::Binary => Reply::Sensors(self.sensor_pattern(sensors).await),
+            StreamKind::Raw => Reply::RawSensors(self.sensor_readings(sensors).await),
+            StreamKind::Telemetry => Reply::Telemetry(self.telemetry_frame(sensors)),
+        })
+    }
+
+    /// Raw readings for the GUI. While calibrating they feed the min/max
+    /// too, so what the GUI shows is what is being learned
+    async fn sensor_readings<S: RawSensorSource<SENSOR_COUNT>>(&self, sensors: &mut CalibratedArray<S>) -> SensorReadings {
+        if let RobotMode::LineFollowerCalibrating(_) = self.mode {
+            sensors.update_calibration().await
+        } else {
+            sensors.read_all().await
+        }
+    }
+
+    /// Binary pattern for the GUI, folded into the calibration like
+    /// `sensor_readings` (without hysteresis: the thresholds are still moving)
+    async fn sensor_pattern<S: RawSensorSource<SENSOR_COUNT>>(&self, sensors: &mut CalibratedArray<S>) -> u16 {
+        if let RobotMode::LineFollowerCalibrating(_) = self.mode {
+            let readings = sensors.update_calibration().await;
+            sensors.binary_pattern(&readings)
+        } else {
+            sensors.read_binary().await
+        }
+    }
+
+    /// Running telemetry from the last control tick.
+    fn telemetry_frame<S: RawSensorSource<SENSOR_COUNT>>(&self, sensors: &CalibratedArray<S>) -> TelemetryFrame {
+        TelemetryFrame {
+            // Saturate rather than wrap, so an out-of-range position
+            // plots at the edge instead of flipping sign
+            position: self.last_weighted_pos.clamp(i16::MIN as i32, i16::MAX as i32) as i16,
+            intensity: self.last_intensity.min(u16::MAX as u32) as u16,
+            steering: self.last_steering as i8,
+            left_speed: self.last_left_speed,
+            right_speed: self.last_right_speed,
+            confidence: self.last_confidence,
+            coverage: sensors.line_coverage(),
+            controller: self.pid.kind().as_byte(),
+            saturated: self.last_saturated,
+            steering_limited: self.last_steering_limited,
+            binary: self.last_binary,
+            search: SearchState::as_byte(self.search_state),
+        }
+    }
+
+    async fn tick_calibrating<S: RawSensorSource<SENSOR_COUNT>>(&mut self, sensors: &mut CalibratedArray<S>, now: Instant, elapsed: u64) -> Actions {
+        // Calibrate for 10 seconds total
+        if elapsed >= 10000 {
+            let result = sensors.finalize_calibration();
+            info!("Calibration Complete! Running...");
+            let mut actions = self.start_running(now);
+            // Some channels never saw both line and background: they run on
+            // fallback ranges, warn so the user can recalibrate
+            if let Err(e) = result {
+                actions.push(Reply::LowContrast(e.low_contrast));
+            }
+            actions.push(Reply::CalibrationEnd);
+            actions.calibrated = true;
+            return actions;
+        }
+
+        // Continue updating calibration in both phases in case we see new
+        // extremes. Sampled on its own timer for half the loop period, so the
+        // density is fixed; the rest of the tick stays free for commands,
+        // the E-stop and the watchdog, which a single 10s await would starve
+        let readings = sensors.calibrate(self.config.control_period_ms as u64 / 2).await;
+        self.last_binary = sensors.binary_pattern(&readings);
+
+        // Once a second, show the GUI how much contrast each channel has seen
+        let mut actions = Actions::default();
+        let elapsed_s = (elapsed / 1000) as u8;
+        if elapsed_s > self.calibration_progress_s {
+            self.calibration_progress_s = elapsed_s;
+            let report = sensors.calibration_report();
+            let spread = core::array::from_fn(|i| report.max[i].saturating_sub(report.min[i]));
+            actions.push(Reply::CalibrationProgress { elapsed_s, spread });
+        }
+
+        let command = if elapsed < 8000 {
+            // Phase 1: Sweep for min/max calibration
+            // Speed 70 to overcome friction
+            let speed = 70;
+
+            // Sweep Sequence (approx 1.4s per full sweep)
+            if elapsed < 700 {
+                MotorCommand::TurnLeft(speed)
+            } else if elapsed < 2100 {
+                MotorCommand::TurnRight(speed)
+            } else if elapsed < 3500 {
+                MotorCommand::TurnLeft(speed)
+            } else if elapsed < 4900 {
+                MotorCommand::TurnRight(speed)
+            } else if elapsed < 6300 {
+                MotorCommand::TurnLeft(speed)
+            } else if elapsed < 7700 {
+                MotorCommand::TurnRight(speed)
+            } else {
+                // Turn Left blindly for remaining time (7700..8000)
+                // This guarantees we are moving towards center/left before tracking starts
+                MotorCommand::TurnLeft(speed)
+            }
+        } else {
+            // Phase 2: Active Centering Phase (8s to 10s)
+            // Use collected calibration data to find line center
+            let (position, intensity) = sensors.read_line_position().await;
+
+            // Check if we have a line signal
+            if intensity > 0 {
+                // P-Control to center (Target position = 0)
+                // Position range +/- 3500.
+                let turn = (position / 20).clamp(-50, 50) as i8;
+
+                // Deadband to avoid jitter
+                if turn.abs() < 10 {
+                    MotorCommand::Stop
+                } else {
+                    // To turn Right (Pos > 0), pivot Right (L fwd, R rev)
+                    MotorCommand::Set { left: turn, right: -turn }
+                }
+            } else {
+                // Stop if line is temporarily lost
+                MotorCommand::Stop
+            }
+        };
+        actions.motors = Some(command);
+        actions
+    }
+
+    /// Halt while an obstacle is ahead, reporting each change. Returns the
+    /// actions for this tick while halted (or just cleared), None to keep
+    /// following the line.
+    #[cfg(feature = "obstacle-sensor")]
+    fn check_obstacle<S: RawSensorSource<SENSOR_COUNT>>(&mut self, sensors: &mut CalibratedArray<S>, now: Instant) -> Option<Actions> {
+        match self.obstacle.update(sensors.read_obstacle_raw()) {
+            Some(true) => {
+                info!("Obstacle ahead, halting");
+                let mut actions = Actions::motors(MotorCommand::Stop);
+                actions.push(Reply::Obstacle(true));
+                Some(actions)
+            }
+            Some(false) => {
+                // Line follower state is stale after the pause; resume next tick
+                info!("Obstacle cleared, resuming");
+                self.pid.reset();
+                self.speed_modulator.reset();
+                self.position_filter.reset();
+                self.last_control_at = now;
+                Some(Actions::reply(Reply::Obstacle(false)))
+            }
+            None if self.obstacle.is_blocked() => Some(Actions::motors(MotorCommand::Stop)),
+            None => None,
+        }
+    }
+
+    async fn tick_running<S: RawSensorSource<SENSOR_COUNT>>(&mut self, sensors: &mut CalibratedArray<S>, now: Instant) -> Actions {
+        // Read weighted position (-3500 to 3500) and intensity, ignoring
+        // channels that only see background noise
+        // Positive = line on right side, Negative = line on left side
+        #[cfg(feature = "obstacle-sensor")]
+        if let Some(actions) = self.check_obstacle(sensors, now) {
+            return actions;
+        }
+
+        let (position, intensity, confidence) = sensors.read_line_position_ex(POSITION_FLOOR).await;
+
+        self.run_range.update(&sensors.last_raw());
+        self.last_binary = sensors.binary_pattern(&sensors.last_raw());
+
+        // Update telemetry
+        self.last_weighted_pos = position;
+        self.last_intensity = intensity;
+        self.last_confidence = confidence;
+
+        let dt_ms = (now - self.last_control_at).as_millis() as u32;
+        self.last_control_at = now;
+        self.run_stats.run_ms = self.run_stats.run_ms.saturating_add(dt_ms);
+
+        if intensity == 0 {
+            if self.lost_since.is_none() {
+                self.run_stats.line_losses = self.run_stats.line_losses.saturating_add(1);
+            }
+            // History is stale once the line is lost; refill after re-acquiring
+            self.speed_modulator.reset();
+            // Start the filter from the fresh position once the line is back
+            self.position_filter.reset();
+            // No error to integrate; let the PID bleed off its integral
+            self.pid.update_lost(dt_ms);
+
+            // Clean background on every channel for a while means we left the
+            // mat; a faint reading means the line may be just between sensors
+            if sensors.surface_state().await == SurfaceState::OffLineWhite {
+                let since = *self.white_since.get_or_insert(now);
+                if (now - since).as_millis() >= OFF_MAT_MS {
+                    info!("Off the mat, stopping");
+                    self.fault = true;
+                    let mut actions = self.enter_mode(RobotMode::LineFollowerIdle);
+                    actions.push(Reply::Error(err::OFF_MAT));
+                    return actions;
+                }
+            } else {
+                self.white_since = None;
+            }
+
+            // Smooth curve: keep arcing the way we were steering before pivoting
+            let lost_since = *self.lost_since.get_or_insert(now);
+            let timeout_ms = self.config.search_timeout_ds as u64 * 100;
+            let timed_out = timeout_ms > 0 && (now - lost_since).as_millis() >= timeout_ms;
+            if self.config.recovery == RecoveryStrategy::Arc && (now - lost_since).as_millis() < ARC_RECOVERY_MS {
+                self.search_state = Some(SearchState::Searching);
+                let bias = self.last_steering.clamp(-ARC_RECOVERY_SPEED, ARC_RECOVERY_SPEED);
+                let left = (ARC_RECOVERY_SPEED + bias) as i8;
+                let right = (ARC_RECOVERY_SPEED - bias) as i8;
+                return Actions::motors(MotorCommand::Set { left, right });
+            }
+
+            // Lost line - widening sweep search, starting in last known direction
+            if !self.searcher.is_active() {
+                self.searcher.start(now, self.last_direction);
+            }
+            return match self.searcher.update(now) {
+                Some(command) if !timed_out => {
+                    self.search_state = Some(self.searcher.state());
+                    Actions::motors(command)
+                }
+                _ => {
+                    info!("Line lost, search gave up");
+                    // One last frame so the GUI sees why the run ends
+                    self.search_state = Some(SearchState::GaveUp);
+                    let frame = self.telemetry_frame(sensors);
+                    self.fault = true;
+                    let mut actions = self.enter_mode(RobotMode::LineFollowerIdle);
+                    actions.push(Reply::Telemetry(frame));
+                    actions.push(Reply::Error(err::LINE_LOST));
+                    actions
+                }
+            };
+        }
+        self.searcher.reset();
+        self.search_state = None;
+        self.white_since = None;
+        self.lost_since = None;
+
+        let binary = sensors.read_binary().await;
+        self.last_binary = binary;
+
+        // A cross intersection is brief; the finish bar keeps every sensor active
+        if self.finish.update(binary.count_ones() as usize == SENSOR_COUNT, now) {
+            info!("Finish line reached");
+            let mut actions = self.enter_mode(RobotMode::LineFollowerIdle);
+            actions.push(Reply::Finished);
+            return actions;
+        }
+
+        // Junctions are reported once confirmed; a full crossing is driven
+        // straight through instead of chasing the smeared centroid
+        let mut actions = Actions::default();
+        let junction = self.junctions.update(classify_line::<SENSOR_COUNT>(binary, position, intensity));
+        if junction != self.junction {
+            if let Some(event) = junction {
+                info!("Junction: {}", event);
+                actions.push(Reply::LineEvent(event));
+            }
+            self.junction = junction;
+        }
+        let crossing = junction == Some(LineEvent::FullLine);
+
+        // Line found - PID steering with straightness-modulated base speed
+        // Physical orientation: Index 0 = Left side of robot
+        // Negative position = line on LEFT -> need to turn LEFT
+        let abs_pos = position.abs();
+        self.run_stats.peak_error = self.run_stats.peak_error.max(abs_pos.min(u16::MAX as i32) as u16);
+
+        // Base speed scales with how straight the recent path has been (50..90),
+        // shifted by the configured base speed (default 70 = no shift)
+        let modulated = self.speed_modulator.update(position);
+        let mut base_speed = (modulated + self.config.speed as i32 - 70).clamp(0, 100);
+
+        // Optionally slow down continuously for a faint or off-center line,
+        // with a matching continuous inner-wheel floor
+        let speed_scale = LineSpeedScale::new(self.config.speed_scale_min);
+        if speed_scale.is_enabled() {
+            base_speed = speed_scale.scale(base_speed, position, intensity);
+        }
+
+        // Split or smeared line (e.g. crossing another line): the centroid is
+        // misleading, so slow down and hold the current steering
+        let low_confidence = confidence < LOW_CONFIDENCE;
+        if low_confidence {
+            base_speed = base_speed.min(LOW_CONFIDENCE_SPEED);
+        }
+
+        // Inner-wheel floor depends on how far off the line is;
+        // steering itself comes from the PID controller
+        let min_speed: i32 = if abs_pos >= 2500 {
+            -(self.config.reverse_limit as i32) // EMERGENCY ZONE: inner wheel may reverse
+        } else if speed_scale.is_enabled() {
+            // Zone floors as anchors at the zone centers, interpolated so
+            // the floor doesn't step at the zone boundaries
+            match abs_pos {
+                ..250 => 55,
+                250..1000 => 55 - (abs_pos - 250) * 15 / 750,
+                1000..2000 => 40 - (abs_pos - 1000) * 10 / 1000,
+                _ => 30,
+            }
+        } else if abs_pos < 500 {
+            55 // CENTER ZONE
+        } else if abs_pos < 1500 {
+            40 // WARNING ZONE
+        } else {
+            30 // CRITICAL ZONE
+        };
+        let min_speed = min_speed.min(base_speed);
+
+        // Tenths of a percent, so the motors can dither the fraction. The
+        // bias only applies here, never to the search maneuvers
+        let steering_fine = if crossing {
+            self.config.steering_bias as i32 * 10
+        } else if low_confidence {
+            self.last_steering * 10
+        } else {
+            let filtered = self.position_filter.update(position, dt_ms);
+            self.pid.update(filtered, dt_ms) + self.config.steering_bias as i32 * 10
+        };
+        // Cap the differential before mixing, so a high Kp stays responsive
+        // near the center without snapping the robot around far off it
+        let limit = self.config.steering_limit as i32 * 10;
+        let steering_limited = steering_fine.abs() > limit;
+        let steering_fine = steering_fine.clamp(-limit, limit);
+        let steering = steering_fine / 10;
+
+        let mut left_wanted = base_speed * 10 + steering_fine;
+        let mut right_wanted = base_speed * 10 - steering_fine;
+
+        // Turn authority: at low base speed the inner wheel hits its floor
+        // and the turn loses differential; the outer wheel makes up what
+        // was lost, up to the boost cap
+        let boost = self.config.turn_boost as i32 * 10;
+        let left_lost = (min_speed * 10 - left_wanted).clamp(0, boost);
+        let right_lost = (min_speed * 10 - right_wanted).clamp(0, boost);
+        left_wanted += right_lost;
+        right_wanted += left_lost;
+        let left_fine = left_wanted.clamp(min_speed * 10, 1000);
+        let right_fine = right_wanted.clamp(min_speed * 10, 1000);
+
+        // A clamped wheel can't follow the PID any further: the gains are
+        // too high for this speed. Count each time it starts happening
+        let saturated = left_fine != left_wanted || right_fine != right_wanted;
+        if saturated && !self.last_saturated {
+            self.run_stats.saturations = self.run_stats.saturations.saturating_add(1);
+        }
+        self.last_saturated = saturated;
+        let left_speed = (left_fine / 10) as i8;
+        let right_speed = (right_fine / 10) as i8;
+
+        // Update telemetry
+        self.last_steering = steering;
+        self.last_steering_limited = steering_limited;
+        self.last_left_speed = left_speed;
+        self.last_right_speed = right_speed;
+        let max_speed = left_speed.unsigned_abs().max(right_speed.unsigned_abs());
+        self.run_stats.max_speed = self.run_stats.max_speed.max(max_speed);
+
+        // Update last direction for when we lose line
+        if low_confidence || crossing {
+            // Keep the last trusted direction
+        } else if position > 300 {
+            self.last_direction = 1; // Line on right, was turning right
+        } else if position < -300 {
+            self.last_direction = -1; // Line on left, was turning left
+        } else {
+            self.last_direction = 0; // Centered
+        }
+
+        actions.motors = Some(MotorCommand::SetFine { left: left_fine as i16, right: right_fine as i16 });
+        actions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core::cell::Cell;
+    use embassy_futures::block_on;
+    use std::rc::Rc;
+
+    const WHITE: SensorReadings = [200; SENSOR_COUNT];
+    const BLACK: SensorReadings = [3000; SENSOR_COUNT];
+    const CENTERED: SensorReadings = [200, 200, 200, 3000, 3000, 200, 200, 200];
+
+    /// Reads whatever sweep the test last put under the array
+    struct Surface {
+        sweep: Rc<Cell<SensorReadings>>,
+    }
+
+    impl RawSensorSource<SENSOR_COUNT> for Surface {
+        async fn read_raw(&mut self) -> SensorReadings {
+            self.sweep.get()
+        }
+    }
+
+    struct Robot {
+        state: RobotState,
+        sensors: CalibratedArray<Surface>,
+        sweep: Rc<Cell<SensorReadings>>,
+    }
+
+    impl Robot {
+        fn new(config: RobotConfig) -> Self {
+            let sweep = Rc::new(Cell::new(WHITE));
+            Self {
+                state: RobotState::new(at(0), config),
+                sensors: CalibratedArray::new(Surface { sweep: sweep.clone() }),
+                sweep,
+            }
+        }
+
+        fn command(&mut self, cmd: Command, ms: u64) -> Actions {
+            block_on(self.state.handle_command(cmd, &mut self.sensors, at(ms)))
+        }
+
+        fn tick(&mut self, ms: u64) -> Actions {
+            block_on(self.state.tick(&mut self.sensors, at(ms)))
+        }
+
+        /// Line Follower Running, calibrated over `WHITE` and `BLACK` by
+        /// the sweep's sensor reads, started at 10s
+        fn running(config: RobotConfig) -> Self {
+            let mut robot = Self::new(config);
+            robot.command(Command::SetMode(1), 0);
+            robot.command(Command::Start, 0);
+            for sweep in [WHITE, BLACK] {
+                robot.sweep.set(sweep);
+                robot.command(Command::GetRawSensors, 0);
+            }
+            robot.sweep.set(CENTERED);
+            let actions = robot.tick(10_000);
+            assert!(actions.calibrated);
+            assert!(robot.state.mode == RobotMode::LineFollowerRunning);
+            robot
+        }
+    }
+
+    fn at(ms: u64) -> Instant {
+        Instant::from_millis(ms)
+    }
+
+    fn has_error(actions: &Actions, code: u8) -> bool {
+        actions.replies.iter().any(|reply| matches!(reply, Reply::Error(e) if *e == code))
+    }
+
+    fn acked(actions: &Actions, code: u8) -> bool {
+        actions.replies.iter().any(|reply| matches!(reply, Reply::Ack(c) if *c == code))
+    }
+
+    #[test]
+    fn start_in_car_mode_is_refused() {
+        let mut robot = Robot::new(RobotConfig::default());
+        let actions = robot.command(Command::Start, 0);
+        assert!(has_error(&actions, err::WRONG_MODE));
+        assert_eq!(actions.motors, None);
+        assert!(robot.state.mode == RobotMode::Car);
+    }
+
+    #[test]
+    fn estop_gates_motor_commands() {
+        let mut robot = Robot::new(RobotConfig::default());
+        robot.command(Command::EStop, 0);
+        let actions = robot.command(Command::Motor { left: 50, right: 50 }, 10);
+        assert!(has_error(&actions, err::ESTOP_LATCHED));
+        assert_eq!(actions.motors, None);
+        assert_eq!(robot.tick(20).motors, Some(MotorCommand::Stop));
+
+        robot.command(Command::ClearEStop, 30);
+        let actions = robot.command(Command::Motor { left: 50, right: 50 }, 40);
+        assert_eq!(actions.motors, Some(MotorCommand::Tank { left: 50, right: 50 }));
+    }
+
+    #[test]
+    fn battery_cutoff_gates_motor_commands() {
+        let mut robot = Robot::running(RobotConfig::default());
+        let actions = robot.state.set_battery_cutoff(true);
+        assert!(has_error(&actions, err::BATTERY_CUTOFF));
+        assert!(robot.state.mode == RobotMode::LineFollowerIdle);
+        assert_eq!(robot.tick(10_010).motors, Some(MotorCommand::Stop));
+        let actions = robot.command(Command::Start, 10_020);
+        assert!(has_error(&actions, err::BATTERY_CUTOFF));
+
+        robot.state.set_battery_cutoff(false);
+        robot.command(Command::SetMode(0), 10_030);
+        let actions = robot.command(Command::Motor { left: -30, right: 30 }, 10_040);
+        assert_eq!(actions.motors, Some(MotorCommand::Tank { left: -30, right: 30 }));
+    }
+
+    #[test]
+    fn calibrate_run_and_give_up_on_a_lost_line() {
+        let config = RobotConfig { search_timeout_ds: 10, ..RobotConfig::default() };
+        let mut robot = Robot::running(config);
+        let actions = robot.tick(10_010);
+        assert!(matches!(actions.motors, Some(MotorCommand::SetFine { .. })));
+
+        // Only background: the 1s search gives up before the off-mat stop
+        robot.sweep.set(WHITE);
+        let actions = robot.tick(10_020);
+        assert!(actions.motors.is_some());
+        assert!(!has_error(&actions, err::LINE_LOST));
+        assert!(robot.state.search_state.is_some());
+        assert!(robot.state.mode == RobotMode::LineFollowerRunning);
+
+        let actions = robot.tick(11_020);
+        assert!(has_error(&actions, err::LINE_LOST));
+        assert_eq!(actions.motors, Some(MotorCommand::Stop));
+        assert!(robot.state.mode == RobotMode::LineFollowerIdle);
+    }
+
+    #[test]
+    fn stop_from_every_mode() {
+        let mut robot = Robot::new(RobotConfig::default());
+        robot.command(Command::Motor { left: 50, right: 50 }, 0);
+        let actions = robot.command(Command::Stop, 10);
+        assert!(acked(&actions, cmd::STOP));
+        assert_eq!(actions.motors, Some(MotorCommand::Stop));
+        assert!(robot.state.mode == RobotMode::Car);
+
+        robot.command(Command::SetMode(1), 20);
+        let actions = robot.command(Command::Stop, 30);
+        assert_eq!(actions.motors, Some(MotorCommand::Stop));
+        assert!(robot.state.mode == RobotMode::LineFollowerIdle);
+
+        robot.command(Command::Start, 40);
+        assert!(matches!(robot.state.mode, RobotMode::LineFollowerCalibrating(_)));
+        let actions = robot.command(Command::Stop, 50);
+        assert_eq!(actions.motors, Some(MotorCommand::Brake));
+        assert!(robot.state.mode == RobotMode::LineFollowerIdle);
+
+        let mut robot = Robot::running(RobotConfig::default());
+        robot.tick(10_010);
+        let actions = robot.command(Command::Stop, 10_020);
+        assert_eq!(actions.motors, Some(MotorCommand::Brake));
+        assert!(actions.replies.iter().any(|reply| matches!(reply, Reply::RunStats(_))));
+        assert!(robot.state.mode == RobotMode::LineFollowerIdle);
+    }
+}
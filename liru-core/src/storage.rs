@@ -0,0 +1,213 @@
+//! Persistent settings for LiRu robot.
+//!
+//! The tunables (`RobotConfig`) and the last sensor calibration live in the
+//! last flash sector (sector 7, 128KB at 0x0806_0000), kept out of the
+//! firmware image by memory.x. Each save appends a CRC-protected record to
+//! the first blank slot and the newest valid record of each kind wins, so
+//! saving never erases: a 128KB sector erase takes over a second, longer
+//! than the watchdog allows. Once the sector is full it is erased at the
+//! next boot, before the watchdog is armed.
+
+use defmt::{info, warn};
+use embassy_stm32::flash::{Blocking, Flash};
+
+use crate::config::RobotConfig;
+use crate::sensors::{CalibrationReport, SENSOR_COUNT};
+
+/// Offset of the config sector from the start of flash
+const CONFIG_SECTOR_OFFSET: u32 = 0x6_0000;
+const CONFIG_SECTOR_SIZE: u32 = 128 * 1024;
+
+/// Bytes per slot, a multiple of the flash write size.
+const RECORD_LEN: usize = 32;
+const SLOTS: u32 = CONFIG_SECTOR_SIZE / RECORD_LEN as u32;
+
+/// A config record (magic, config, padding, CRC) takes two slots. Records
+/// from the older single-slot layout carry a different magic and are
+/// skipped one slot at a time.
+const CONFIG_RECORD_LEN: usize = 2 * RECORD_LEN;
+const _: () = assert!(4 + RobotConfig::LEN + 4 <= CONFIG_RECORD_LEN, "config outgrew the flash record");
+
+/// A calibration record (magic, min, max, thresholds, padding, CRC) takes
+/// two slots
+const CALIBRATION_RECORD_LEN: usize = 2 * RECORD_LEN;
+const _: () = assert!(4 + 6 * SENSOR_COUNT + 4 <= CALIBRATION_RECORD_LEN, "calibration outgrew the flash record");
+
+/// Marks a written record ("LiR" and the config length); a blank slot reads
+/// all 0xFF, and a record saved with another config layout is ignored
+const RECORD_MAGIC: [u8; 4] = [b'L', b'i', b'R', RobotConfig::LEN as u8];
+
+/// Marks a calibration record ("LiC" and the sensor count)
+const CALIBRATION_MAGIC: [u8; 4] = [b'L', b'i', b'C', SENSOR_COUNT as u8];
+
+/// Tunables and calibration stored in flash
+pub struct ConfigStore<'d> {
+    flash: Flash<'d, Blocking>,
+    /// First blank slot, None when the sector is full
+    next_slot: Option<u32>,
+    /// Newest valid config found
+    loaded: Option<RobotConfig>,
+    /// Newest valid calibration found
+    calibration: Option<CalibrationReport>,
+}
+
+impl<'d> ConfigStore<'d> {
+    /// Scan the sector for the newest valid record. A full sector is erased
+    /// and the newest record written back: call before arming the watchdog.
+    pub fn new(flash: Flash<'d, Blocking>) -> Self {
+        let mut store = Self { flash, next_slot: None, loaded: None, calibration: None };
+        let mut slot = 0;
+        while slot < SLOTS {
+            // Both kinds of record take two slots
+            let mut record = [0u8; CALIBRATION_RECORD_LEN];
+            if store.flash.blocking_read(slot_offset(slot), &mut record[..RECORD_LEN]).is_err() {
+                break;
+            }
+            if record[..RECORD_LEN].iter().all(|&byte| byte == 0xFF) {
+                store.next_slot = Some(slot);
+                break;
+            }
+            if record[..4] != CALIBRATION_MAGIC && record[..4] != RECORD_MAGIC {
+                slot += 1;
+                continue;
+            }
+            if slot + 1 < SLOTS
+                && store.flash.blocking_read(slot_offset(slot + 1), &mut record[RECORD_LEN..]).is_ok()
+            {
+                if record[..4] == CALIBRATION_MAGIC {
+                    if let Some(calibration) = decode_calibration(&record) {
+                        store.calibration = Some(calibration);
+                    }
+                } else if let Some(config) = decode_record(&record) {
+                    store.loaded = Some(config);
+                }
+            }
+            slot += 2;
+        }
+
+        if store.next_slot.is_none() {
+            info!("Config sector full, compacting");
+            let end = CONFIG_SECTOR_OFFSET + CONFIG_SECTOR_SIZE;
+            if store.flash.blocking_erase(CONFIG_SECTOR_OFFSET, end).is_ok() {
+                store.next_slot = Some(0);
+                if let Some(config) = store.loaded {
+                    store.save(&config);
+                }
+                if let Some(calibration) = store.calibration {
+                    store.save_calibration(&calibration);
+                }
+            } else {
+                warn!("Config sector erase failed");
+            }
+        }
+        store
+    }
+
+    /// The saved config, if there is a valid one.
+    pub fn load(&self) -> Option<RobotConfig> {
+        self.loaded
+    }
+
+    /// Append `config` as the newest record. False if the sector is full
+    /// (until the next boot) or the write failed.
+    pub fn save(&mut self, config: &RobotConfig) -> bool {
+        if !self.append(&encode_record(config)) {
+            return false;
+        }
+        self.loaded = Some(*config);
+        true
+    }
+
+    /// The saved calibration, if there is a valid one.
+    pub fn load_calibration(&self) -> Option<CalibrationReport> {
+        self.calibration
+    }
+
+    /// Append `calibration` as the newest calibration record. False if the
+    /// sector is full (until the next boot) or the write failed.
+    pub fn save_calibration(&mut self, calibration: &CalibrationReport) -> bool {
+        if !self.append(&encode_calibration(calibration)) {
+            return false;
+        }
+        self.calibration = Some(*calibration);
+        true
+    }
+
+    /// Write `record` (a whole number of slots) at the first blank slot.
+    fn append(&mut self, record: &[u8]) -> bool {
+        let Some(slot) = self.next_slot else {
+            return false;
+        };
+        let slots = (record.len() / RECORD_LEN) as u32;
+        if slot + slots > SLOTS {
+            return false;
+        }
+        // A failed write leaves a bad record in the slots; skip past it
+        self.next_slot = Some(slot + slots).filter(|&next| next < SLOTS);
+        self.flash.blocking_write(slot_offset(slot), record).is_ok()
+    }
+}
+
+fn slot_offset(slot: u32) -> u32 {
+    CONFIG_SECTOR_OFFSET + slot * RECORD_LEN as u32
+}
+
+/// [magic (4)] [config] [padding 0] [CRC-32 of everything before it (4, little-endian)]
+fn encode_record(config: &RobotConfig) -> [u8; CONFIG_RECORD_LEN] {
+    let mut record = [0u8; CONFIG_RECORD_LEN];
+    record[..4].copy_from_slice(&RECORD_MAGIC);
+    record[4..4 + RobotConfig::LEN].copy_from_slice(&config.encode());
+    let crc = crc32(&record[..CONFIG_RECORD_LEN - 4]);
+    record[CONFIG_RECORD_LEN - 4..].copy_from_slice(&crc.to_le_bytes());
+    record
+}
+
+fn decode_record(record: &[u8; CONFIG_RECORD_LEN]) -> Option<RobotConfig> {
+    let (body, crc) = record.split_at(CONFIG_RECORD_LEN - 4);
+    if body[..4] != RECORD_MAGIC || crc32(body).to_le_bytes() != crc {
+        return None;
+    }
+    RobotConfig::decode(body[4..4 + RobotConfig::LEN].try_into().ok()?)
+}
+
+/// [magic (4)] [min (2 each)] [max (2 each)] [thresholds (2 each)]
+/// [padding 0] [CRC-32 of everything before it (4, little-endian)], readings
+/// little-endian
+fn encode_calibration(calibration: &CalibrationReport) -> [u8; CALIBRATION_RECORD_LEN] {
+    let mut record = [0u8; CALIBRATION_RECORD_LEN];
+    record[..4].copy_from_slice(&CALIBRATION_MAGIC);
+    let readings = calibration.min.iter().chain(&calibration.max).chain(&calibration.thresholds);
+    for (bytes, value) in record[4..].chunks_exact_mut(2).zip(readings) {
+        bytes.copy_from_slice(&value.to_le_bytes());
+    }
+    let crc = crc32(&record[..CALIBRATION_RECORD_LEN - 4]);
+    record[CALIBRATION_RECORD_LEN - 4..].copy_from_slice(&crc.to_le_bytes());
+    record
+}
+
+fn decode_calibration(record: &[u8; CALIBRATION_RECORD_LEN]) -> Option<CalibrationReport> {
+    let (body, crc) = record.split_at(CALIBRATION_RECORD_LEN - 4);
+    if body[..4] != CALIBRATION_MAGIC || crc32(body).to_le_bytes() != crc {
+        return None;
+    }
+    let reading = |i: usize| u16::from_le_bytes([body[4 + 2 * i], body[5 + 2 * i]]);
+    Some(CalibrationReport {
+        calibrated: true,
+        min: core::array::from_fn(reading),
+        max: core::array::from_fn(|i| reading(SENSOR_COUNT + i)),
+        thresholds: core::array::from_fn(|i| reading(2 * SENSOR_COUNT + i)),
+    })
+}
+
+/// CRC-32 (IEEE 802.3), bitwise: only used on saves and the boot scan, so
+/// a lookup table isn't worth the flash.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
@@ -1,7 +1,7 @@
 /**
  * WebSocket-to-Serial Bridge for HC-05 Bluetooth
  * 
- * Protocol bytes (matching liru-core/src/bluetooth.rs):
+ * Protocol bytes (matching liru-core/src/protocol.rs):
  * Commands to robot:
  *   0x01 left right - Set motor speeds (-100 to 100)
  *   0x02            - Stop motors
@@ -9,9 +9,11 @@
  *   0x04            - Ping
  * 
  * Messages from robot:
- *   0x10 byte       - Sensor data (8-bit pattern)
+ *   0x10 low high   - Sensor data (binary pattern, sensors 1-8 then 9-16)
  *   0x11            - Pong
  *   0x12            - Connected
+ *
+ * Every message in both directions is COBS-encoded and terminated by 0x00.
  * 
  * Usage: node server.js
  */
@@ -22,7 +24,7 @@ const { SerialPort } = require('serialport');
 const WS_PORT = 3001;
 const BAUD_RATE = 9600; // HC-05 default, change to 2000000 if configured
 
-// Command constants (matching bluetooth.rs)
+// Command constants (matching protocol.rs)
 const CMD = {
     MOTOR: 0x01,
     STOP: 0x02,
@@ -31,6 +33,43 @@ const CMD = {
     GET_RAW_SENSORS: 0x05,
     SET_MODE: 0x06,
     START: 0x07,
+    ESTOP: 0x0C,
+    CLEAR_ESTOP: 0x0D,
+    DRIVE: 0x0F,
+    GET_CALIBRATION: 0x13,
+    GET_UART_ERRORS: 0x14,
+    CAL_WHITE: 0x16,
+    CAL_BLACK: 0x17,
+    DRIVE_TIMED: 0x19,
+    CAL_CENTER: 0x1A,
+    SET_CONTROLLER: 0x1B,
+    GET_DIAG: 0x1C,
+    SET_STEERING_BIAS: 0x1D,
+    NUDGE: 0x1E,
+    SET_SEARCH_TIMEOUT: 0x1F,
+    SET_POSITION_FILTER: 0x20,
+    TURN_CAL: 0x21,
+    TURN: 0x22,
+    SET_STEERING_LIMIT: 0x23,
+    PAUSE: 0x24,
+    RESUME: 0x25,
+    SET_THRESHOLDS: 0x26,
+    SET_MOTOR_ECHO: 0x27,
+    SET_FIXED_THRESHOLD: 0x28,
+    GET_PERF: 0x29,
+    SET_TURN_BOOST: 0x2A,
+    GET_CONFIG: 0x2B,
+    LOAD_CONFIG: 0x2C,
+    SAVE_CONFIG: 0x2D,
+    SET_CAR_TIMEOUT: 0x2E,
+    GET_RUN_RANGE: 0x2F,
+    SET_SIDE_KP: 0x30,
+    SHUTDOWN: 0x31,
+    SET_SPEED_SCALE: 0x32,
+    SET_CENTER_ON_START: 0x33,
+    SET_POLARITY: 0x34,
+    STREAM: 0x35,
+    SET_FINISH_SUSTAIN: 0x36,
 };
 
 const MSG = {
@@ -42,11 +81,93 @@ const MSG = {
     CALIBRATION_START: 0x15,
     CALIBRATION_END: 0x16,
     DEBUG_ANALOG: 0x17,
+    ESTOP: 0x1D,
+    ACK: 0x1E,
+    CALIBRATION: 0x1F,
+    RUN_STATS: 0x21,
+    CALIBRATION_PROGRESS: 0x22,
+    STATUS: 0x23,
+    DIAG: 0x24,
+    MODE: 0x25,
+    MOTOR_OUTPUT: 0x26,
+    PERF: 0x27,
+    CONFIG: 0x28,
+    RUN_RANGE: 0x29,
+    LINE_EVENT: 0x2A,
     ERROR: 0xFF,
 };
 
+// Tunables in `RobotConfig` wire order: [name, size in bytes, signed]
+const CONFIG_FIELDS = [
+    ['speed', 1], ['turnSpeed', 1], ['kpLeft', 2], ['kpRight', 2], ['ki', 2], ['kd', 2], ['controller', 1],
+    ['reverseLimit', 1], ['steeringBias', 1, true], ['steeringLimit', 1], ['turnBoost', 1],
+    ['loopPeriodMs', 1], ['searchTurn', 1], ['searchForward', 1], ['searchTimeoutDs', 1],
+    ['recovery', 1], ['positionTauMs', 1], ['carTimeoutDs', 1],
+    ['speedScaleMin', 1], ['centerOnStart', 1],
+    ['finishSustainDs', 1],
+];
+
+// Error code carrying the UART error counters rather than a failure
+const ERR_UART_ERRORS = 0x07;
+
+// Steering controller kinds (CMD.SET_CONTROLLER, last analog debug byte)
+const CONTROLLER_NAMES = ['P', 'PI', 'PD', 'PID'];
+const SEARCH_NAMES = ['', 'searching', 'reversing', 'sweeping', 'gave up'];
+
 let serialPort = null;
 
+// COBS framing (matching cobs_encode/cobs_decode in framing.rs)
+function cobsEncode(data) {
+    const out = [0];
+    let codeIdx = 0;
+    let code = 1;
+    for (const byte of data) {
+        if (byte === 0) {
+            out[codeIdx] = code;
+            codeIdx = out.length;
+            out.push(0);
+            code = 1;
+        } else {
+            out.push(byte);
+            code++;
+            if (code === 0xFF) {
+                out[codeIdx] = code;
+                codeIdx = out.length;
+                out.push(0);
+                code = 1;
+            }
+        }
+    }
+    out[codeIdx] = code;
+    return out;
+}
+
+function cobsDecode(frame) {
+    const out = [];
+    let i = 0;
+    while (i < frame.length) {
+        const code = frame[i];
+        if (code === 0 || i + code > frame.length) return null;
+        for (let j = i + 1; j < i + code; j++) out.push(frame[j]);
+        i += code;
+        if (code < 0xFF && i < frame.length) out.push(0);
+    }
+    return out;
+}
+
+// XOR checksum closing every frame (matching checksum in framing.rs)
+function checksum(bytes) {
+    return bytes.reduce((sum, byte) => sum ^ byte, 0);
+}
+
+// Send one message to the robot as a delimited COBS frame, checksum appended
+function sendFrame(bytes) {
+    serialPort.write(Buffer.from([...cobsEncode([...bytes, checksum(bytes)]), 0x00]));
+}
+
+// Encoded bytes of the frame currently being received
+let rxFrame = [];
+
 const wss = new WebSocketServer({ port: WS_PORT });
 
 console.log(`🚀 WebSocket bridge started on ws://localhost:${WS_PORT}`);
@@ -68,36 +189,149 @@ wss.on('connection', (ws) => {
                     if (serialPort && serialPort.isOpen) {
                         const left = Math.max(-100, Math.min(100, message.left));
                         const right = Math.max(-100, Math.min(100, message.right));
-                        const buf = Buffer.from([CMD.MOTOR, left & 0xFF, right & 0xFF]);
-                        serialPort.write(buf);
+                        const buf = [CMD.MOTOR, left & 0xFF, right & 0xFF];
+                        sendFrame(buf);
                         console.log(`→ Motor: L=${left} R=${right}`);
                     }
                     break;
 
+                case 'setMotorEcho':
+                    // [CMD_SET_MOTOR_ECHO, enabled]: MOTOR answered with the applied speeds
+                    if (serialPort && serialPort.isOpen) {
+                        sendFrame([CMD.SET_MOTOR_ECHO, message.enabled ? 1 : 0]);
+                        console.log(`→ Motor echo: ${message.enabled ? 'on' : 'off'}`);
+                    }
+                    break;
+
+                case 'drive':
+                    // Proportional joystick: [CMD_DRIVE, linear, angular], mixed on the robot
+                    if (serialPort && serialPort.isOpen) {
+                        const linear = Math.max(-100, Math.min(100, message.linear));
+                        const angular = Math.max(-100, Math.min(100, message.angular));
+                        sendFrame([CMD.DRIVE, linear & 0xFF, angular & 0xFF]);
+                        console.log(`→ Drive: lin=${linear} ang=${angular}`);
+                    }
+                    break;
+
                 case 'stop':
                     if (serialPort && serialPort.isOpen) {
-                        serialPort.write(Buffer.from([CMD.STOP]));
+                        sendFrame([CMD.STOP]);
                         console.log(`→ Stop`);
                     }
                     break;
 
                 case 'getSensors':
                     if (serialPort && serialPort.isOpen) {
-                        serialPort.write(Buffer.from([CMD.GET_SENSORS]));
+                        sendFrame([CMD.GET_SENSORS]);
                         console.log(`→ Request sensors`);
                     }
                     break;
 
                 case 'getRawSensors':
                     if (serialPort && serialPort.isOpen) {
-                        serialPort.write(Buffer.from([CMD.GET_RAW_SENSORS]));
+                        sendFrame([CMD.GET_RAW_SENSORS]);
                         console.log(`→ Request raw sensors`);
                     }
                     break;
 
+                case 'getDiag':
+                    if (serialPort && serialPort.isOpen) {
+                        sendFrame([CMD.GET_DIAG]);
+                        console.log(`→ Request diagnostics`);
+                    }
+                    break;
+
+                case 'getCalibration':
+                    if (serialPort && serialPort.isOpen) {
+                        sendFrame([CMD.GET_CALIBRATION]);
+                        console.log(`→ Request calibration`);
+                    }
+                    break;
+
+                case 'setThresholds':
+                    // [CMD_SET_THRESHOLDS, 8 x u16 little-endian] raw ADC counts
+                    if (serialPort && serialPort.isOpen && Array.isArray(message.thresholds) && message.thresholds.length === 8) {
+                        const frame = [CMD.SET_THRESHOLDS];
+                        for (const value of message.thresholds) {
+                            const threshold = Math.max(0, Math.min(4095, Math.round(value)));
+                            frame.push(threshold & 0xFF, (threshold >> 8) & 0xFF);
+                        }
+                        sendFrame(frame);
+                        console.log(`→ Manual thresholds: ${message.thresholds.join(', ')}`);
+                    }
+                    break;
+
+                case 'setFixedThreshold':
+                    // [CMD_SET_FIXED_THRESHOLD, threshold:2] big-endian raw ADC counts, 0 = calibrated
+                    if (serialPort && serialPort.isOpen) {
+                        const threshold = Math.max(0, Math.min(4095, Math.round(message.threshold)));
+                        sendFrame([CMD.SET_FIXED_THRESHOLD, (threshold >> 8) & 0xFF, threshold & 0xFF]);
+                        console.log(`→ Fixed threshold: ${threshold || 'off'}`);
+                    }
+                    break;
+
+                case 'shutdown':
+                    if (serialPort && serialPort.isOpen) {
+                        sendFrame([CMD.SHUTDOWN]);
+                        console.log(`→ Shutdown`);
+                    }
+                    break;
+
+                case 'getRunRange':
+                    if (serialPort && serialPort.isOpen) {
+                        sendFrame([CMD.GET_RUN_RANGE]);
+                        console.log(`→ Request run range`);
+                    }
+                    break;
+
+                case 'getConfig':
+                    if (serialPort && serialPort.isOpen) {
+                        sendFrame([CMD.GET_CONFIG]);
+                        console.log(`→ Request config`);
+                    }
+                    break;
+
+                case 'loadConfig':
+                    // [CMD_LOAD_CONFIG, config]: every field required, validated on the robot
+                    if (serialPort && serialPort.isOpen && message.config) {
+                        const frame = [CMD.LOAD_CONFIG];
+                        for (const [name, size] of CONFIG_FIELDS) {
+                            const value = Math.round(message.config[name] ?? 0);
+                            if (size === 2) {
+                                frame.push((value >> 8) & 0xFF, value & 0xFF);
+                            } else {
+                                frame.push(value & 0xFF);
+                            }
+                        }
+                        sendFrame(frame);
+                        console.log(`→ Load config: ${JSON.stringify(message.config)}`);
+                    }
+                    break;
+
+                case 'saveConfig':
+                    if (serialPort && serialPort.isOpen) {
+                        sendFrame([CMD.SAVE_CONFIG]);
+                        console.log(`→ Save config to flash`);
+                    }
+                    break;
+
+                case 'getPerf':
+                    if (serialPort && serialPort.isOpen) {
+                        sendFrame([CMD.GET_PERF]);
+                        console.log(`→ Request loop timing`);
+                    }
+                    break;
+
+                case 'getUartErrors':
+                    if (serialPort && serialPort.isOpen) {
+                        sendFrame([CMD.GET_UART_ERRORS]);
+                        console.log(`→ Request UART error counters`);
+                    }
+                    break;
+
                 case 'ping':
                     if (serialPort && serialPort.isOpen) {
-                        serialPort.write(Buffer.from([CMD.PING]));
+                        sendFrame([CMD.PING]);
                         console.log(`→ Ping`);
                     }
                     break;
@@ -105,22 +339,231 @@ wss.on('connection', (ws) => {
                 case 'setMode':
                     if (serialPort && serialPort.isOpen) {
                         const mode = message.mode === 1 ? 1 : 0;
-                        serialPort.write(Buffer.from([CMD.SET_MODE, mode]));
+                        sendFrame([CMD.SET_MODE, mode]);
                         console.log(`→ Set Mode: ${mode === 0 ? 'Car' : 'Line Follower'}`);
                     }
                     break;
 
                 case 'start':
                     if (serialPort && serialPort.isOpen) {
-                        serialPort.write(Buffer.from([CMD.START]));
+                        sendFrame([CMD.START]);
                         console.log('→ Start Calibration');
                     }
                     break;
 
+                case 'pause':
+                    if (serialPort && serialPort.isOpen) {
+                        sendFrame([CMD.PAUSE]);
+                        console.log('→ Pause');
+                    }
+                    break;
+
+                case 'resume':
+                    if (serialPort && serialPort.isOpen) {
+                        sendFrame([CMD.RESUME]);
+                        console.log('→ Resume');
+                    }
+                    break;
+
+                case 'driveTimed':
+                    // Straight run: [CMD_DRIVE_TIMED, speed, duration in 10ms units]
+                    if (serialPort && serialPort.isOpen) {
+                        const speed = Math.max(0, Math.min(100, message.speed));
+                        const ds = Math.max(0, Math.min(255, Math.round(message.durationMs / 10)));
+                        sendFrame([CMD.DRIVE_TIMED, speed, ds]);
+                        console.log(`→ Timed drive: ${speed}% for ${ds * 10}ms`);
+                    }
+                    break;
+
+                case 'setController':
+                    // [CMD_SET_CONTROLLER, kind] (0=P, 1=PI, 2=PD, 3=PID)
+                    if (serialPort && serialPort.isOpen) {
+                        sendFrame([CMD.SET_CONTROLLER, message.kind]);
+                        console.log(`→ Set Controller: ${CONTROLLER_NAMES[message.kind] ?? message.kind}`);
+                    }
+                    break;
+
+                case 'setSteeringBias':
+                    // [CMD_SET_STEERING_BIAS, bias_i8] (-20 to 20, + = right)
+                    if (serialPort && serialPort.isOpen) {
+                        const bias = Math.max(-20, Math.min(20, Math.round(message.bias)));
+                        sendFrame([CMD.SET_STEERING_BIAS, bias & 0xFF]);
+                        console.log(`→ Steering bias: ${bias}%`);
+                    }
+                    break;
+
+                case 'nudge': {
+                    // [CMD_NUDGE, direction]: single short tap for positioning
+                    const NUDGE_DIRECTIONS = { forward: 0, back: 1, left: 2, right: 3 };
+                    const direction = NUDGE_DIRECTIONS[message.direction];
+                    if (serialPort && serialPort.isOpen && direction !== undefined) {
+                        sendFrame([CMD.NUDGE, direction]);
+                        console.log(`→ Nudge ${message.direction}`);
+                    }
+                    break;
+                }
+
+                case 'setCenterOnStart':
+                    // [CMD_SET_CENTER_ON_START, enabled]
+                    if (serialPort && serialPort.isOpen) {
+                        sendFrame([CMD.SET_CENTER_ON_START, message.enabled ? 1 : 0]);
+                        console.log(`→ Center on start: ${message.enabled ? 'on' : 'off'}`);
+                    }
+                    break;
+
+                case 'setPolarity':
+                    // [CMD_SET_POLARITY, polarity] (0 = dark line on light, 1 = light line on dark)
+                    if (serialPort && serialPort.isOpen) {
+                        sendFrame([CMD.SET_POLARITY, message.lightOnDark ? 1 : 0]);
+                        console.log(`→ Line polarity: ${message.lightOnDark ? 'light on dark' : 'dark on light'}`);
+                    }
+                    break;
+
+                case 'setStream':
+                    // [CMD_STREAM, kind, period in 10ms] (kind or period 0 = off)
+                    if (serialPort && serialPort.isOpen) {
+                        const kinds = { none: 0, binary: 1, raw: 2, telemetry: 3 };
+                        const kind = kinds[message.kind] ?? 0;
+                        const period = Math.max(0, Math.min(255, Math.round((message.periodMs || 0) / 10)));
+                        sendFrame([CMD.STREAM, kind, period]);
+                        console.log(`→ Stream: ${message.kind || 'none'} every ${period * 10}ms`);
+                    }
+                    break;
+
+                case 'setSpeedScale':
+                    // [CMD_SET_SPEED_SCALE, percent] (100 = off)
+                    if (serialPort && serialPort.isOpen) {
+                        const pct = Math.max(0, Math.min(100, Math.round(message.minPercent)));
+                        sendFrame([CMD.SET_SPEED_SCALE, pct]);
+                        console.log(`→ Speed scale: ${pct}%`);
+                    }
+                    break;
+
+                case 'setCarTimeout':
+                    // [CMD_SET_CAR_TIMEOUT, duration in 100ms units] (0 = never)
+                    if (serialPort && serialPort.isOpen) {
+                        const ds = Math.max(0, Math.min(255, Math.round(message.timeoutMs / 100)));
+                        sendFrame([CMD.SET_CAR_TIMEOUT, ds]);
+                        console.log(`→ Car timeout: ${ds * 100}ms`);
+                    }
+                    break;
+
+                case 'setFinishSustain':
+                    // [CMD_SET_FINISH_SUSTAIN, duration in 100ms units] (0 = never)
+                    if (serialPort && serialPort.isOpen) {
+                        const ds = Math.max(0, Math.min(255, Math.round(message.sustainMs / 100)));
+                        sendFrame([CMD.SET_FINISH_SUSTAIN, ds]);
+                        console.log(`→ Finish sustain: ${ds * 100}ms`);
+                    }
+                    break;
+
+                case 'setSearchTimeout':
+                    // [CMD_SET_SEARCH_TIMEOUT, duration in 100ms units] (0 = no cap)
+                    if (serialPort && serialPort.isOpen) {
+                        const ds = Math.max(0, Math.min(255, Math.round(message.timeoutMs / 100)));
+                        sendFrame([CMD.SET_SEARCH_TIMEOUT, ds]);
+                        console.log(`→ Search timeout: ${ds * 100}ms`);
+                    }
+                    break;
+
+                case 'setPositionFilter':
+                    // [CMD_SET_POSITION_FILTER, time constant ms] (0 = off, max 200)
+                    if (serialPort && serialPort.isOpen) {
+                        const tau = Math.max(0, Math.min(200, Math.round(message.tauMs)));
+                        sendFrame([CMD.SET_POSITION_FILTER, tau]);
+                        console.log(`→ Position filter: ${tau}ms`);
+                    }
+                    break;
+
+                case 'setSideKp': {
+                    // [CMD_SET_SIDE_KP, left:2, right:2] (x100, big-endian)
+                    const left = Math.max(0, Math.min(65535, Math.round(message.kpLeft * 100)));
+                    const right = Math.max(0, Math.min(65535, Math.round(message.kpRight * 100)));
+                    if (serialPort && serialPort.isOpen) {
+                        sendFrame([CMD.SET_SIDE_KP, (left >> 8) & 0xFF, left & 0xFF, (right >> 8) & 0xFF, right & 0xFF]);
+                        console.log(`→ Side kp: left ${left / 100}, right ${right / 100}`);
+                    }
+                    break;
+                }
+
+                case 'turnCal': {
+                    // [CMD_TURN_CAL, ms_per_90_hi, ms_per_90_lo, speed]: store and pivot 90° right
+                    const ms = Math.max(1, Math.min(5000, Math.round(message.msPer90)));
+                    const speed = Math.max(1, Math.min(100, Math.round(message.speed)));
+                    if (serialPort && serialPort.isOpen) {
+                        sendFrame([CMD.TURN_CAL, (ms >> 8) & 0xFF, ms & 0xFF, speed]);
+                        console.log(`→ Turn calibration: ${ms}ms per 90° at ${speed}%`);
+                    }
+                    break;
+                }
+
+                case 'setTurnBoost':
+                    // [CMD_SET_TURN_BOOST, percent] (0 = off)
+                    if (serialPort && serialPort.isOpen) {
+                        const boost = Math.max(0, Math.min(100, Math.round(message.boost)));
+                        sendFrame([CMD.SET_TURN_BOOST, boost]);
+                        console.log(`→ Turn boost: ${boost}%`);
+                    }
+                    break;
+
+                case 'setSteeringLimit':
+                    // [CMD_SET_STEERING_LIMIT, percent] (100 = no limit)
+                    if (serialPort && serialPort.isOpen) {
+                        const limit = Math.max(0, Math.min(100, Math.round(message.limit)));
+                        sendFrame([CMD.SET_STEERING_LIMIT, limit]);
+                        console.log(`→ Steering limit: ${limit}%`);
+                    }
+                    break;
+
+                case 'turn': {
+                    // [CMD_TURN, degrees_hi, degrees_lo] (signed, + = right)
+                    const degrees = Math.max(-32768, Math.min(32767, Math.round(message.degrees)));
+                    if (serialPort && serialPort.isOpen) {
+                        sendFrame([CMD.TURN, (degrees >> 8) & 0xFF, degrees & 0xFF]);
+                        console.log(`→ Turn ${degrees}°`);
+                    }
+                    break;
+                }
+
+                case 'calCenter':
+                    if (serialPort && serialPort.isOpen) {
+                        sendFrame([CMD.CAL_CENTER]);
+                        console.log('→ Calibrate Center');
+                    }
+                    break;
+
+                case 'calWhite':
+                    if (serialPort && serialPort.isOpen) {
+                        sendFrame([CMD.CAL_WHITE]);
+                        console.log('→ Calibrate White');
+                    }
+                    break;
+
+                case 'calBlack':
+                    if (serialPort && serialPort.isOpen) {
+                        sendFrame([CMD.CAL_BLACK]);
+                        console.log('→ Calibrate Black');
+                    }
+                    break;
+
+                case 'estop':
+                    if (serialPort && serialPort.isOpen) {
+                        sendFrame([CMD.ESTOP]);
+                        console.log('→ E-STOP');
+                    }
+                    break;
+
+                case 'clearEstop':
+                    if (serialPort && serialPort.isOpen) {
+                        sendFrame([CMD.CLEAR_ESTOP]);
+                        console.log('→ Clear E-STOP');
+                    }
+                    break;
+
                 // Legacy text command support
                 case 'command':
                     if (serialPort && serialPort.isOpen) {
-                        serialPort.write(message.data);
+                        sendFrame([...Buffer.from(message.data)]);
                         console.log(`→ Sent (legacy): ${message.data}`);
                     }
                     break;
@@ -169,119 +612,25 @@ async function connectSerial(portName, ws) {
             }
         });
 
-        // Binary data handler
+        // Binary data handler: accumulate until the 0x00 delimiter, then decode
+        rxFrame = [];
         serialPort.on('data', (data) => {
-            for (let i = 0; i < data.length; i++) {
-                const byte = data[i];
-
-                switch (byte) {
-                    case MSG.SENSORS:
-                        // Next byte is sensor data
-                        if (i + 1 < data.length) {
-                            const sensorByte = data[++i];
-                            console.log(`← Sensors: ${sensorByte.toString(2).padStart(8, '0')}`);
-                            ws.send(JSON.stringify({
-                                type: 'sensors',
-                                data: sensorByte,
-                                binary: sensorByte.toString(2).padStart(8, '0')
-                            }));
-                        }
-                        break;
-
-                    case MSG.RAW_SENSORS:
-                        // Next 16 bytes are 8 x u16
-                        if (i + 16 < data.length) {
-                            const rawData = [];
-                            for (let j = 0; j < 8; j++) {
-                                const low = data[++i];
-                                const high = data[++i];
-                                const val = (high << 8) | low;
-                                rawData.push(val);
-                            }
-                            console.log(`← Raw: ${rawData.join(', ')}`);
-                            ws.send(JSON.stringify({
-                                type: 'rawSensors',
-                                data: rawData
-                            }));
-                        }
-                        break;
-
-                    case MSG.PONG:
-                        console.log(`← Pong`);
-                        ws.send(JSON.stringify({ type: 'pong' }));
-                        break;
-
-                    case MSG.CONNECTED:
-                        console.log(`← Robot connected`);
-                        ws.send(JSON.stringify({ type: 'robotConnected' }));
-                        break;
-
-                    case MSG.DEBUG:
-                        // Debug message: mode, position, motor_action
-                        if (i + 3 < data.length) {
-                            const debugMode = data[++i];
-                            const debugPosition = data[++i];
-                            const debugMotorAction = data[++i];
-                            const actionNames = ['STOP', 'FWD', 'LEFT', 'RIGHT'];
-                            const modeNames = ['Car', 'LineIdle', 'LineCalib', 'LineRun'];
-                            console.log(`← DEBUG: Mode=${modeNames[debugMode] || debugMode} Pos=${debugPosition.toString(2).padStart(8, '0')} Motor=${actionNames[debugMotorAction] || debugMotorAction}`);
-                            ws.send(JSON.stringify({
-                                type: 'debug',
-                                mode: debugMode,
-                                position: debugPosition,
-                                motorAction: debugMotorAction
-                            }));
-                        }
-                        break;
-
-                    case MSG.DEBUG_ANALOG:
-                        // [Type 0x17] [Pos_H] [Pos_L] [Int_H] [Int_L] [Steer] [L_Speed] [R_Speed]
-                        if (i + 7 < data.length) {
-                            const posH = data[++i];
-                            const posL = data[++i];
-                            // Combine signed 16-bit big endian
-                            let pos = (posH << 8) | posL;
-                            if (pos > 32767) pos -= 65536; // signed 16-bit
-
-                            const intH = data[++i];
-                            const intL = data[++i];
-                            const intensity = (intH << 8) | intL;
-
-                            const steerByte = data[++i];
-                            let steering = steerByte;
-                            if (steering > 127) steering -= 256; // signed 8-bit
-
-                            const leftSpeed = data[++i];
-                            const rightSpeed = data[++i];
-
-                            const text = `Pos:${pos} Int:${intensity} St:${steering} L:${leftSpeed} R:${rightSpeed}`;
-                            console.log(`← ANALOG: ${text}`);
-
-                            ws.send(JSON.stringify({
-                                type: 'debug',
-                                mode: 3, // LineRunning assumption
-                                position: 0,
-                                motorAction: 0,
-                                text: text
-                            }));
-                        }
-                        break;
-
-                    case MSG.CALIBRATION_START:
-                        console.log('← Calibration Started');
-                        ws.send(JSON.stringify({ type: 'calibrationStart' }));
-                        break;
-
-                    case MSG.CALIBRATION_END:
-                        console.log('← Calibration Ended');
-                        ws.send(JSON.stringify({ type: 'calibrationEnd' }));
-                        break;
-
-                    default:
-                        // Ignore unrecognized bytes to reduce noise
-                        // console.log(`← Raw: 0x${byte.toString(16)}`);
-                        break;
+            for (const byte of data) {
+                if (byte !== 0x00) {
+                    rxFrame.push(byte);
+                    continue;
                 }
+                const frame = cobsDecode(rxFrame);
+                rxFrame = [];
+                if (!frame || frame.length < 2) {
+                    continue;
+                }
+                const sum = frame.pop();
+                if (checksum(frame) !== sum) {
+                    console.warn('← Dropped frame with bad checksum');
+                    continue;
+                }
+                handleFrame(frame, ws);
             }
         });
 
@@ -301,6 +650,319 @@ async function connectSerial(portName, ws) {
     }
 }
 
+// A binary sensor pattern from its low byte and the high byte newer
+// firmware appends (sensors 9-16), as a number and a bit string
+function readPattern(low, high) {
+    const value = low | ((high ?? 0) << 8);
+    return { value, bits: value.toString(2).padStart(high ? 16 : 8, '0') };
+}
+
+// Dispatch one decoded message from the robot
+function handleFrame(frame, ws) {
+    const payload = frame.slice(1);
+
+    switch (frame[0]) {
+        case MSG.SENSORS:
+            if (payload.length >= 1) {
+                const pattern = readPattern(payload[0], payload[1]);
+                console.log(`← Sensors: ${pattern.bits}`);
+                ws.send(JSON.stringify({
+                    type: 'sensors',
+                    data: pattern.value,
+                    binary: pattern.bits
+                }));
+            }
+            break;
+
+        case MSG.RAW_SENSORS:
+            // 8 x u16 little-endian
+            if (payload.length >= 16) {
+                const rawData = [];
+                for (let j = 0; j < 8; j++) {
+                    rawData.push(payload[j * 2] | (payload[j * 2 + 1] << 8));
+                }
+                console.log(`← Raw: ${rawData.join(', ')}`);
+                ws.send(JSON.stringify({
+                    type: 'rawSensors',
+                    data: rawData
+                }));
+            }
+            break;
+
+        case MSG.CALIBRATION:
+            // [calibrated] then 8 x threshold, 8 x min, 8 x max, each u16 little-endian
+            if (payload.length >= 49) {
+                const readU16 = (j) => payload[1 + j * 2] | (payload[2 + j * 2] << 8);
+                const channels = (offset) => Array.from({ length: 8 }, (_, j) => readU16(offset + j));
+                const report = {
+                    calibrated: payload[0] === 1,
+                    thresholds: channels(0),
+                    min: channels(8),
+                    max: channels(16),
+                };
+                console.log(`← Calibration (${report.calibrated ? 'ok' : 'not calibrated'}): ${report.thresholds.join(', ')}`);
+                ws.send(JSON.stringify({ type: 'calibration', ...report }));
+            }
+            break;
+
+        case MSG.RUN_RANGE:
+            // 8 x min, 8 x max, each u16 little-endian
+            if (payload.length >= 32) {
+                const readU16 = (j) => payload[j * 2] | (payload[1 + j * 2] << 8);
+                const channels = (offset) => Array.from({ length: 8 }, (_, j) => readU16(offset + j));
+                const range = { min: channels(0), max: channels(8) };
+                console.log(`← Run range: min ${range.min.join(', ')} / max ${range.max.join(', ')}`);
+                ws.send(JSON.stringify({ type: 'runRange', ...range }));
+            }
+            break;
+
+        case MSG.LINE_EVENT:
+            // [event]: 1 = full line, 2 = left branch, 3 = right branch
+            if (payload.length >= 1) {
+                const event = ['', 'full line', 'left branch', 'right branch'][payload[0]] ?? payload[0];
+                console.log(`← Junction: ${event}`);
+                ws.send(JSON.stringify({ type: 'lineEvent', event: payload[0] }));
+            }
+            break;
+
+        case MSG.DIAG:
+            // 8 x raw, 8 x normalized (u16), binary low, position (i16), intensity (u16), (binary high), little-endian
+            if (payload.length >= 37) {
+                const readU16 = (offset) => payload[offset] | (payload[offset + 1] << 8);
+                let position = readU16(33);
+                if (position > 32767) position -= 65536; // signed 16-bit
+                const diag = {
+                    raw: Array.from({ length: 8 }, (_, j) => readU16(j * 2)),
+                    normalized: Array.from({ length: 8 }, (_, j) => readU16(16 + j * 2)),
+                    binary: readPattern(payload[32], payload[37]).value,
+                    position,
+                    intensity: readU16(35),
+                };
+                console.log(`← Diag: raw ${diag.raw.join(', ')} | norm ${diag.normalized.join(', ')} | bin ${readPattern(payload[32], payload[37]).bits} | pos ${diag.position} int ${diag.intensity}`);
+                ws.send(JSON.stringify({ type: 'diag', ...diag }));
+            }
+            break;
+
+        case MSG.MODE:
+            // [mode]: 0=Car, 1=Idle, 2=Calibrating, 3=Running, 4=Paused, 5=E-stop, 6=Shut down
+            if (payload.length >= 1) {
+                const MODE_NAMES = ['Car', 'Idle', 'Calibrating', 'Running', 'Paused', 'E-stop', 'Shut down'];
+                console.log(`← Mode: ${MODE_NAMES[payload[0]] ?? payload[0]}`);
+                ws.send(JSON.stringify({ type: 'mode', mode: payload[0] }));
+            }
+            break;
+
+        case MSG.MOTOR_OUTPUT:
+            // [left_i8, right_i8]: speeds applied after limits, reply to MOTOR with echo on
+            if (payload.length >= 2) {
+                const left = payload[0] > 127 ? payload[0] - 256 : payload[0];
+                const right = payload[1] > 127 ? payload[1] - 256 : payload[1];
+                ws.send(JSON.stringify({ type: 'motorOutput', left, right }));
+            }
+            break;
+
+        case MSG.CONFIG:
+            // Every tunable, in CONFIG_FIELDS order, big-endian
+            if (payload.length >= 19) {
+                const config = {};
+                let offset = 0;
+                for (const [name, size, signed] of CONFIG_FIELDS) {
+                    let value = size === 2 ? (payload[offset] << 8) | payload[offset + 1] : payload[offset];
+                    if (signed && value > 127) value -= 256;
+                    config[name] = value;
+                    offset += size;
+                }
+                console.log(`← Config: ${JSON.stringify(config)}`);
+                ws.send(JSON.stringify({ type: 'config', config }));
+            }
+            break;
+
+        case MSG.PERF:
+            // [min:2] [max:2] [avg:2] control loop iteration time in us, big-endian
+            if (payload.length >= 6) {
+                const readU16 = (j) => (payload[j * 2] << 8) | payload[j * 2 + 1];
+                const perf = { minUs: readU16(0), maxUs: readU16(1), avgUs: readU16(2) };
+                console.log(`← Loop timing: min ${perf.minUs}us max ${perf.maxUs}us avg ${perf.avgUs}us`);
+                ws.send(JSON.stringify({ type: 'perf', ...perf }));
+            }
+            break;
+
+        case MSG.CALIBRATION_PROGRESS:
+            // [elapsed_s] then 8 x max-min spread, each u16 little-endian
+            if (payload.length >= 17) {
+                const elapsed = payload[0];
+                const spread = Array.from({ length: 8 }, (_, j) => payload[1 + j * 2] | (payload[2 + j * 2] << 8));
+                console.log(`← Calibrating ${elapsed}s, spread: ${spread.join(', ')}`);
+                ws.send(JSON.stringify({ type: 'calibrationProgress', elapsed, spread }));
+            }
+            break;
+
+        case MSG.RUN_STATS:
+            // [peak_error:2] [line_losses:2] [run_ms:4] [max_speed] ([saturations:2], newer firmware), big-endian
+            if (payload.length >= 9) {
+                const stats = {
+                    peakError: (payload[0] << 8) | payload[1],
+                    lineLosses: (payload[2] << 8) | payload[3],
+                    runMs: ((payload[4] << 24) | (payload[5] << 16) | (payload[6] << 8) | payload[7]) >>> 0,
+                    maxSpeed: payload[8],
+                    saturations: payload.length >= 11 ? (payload[9] << 8) | payload[10] : null,
+                };
+                console.log(`← Run stats: peak error ${stats.peakError}, ${stats.lineLosses} losses, ${stats.runMs}ms, max speed ${stats.maxSpeed}%${stats.saturations !== null ? `, ${stats.saturations} saturations` : ''}`);
+                ws.send(JSON.stringify({ type: 'runStats', ...stats }));
+            }
+            break;
+
+        case MSG.STATUS:
+            // [flags] [stalled]: mode in bits 0-1, then one flag per bit; stalled motors bitmask
+            if (payload.length >= 1) {
+                const flags = payload[0];
+                ws.send(JSON.stringify({
+                    type: 'status',
+                    mode: flags & 0x03,
+                    connected: !!(flags & 0x04),
+                    estop: !!(flags & 0x08),
+                    calibrated: !!(flags & 0x10),
+                    lineLost: !!(flags & 0x20),
+                    batteryLow: !!(flags & 0x40),
+                    paused: !!(flags & 0x80),
+                    stalledLeft: payload.length >= 2 && !!(payload[1] & 0x01),
+                    stalledRight: payload.length >= 2 && !!(payload[1] & 0x02),
+                }));
+            }
+            break;
+
+        case MSG.PONG:
+            console.log(`← Pong`);
+            ws.send(JSON.stringify({ type: 'pong' }));
+            break;
+
+        case MSG.CONNECTED:
+            console.log(`← Robot connected`);
+            ws.send(JSON.stringify({ type: 'robotConnected' }));
+            break;
+
+        case MSG.DEBUG:
+            // Debug message: mode, pattern low byte, motor_action (, pattern high byte)
+            if (payload.length >= 3) {
+                const [debugMode, low, debugMotorAction, high] = payload;
+                const pattern = readPattern(low, high);
+                const debugPosition = pattern.value;
+                const actionNames = ['STOP', 'FWD', 'LEFT', 'RIGHT', 'REV', 'ARC_L', 'ARC_R'];
+                const modeNames = ['Car', 'LineIdle', 'LineCalib', 'LineRun'];
+                console.log(`← DEBUG: Mode=${modeNames[debugMode] || debugMode} Pos=${pattern.bits} Motor=${actionNames[debugMotorAction] || debugMotorAction}`);
+                ws.send(JSON.stringify({
+                    type: 'debug',
+                    mode: debugMode,
+                    position: debugPosition,
+                    motorAction: debugMotorAction
+                }));
+            }
+            break;
+
+        case MSG.DEBUG_ANALOG:
+            // [Pos_H] [Pos_L] [Int_H] [Int_L] [Steer] [L_Speed] [R_Speed] ([Confidence] [Coverage] [Controller] [Saturated] [Binary] [Limited] [Search] [Binary_H], newer firmware)
+            if (payload.length >= 7) {
+                // Combine signed 16-bit big endian
+                let pos = (payload[0] << 8) | payload[1];
+                if (pos > 32767) pos -= 65536; // signed 16-bit
+
+                const intensity = (payload[2] << 8) | payload[3];
+
+                let steering = payload[4];
+                if (steering > 127) steering -= 256; // signed 8-bit
+
+                // Signed: the inner wheel may reverse in sharp corrections
+                let leftSpeed = payload[5];
+                if (leftSpeed > 127) leftSpeed -= 256;
+                let rightSpeed = payload[6];
+                if (rightSpeed > 127) rightSpeed -= 256;
+
+                let text = `Pos:${pos} Int:${intensity} St:${steering} L:${leftSpeed} R:${rightSpeed}`;
+                if (payload.length >= 8) {
+                    text += ` Conf:${payload[7]}`;
+                }
+                if (payload.length >= 9) {
+                    text += ` Cov:${payload[8]}%`;
+                }
+                if (payload.length >= 10) {
+                    text += ` Ctl:${CONTROLLER_NAMES[payload[9]] ?? payload[9]}`;
+                }
+                if (payload.length >= 11 && payload[10]) {
+                    text += ' SAT';
+                }
+                const pattern = payload.length >= 12 ? readPattern(payload[11], payload[14]) : null;
+                const binary = pattern ? pattern.value : null;
+                if (pattern) {
+                    text += ` Bin:${pattern.bits}`;
+                }
+                if (payload.length >= 13 && payload[12]) {
+                    text += ' LIM';
+                }
+                if (payload.length >= 14 && payload[13]) {
+                    text += ` Search:${SEARCH_NAMES[payload[13]] ?? payload[13]}`;
+                }
+                console.log(`← ANALOG: ${text}`);
+
+                ws.send(JSON.stringify({
+                    type: 'debug',
+                    mode: 3, // LineRunning assumption
+                    position: 0,
+                    motorAction: 0,
+                    sensors: binary,
+                    text: text
+                }));
+            }
+            break;
+
+        case MSG.CALIBRATION_START:
+            console.log('← Calibration Started');
+            ws.send(JSON.stringify({ type: 'calibrationStart' }));
+            break;
+
+        case MSG.CALIBRATION_END:
+            console.log('← Calibration Ended');
+            ws.send(JSON.stringify({ type: 'calibrationEnd' }));
+            break;
+
+        case MSG.ESTOP:
+            if (payload.length >= 1) {
+                const latched = payload[0] === 1;
+                console.log(`← E-STOP ${latched ? 'latched' : 'cleared'}`);
+                ws.send(JSON.stringify({ type: 'estop', latched }));
+            }
+            break;
+
+        case MSG.ACK:
+            // Acknowledged command byte; forwarded so the GUI can retransmit what's missing
+            if (payload.length >= 1) {
+                ws.send(JSON.stringify({ type: 'ack', command: payload[0] }));
+            }
+            break;
+
+        case MSG.ERROR:
+            if (payload.length >= 9 && payload[0] === ERR_UART_ERRORS) {
+                // Robot-side receive error counters, u16 big-endian (checksum on newer firmware)
+                const readU16 = (j) => (payload[1 + j * 2] << 8) | payload[2 + j * 2];
+                const stats = { overrun: readU16(0), framing: readU16(1), parity: readU16(2), noise: readU16(3) };
+                if (payload.length >= 11) {
+                    stats.checksum = readU16(4);
+                }
+                console.log(`← UART errors: overrun=${stats.overrun} framing=${stats.framing} parity=${stats.parity} noise=${stats.noise}${stats.checksum !== undefined ? ` checksum=${stats.checksum}` : ''}`);
+                ws.send(JSON.stringify({ type: 'uartErrors', ...stats }));
+            } else if (payload.length >= 1) {
+                const [code, low, high] = payload;
+                const detail = low !== undefined ? readPattern(low, high) : undefined;
+                console.log(`← ERROR code=${code}${detail ? ` detail=${detail.bits}` : ''}`);
+                ws.send(JSON.stringify({ type: 'robotError', code, detail: detail?.value }));
+            }
+            break;
+
+        default:
+            // Ignore unrecognized messages to reduce noise
+            break;
+    }
+}
+
 function disconnectSerial(ws) {
     if (serialPort && serialPort.isOpen) {
         serialPort.close();
@@ -13,6 +13,7 @@ interface UseRobotConnectionReturn {
     disconnect: () => void;
     sendCommand: (command: string) => void;
     sendMotor: (left: number, right: number) => void;
+    sendDrive: (linear: number, angular: number) => void;
     sendStop: () => void;
     requestSensors: () => void;
     requestRawSensors: () => void;
@@ -20,6 +21,9 @@ interface UseRobotConnectionReturn {
     sendSetMode: (mode: 'car' | 'line') => void;
     sendStart: () => void;
     resetCalibration: () => void;
+    sendEStop: () => void;
+    clearEStop: () => void;
+    estopLatched: boolean;
     pollingEnabled: boolean;
     setPollingEnabled: (enabled: boolean) => void;
     calibrationStatus: CalibrationStatus;
@@ -36,6 +40,7 @@ export function useRobotConnection(): UseRobotConnectionReturn {
     const [rawSensorData, setRawSensorData] = useState<number[]>([]);
     const [calibrationStatus, setCalibrationStatus] = useState<CalibrationStatus>('idle');
     const [debugLog, setDebugLog] = useState<string[]>([]);
+    const [estopLatched, setEstopLatched] = useState<boolean>(false);
 
     // Ref to track calibration start time for debouncing
     const calibrationStartTime = useRef<number>(0);
@@ -77,6 +82,19 @@ export function useRobotConnection(): UseRobotConnectionReturn {
                         setCalibrationStatus('idle');
                     }
                     break;
+                case 'estop':
+                    setEstopLatched(data.latched);
+                    if (data.latched) setCalibrationStatus('idle');
+                    break;
+                case 'robotError':
+                    // 0x04 = calibration contrast too low; robot went back to idle
+                    if (data.code === 0x04) {
+                        setCalibrationStatus('idle');
+                        setLastMessage(`Calibration failed: low contrast on ${(data.detail || 0).toString(2).padStart(8, '0')}`);
+                    } else {
+                        setLastMessage(`Robot error ${data.code}`);
+                    }
+                    break;
                 case 'calibrationStart':
                     console.log('Received calibrationStart');
                     calibrationStartTime.current = Date.now();
@@ -100,7 +118,7 @@ export function useRobotConnection(): UseRobotConnectionReturn {
                     if (data.text) {
                         debugMsg = `[${timestamp}] ${data.text}`;
                     } else {
-                        const actionNames = ['CENTER', 'LOST', 'LEFT', 'RIGHT'];
+                        const actionNames = ['STOP', 'FWD', 'LEFT', 'RIGHT', 'REV', 'ARC L', 'ARC R'];
                         const modeNames = ['Car', 'LineIdle', 'LineCal', 'LineRun'];
                         const pos = (data.position || 0).toString(2).padStart(8, '0');
                         const action = actionNames[data.motorAction] || data.motorAction;
@@ -146,6 +164,13 @@ export function useRobotConnection(): UseRobotConnectionReturn {
         }
     }, [ws, connectionState]);
 
+    // Proportional joystick drive (mixed on the robot)
+    const sendDrive = useCallback((linear: number, angular: number) => {
+        if (ws && connectionState === 'connected') {
+            ws.send(JSON.stringify({ type: 'drive', linear, angular }));
+        }
+    }, [ws, connectionState]);
+
     // Stop command
     const sendStop = useCallback(() => {
         if (ws && connectionState === 'connected') {
@@ -195,6 +220,19 @@ export function useRobotConnection(): UseRobotConnectionReturn {
         setCalibrationStatus('idle');
     }, []);
 
+    // Emergency stop: latches on the robot until cleared
+    const sendEStop = useCallback(() => {
+        if (ws && connectionState === 'connected') {
+            ws.send(JSON.stringify({ type: 'estop' }));
+        }
+    }, [ws, connectionState]);
+
+    const clearEStop = useCallback(() => {
+        if (ws && connectionState === 'connected') {
+            ws.send(JSON.stringify({ type: 'clearEstop' }));
+        }
+    }, [ws, connectionState]);
+
     const [pollingEnabled, setPollingEnabled] = useState(false);
 
     // Auto-poll sensors when connected AND polling is enabled
@@ -208,6 +246,16 @@ export function useRobotConnection(): UseRobotConnectionReturn {
         }
     }, [connectionState, pollingEnabled, requestSensors, requestRawSensors]);
 
+    // Heartbeat: robot stops its motors if it hears nothing for 1s
+    useEffect(() => {
+        if (connectionState === 'connected') {
+            const interval = setInterval(() => {
+                sendPing();
+            }, 400);
+            return () => clearInterval(interval);
+        }
+    }, [connectionState, sendPing]);
+
     useEffect(() => {
         return () => {
             if (ws) {
@@ -226,6 +274,7 @@ export function useRobotConnection(): UseRobotConnectionReturn {
         disconnect,
         sendCommand,
         sendMotor,
+        sendDrive,
         sendStop,
         requestSensors,
         requestRawSensors,
@@ -233,6 +282,9 @@ export function useRobotConnection(): UseRobotConnectionReturn {
         sendSetMode,
         sendStart,
         resetCalibration,
+        sendEStop,
+        clearEStop,
+        estopLatched,
         pollingEnabled,
         setPollingEnabled,
         calibrationStatus,